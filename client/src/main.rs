@@ -19,9 +19,12 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::path::Path;
+
 use libtea::Message;
 use rand::Rng;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio_stream::StreamExt;
 
 #[tokio::main]
 async fn main() {
@@ -42,45 +45,39 @@ async fn main2() {
     data_dir.push(".config");
     data_dir.push("RYOKUCHAT");
 
-    println!("This client is developed debugging libtea.");
-    println!("Select debug mode:");
-    println!("1: As client 1");
-    println!("2: As client 2");
-    let port;
-    loop {
-        let mut stdout = tokio::io::stdout();
-        stdout.write_all("> ".as_bytes()).await.unwrap();
-        stdout.flush().await.unwrap();
-        drop(stdout);
-        let mut stdin = BufReader::new(tokio::io::stdin());
-        let mut input = String::new();
-        stdin.read_line(&mut input).await.unwrap();
-        let input = input.trim();
+    // プロフィールごとに別のIDを使い、Torは全てのプロフィールで共有する
+    let manager = libtea::ProfileManager::new(libtea::SessionBuilder::new(data_dir));
+    let name = match select_profile(&manager).await {
+        Some(s) => s,
+        None => return,
+    };
 
-        match input {
-            "1" => {
-                data_dir.push("client1");
-                port = 4545;
-            }
-            "2" => {
-                data_dir.push("client2");
-                port = 1919;
-            }
-            _ => continue,
-        }
-        break;
+    let mut session = manager.open(&name, None).await;
+    // 秘密鍵がパスフレーズで暗号化されている場合は、入力してもらってやり直す
+    while let Err(libtea::Error::Locked) = session {
+        let passphrase = read_input("Passphrase> ").await;
+        session = manager.open(&name, Some(&passphrase)).await;
     }
-
-    let session = libtea::RYOKUCHATSession::new(data_dir, port).await;
-    let (send, mut receive) = tokio::sync::mpsc::channel(1);
-    *session.notify.lock().await = Some(send);
+    let session = match session {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Failed to start libtea: {}", e);
+            return;
+        }
+    };
 
     loop {
         println!("Your address is: {}", &session.myaddress());
         println!("/help to command list.");
         println!("Input index of friend or command.");
         let mut temp: usize = 0;
-        let data = session.get_users().await.unwrap();
+        let data = match session.get_users().await {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("Failed to get the friend list: {}", e);
+                return;
+            }
+        };
         for i in &data {
             match &i.username {
                 Some(s) => println!("{}. {}", temp, s),
@@ -106,14 +103,25 @@ async fn main2() {
             command_ok = Some(add(&session, input).await);
         } else if input.starts_with("/del") {
             command_ok = Some(del(&session, &data, input).await);
+        } else if input.starts_with("/passphrase") {
+            command_ok = Some(passphrase(&session).await);
+        } else if input.starts_with("/export") {
+            command_ok = Some(export(&session, input).await);
+        } else if input.starts_with("/rotate") {
+            command_ok = Some(rotate(&session).await);
+        } else if input.starts_with("/resend") {
+            command_ok = Some(resend(&session).await);
         } else if input.starts_with("/exit") {
+            if let Err(e) = manager.shutdown().await {
+                eprintln!("Error while shutting down: {}", e);
+            }
             return;
         } else {
             let index: usize = match input.parse() {
                 Ok(o) => o,
                 Err(_) => continue,
             };
-            chat_session(&session, &data[index], &mut receive).await;
+            chat_session(&session, &data[index]).await;
         }
         if let Some(s) = command_ok {
             match s {
@@ -125,31 +133,93 @@ async fn main2() {
     }
 }
 
-async fn chat_session(
-    session: &libtea::RYOKUCHATSession,
-    user: &libtea::UserData,
-    receiver: &mut tokio::sync::mpsc::Receiver<Message>,
-) {
-    let receiver = unsafe {
-        std::mem::transmute::<
-            &mut tokio::sync::mpsc::Receiver<Message>,
-            &mut tokio::sync::mpsc::Receiver<Message>,
-        >(receiver)
+// 使うプロフィールを選んでもらう
+// 作ったばかりのプロフィールには、別のマシンで作ったバックアップを復元できるようにする
+async fn select_profile(manager: &libtea::ProfileManager) -> Option<String> {
+    loop {
+        let profiles = match manager.list().await {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("Failed to get the profile list: {}", e);
+                return None;
+            }
+        };
+        let current = manager.current().await.ok().flatten();
+        println!("Select a profile:");
+        for (i, name) in profiles.iter().enumerate() {
+            match current.as_deref() == Some(name.as_str()) {
+                true => println!("{}. {} (current)", i, name),
+                false => println!("{}. {}", i, name),
+            }
+        }
+        println!("/new (name): Create a new profile.\n/del (index): Delete a profile.\nPress enter to use the current profile.");
+
+        let input = read_input("PROFILE> ").await;
+        let input = input.trim();
+        if input.is_empty() {
+            if current.is_some() {
+                return current;
+            }
+        } else if let Some(name) = input.strip_prefix("/new ") {
+            let name = name.trim();
+            match manager.create(name).await {
+                Ok(_) => import(manager, name).await,
+                Err(e) => eprintln!("Failed to create the profile: {}", e),
+            }
+        } else if let Some(index) = input.strip_prefix("/del ") {
+            let name = match index
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| profiles.get(i))
+            {
+                Some(s) => s,
+                None => continue,
+            };
+            if let Err(e) = manager.delete(name).await {
+                eprintln!("Failed to delete the profile: {}", e);
+            }
+        } else if let Some(name) = input.parse::<usize>().ok().and_then(|i| profiles.get(i)) {
+            if let Err(e) = manager.switch(name).await {
+                eprintln!("Failed to switch the profile: {}", e);
+            }
+            return Some(name.clone());
+        }
+        println!();
+    }
+}
+
+async fn import(manager: &libtea::ProfileManager, name: &str) {
+    let path = read_input("Backup file to import (empty to create a new identity)> ").await;
+    if path.is_empty() {
+        return;
+    }
+    let passphrase = read_input("Backup passphrase> ").await;
+    let builder = match manager.profile_dir(name) {
+        Ok(o) => libtea::SessionBuilder::new(o),
+        Err(e) => {
+            eprintln!("Failed to import the backup: {}", e);
+            return;
+        }
     };
-    let user = unsafe { std::mem::transmute::<&libtea::UserData, &libtea::UserData>(user) };
+    match libtea::RYOKUCHATSession::import_backup(&builder, Path::new(&path), &passphrase).await {
+        Ok(_) => println!("The backup is imported."),
+        Err(e) => eprintln!("Failed to import the backup: {}", e),
+    }
+}
+
+async fn chat_session(session: &libtea::RYOKUCHATSession, user: &libtea::UserData) {
+    let mut events = session.subscribe();
+    let userid = user.id.clone();
     let handle = tokio::spawn(async move {
-        loop {
-            let newmsg = match receiver.recv().await {
-                Some(Message::DirectMsg(a, b)) => {
-                    if a.as_byte() == user.id.as_byte() {
-                        b
-                    } else {
-                        continue;
-                    }
+        while let Some(event) = events.next().await {
+            match event {
+                Message::DirectMsg(a, b) if a.as_byte() == userid.as_byte() => {
+                    println!("> {}", b);
                 }
-                None => continue,
-            };
-            println!("> {}", newmsg);
+                Message::Lagged(n) => println!("{} messages were dropped.", n),
+                _ => continue,
+            }
         }
     });
     loop {
@@ -163,7 +233,13 @@ async fn chat_session(
 
         if input.starts_with("/help") {
             help().await;
-        } else if input.starts_with("/add") || input.starts_with("/del") {
+        } else if input.starts_with("/add")
+            || input.starts_with("/del")
+            || input.starts_with("/passphrase")
+            || input.starts_with("/export")
+            || input.starts_with("/rotate")
+            || input.starts_with("/resend")
+        {
             println!("Can't use this command now.");
         } else if input.starts_with("/exit") {
             handle.abort();
@@ -175,14 +251,14 @@ async fn chat_session(
             eprintln!("こころの健康相談統一ダイヤル");
             eprintln!("+81 570-064-556");
         }
-        if session.send_dm(&user.id, input).await.is_none() {
-            println!("Error while sending.");
+        if let Err(e) = session.send_dm(&user.id, input).await {
+            println!("Error while sending: {}", e);
         }
     }
 }
 
 async fn help() {
-    println!("/help: Display this message\n/add (address): Add friend to your addressbook.\n/del (index): Delete friend from your addressbook.\n/exit: Exit from this screen.")
+    println!("/help: Display this message\n/add (address): Add friend to your addressbook.\n/del (index): Delete friend from your addressbook.\n/passphrase: Change the passphrase of your key file. Leave empty to remove it.\n/export (path): Save your identity and addressbook to an encrypted backup file.\n/rotate: Replace your identity key and tell your friends the new one.\n/resend: Tell the new identity key again to friends who missed it.\n/exit: Exit from this screen.")
 }
 
 async fn add(session: &libtea::RYOKUCHATSession, input: &str) -> bool {
@@ -193,7 +269,7 @@ async fn add(session: &libtea::RYOKUCHATSession, input: &str) -> bool {
         None => return false,
     };
 
-    session.add_user(address).await.is_some()
+    session.add_user(address).await.is_ok()
 }
 
 async fn del(session: &libtea::RYOKUCHATSession, data: &[libtea::UserData], input: &str) -> bool {
@@ -208,7 +284,70 @@ async fn del(session: &libtea::RYOKUCHATSession, data: &[libtea::UserData], inpu
     };
 
     let user = &data[index];
-    session.del_user(&user.id).await.is_some()
+    session.del_user(&user.id).await.is_ok()
+}
+
+async fn passphrase(session: &libtea::RYOKUCHATSession) -> bool {
+    let input = read_input("New passphrase> ").await;
+    let passphrase = match input.as_str() {
+        "" => None,
+        s => Some(s),
+    };
+
+    session.change_passphrase(passphrase).await.is_ok()
+}
+
+async fn export(session: &libtea::RYOKUCHATSession, input: &str) -> bool {
+    let mut hoge = input.split(' ');
+    let _ = hoge.next();
+    let path = match hoge.next() {
+        Some(s) => s,
+        None => return false,
+    };
+    let passphrase = read_input("Backup passphrase> ").await;
+
+    session
+        .export_backup(Path::new(path), &passphrase)
+        .await
+        .is_ok()
+}
+
+async fn rotate(session: &libtea::RYOKUCHATSession) -> bool {
+    match session.rotate_identity().await {
+        Ok(failed) => {
+            println!("Your new address is {}", session.myaddress());
+            print_failed(failed.len());
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+async fn resend(session: &libtea::RYOKUCHATSession) -> bool {
+    match session.resend_identity_rotation().await {
+        Ok(failed) => {
+            print_failed(failed.len());
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn print_failed(failed: usize) {
+    if failed != 0 {
+        println!("{} friends could not be told. Try /resend later.", failed);
+    }
+}
+
+async fn read_input(prompt: &str) -> String {
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(prompt.as_bytes()).await.unwrap();
+    stdout.flush().await.unwrap();
+    drop(stdout);
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut input = String::new();
+    stdin.read_line(&mut input).await.unwrap();
+    input.trim_end_matches(['\r', '\n']).to_string()
 }
 
 fn suicide_check(msg: &str) -> bool {
@@ -0,0 +1,367 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::Stream;
+
+use crate::{
+    inside::{
+        functions::event_stream,
+        structs::{ClientAuthRegistry, Passphrase},
+    },
+    transport::{CircuitIsolation, Transport},
+    Error, Message, RYOKUCHATSession, TorConfig,
+};
+
+/// RYOKUCHATSessionを細かく設定して作るためのビルダーです  
+/// newメソッドでデータを設置する場所を指定し、必要な設定をしてからbuildを呼んでください  
+/// ポートに0を指定した場合や指定しなかった場合は、空いているポートが自動で選ばれます  
+/// ファイルやディレクトリの場所は、相対パスならデータを設置する場所からの相対パスになります  
+#[derive(Clone, Debug)]
+pub struct SessionBuilder {
+    pub(crate) data_dir: PathBuf,
+    pub(crate) port: u16,
+    pub(crate) socks_port: u16,
+    pub(crate) control_port: u16,
+    pub(crate) bind_address: Option<IpAddr>,
+    pub(crate) virtual_port: u16,
+    pub(crate) database_file: PathBuf,
+    pub(crate) tor_dir: PathBuf,
+    pub(crate) hidden_service_dir: PathBuf,
+    pub(crate) key_file: PathBuf,
+    pub(crate) passphrase: Option<Passphrase>,
+    pub(crate) events: broadcast::Sender<Message>,
+    pub(crate) event_capacity: usize,
+    pub(crate) transport: Option<Arc<dyn Transport>>,
+    pub(crate) bootstrap_timeout: Duration,
+    pub(crate) bootstrap_stuck_timeout: Duration,
+    pub(crate) system_tor: Option<(String, ControlAuth)>,
+    pub(crate) onion_key_file: PathBuf,
+    pub(crate) client_auth_key_file: PathBuf,
+    pub(crate) previous_key_file: PathBuf,
+    pub(crate) client_auth: bool,
+    pub(crate) circuit_isolation: CircuitIsolation,
+    pub(crate) health_check_interval: Duration,
+    pub(crate) tor_config: TorConfig,
+    // 複製したSessionBuilderの間で共有され、同じTorに登録した鍵を数える
+    pub(crate) client_auth_keys: Arc<ClientAuthRegistry>,
+    // ProfileManagerが作ったセッションでは、NEWNYMを送る代わりにここで頼む
+    pub(crate) newnym: Option<mpsc::Sender<()>>,
+}
+
+/// 既に動いているTorのControlPortに認証する方法です  
+/// ログに出ないように、Debugではパスワードを表示しません  
+#[derive(Clone)]
+pub enum ControlAuth {
+    /// 認証が設定されていない場合に使います
+    Null,
+    /// HashedControlPasswordで設定したパスワードで認証します
+    Password(String),
+    /// CookieAuthenticationのCookieファイルで認証します  
+    /// Noneの場合はファイルの場所をTorに問い合わせます  
+    Cookie(Option<PathBuf>),
+}
+
+impl std::fmt::Debug for ControlAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlAuth::Null => f.write_str("Null"),
+            ControlAuth::Password(_) => f.write_str("Password(..)"),
+            ControlAuth::Cookie(path) => f.debug_tuple("Cookie").field(path).finish(),
+        }
+    }
+}
+
+impl SessionBuilder {
+    /// 動作の説明:  
+    /// 新しくSessionBuilderを作ります  
+    /// 引数について:  
+    /// libteaのデータを設置する場所を指定します  
+    pub fn new(data_dir: impl Into<PathBuf>) -> SessionBuilder {
+        SessionBuilder {
+            data_dir: data_dir.into(),
+            port: 0,
+            socks_port: 0,
+            control_port: 0,
+            bind_address: None,
+            virtual_port: 4545,
+            database_file: PathBuf::from("sqlite.db"),
+            tor_dir: PathBuf::from("tor"),
+            hidden_service_dir: ["tor", "hidden"].iter().collect(),
+            key_file: PathBuf::from("DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr"),
+            passphrase: None,
+            events: broadcast::channel(64).0,
+            event_capacity: 64,
+            transport: None,
+            bootstrap_timeout: Duration::from_secs(300),
+            bootstrap_stuck_timeout: Duration::from_secs(60),
+            system_tor: None,
+            onion_key_file: PathBuf::from("DO_NOT_SEND_TO_OTHER_PEOPLE_onionkey.ykr"),
+            client_auth_key_file: PathBuf::from("DO_NOT_SEND_TO_OTHER_PEOPLE_clientauth.ykr"),
+            previous_key_file: PathBuf::from("DO_NOT_SEND_TO_OTHER_PEOPLE_previouskeys.ykr"),
+            client_auth: true,
+            circuit_isolation: CircuitIsolation::default(),
+            health_check_interval: Duration::from_secs(300),
+            tor_config: TorConfig::default(),
+            client_auth_keys: Arc::default(),
+            newnym: None,
+        }
+    }
+
+    /// 動作の説明:  
+    /// Tor Hidden Serviceを経由して送られてきたリクエストを受け付けるためのポートを指定します  
+    pub fn port(mut self, port: u16) -> SessionBuilder {
+        self.port = port;
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torが使うSocksプロキシのポートを指定します  
+    pub fn socks_port(mut self, port: u16) -> SessionBuilder {
+        self.socks_port = port;
+        self
+    }
+
+    /// 動作の説明:  
+    /// TorのControlPortのポートを指定します  
+    pub fn control_port(mut self, port: u16) -> SessionBuilder {
+        self.control_port = port;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 各ポートをbindするアドレスを指定します  
+    /// 指定しなかった場合はlocalhostを名前解決したアドレスが使われます  
+    pub fn bind_address(mut self, address: IpAddr) -> SessionBuilder {
+        self.bind_address = Some(address);
+        self
+    }
+
+    /// 動作の説明:  
+    /// Tor Hidden Service側で公開するポートを指定します  
+    /// 注意点:  
+    /// 相手に接続するときにもこのポートが使われるため、連絡先と同じ値にしてください  
+    /// 初期値は4545です  
+    pub fn virtual_port(mut self, port: u16) -> SessionBuilder {
+        self.virtual_port = port;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 連絡先リストなどを保存するSQLiteのファイルを指定します  
+    /// 初期値はsqlite.dbです  
+    pub fn database_file(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.database_file = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// TorのDataDirectoryを指定します  
+    /// torrcもこの中に作られます  
+    /// 初期値はtorです  
+    pub fn tor_dir(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.tor_dir = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// 以前のバージョンでTorのHiddenServiceDirとして使っていたディレクトリを指定します  
+    /// onion_key_fileが無い場合に、この中にあるTorの鍵がonion_key_fileに移行されます  
+    /// 初期値はtor/hiddenです  
+    pub fn hidden_service_dir(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.hidden_service_dir = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// 秘密鍵を保存するファイルを指定します  
+    /// 初期値はDO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykrです  
+    /// 注意点:  
+    /// ファイルが壊れている場合は新しい鍵を作らず、buildがError::KeyFileを返します  
+    /// 以前のバージョンで保存されたファイルは、読み込んだときに今の形式で保存し直されます  
+    pub fn key_file(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.key_file = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// 秘密鍵のファイルのパスフレーズを指定します  
+    /// 秘密鍵はパスフレーズから作った鍵で暗号化して保存されます  
+    /// Hidden Serviceの鍵とクライアント認証の鍵も、同じパスフレーズで暗号化されます  
+    /// 暗号化されていないファイルの場合は、暗号化して保存し直されます  
+    /// 注意点:  
+    /// ファイルが暗号化されているのにパスフレーズを指定しなかった場合や、間違っている場合はbuildがError::Lockedを返します  
+    /// パスフレーズを忘れた場合は秘密鍵を取り出せず、自分のIDも使えなくなります  
+    pub fn passphrase(mut self, passphrase: impl Into<String>) -> SessionBuilder {
+        self.passphrase = Some(Passphrase(passphrase.into()));
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torを起動せずに、既に動いているTorを使うように指定します  
+    /// Hidden ServiceはADD_ONIONで作られ、そのTorのSocksプロキシが使われます  
+    /// 引数について:  
+    /// 1: ControlPortのアドレスを(アドレス):(ポート)の形式で指定します  
+    /// 2: ControlPortへの認証方法を指定します  
+    /// 注意点:  
+    /// socks_port、control_port、tor_dirは使われません  
+    /// shutdownしてもTor自体は終了しません  
+    pub fn system_tor(
+        mut self,
+        control_address: impl Into<String>,
+        auth: ControlAuth,
+    ) -> SessionBuilder {
+        self.system_tor = Some((control_address.into(), auth));
+        self
+    }
+
+    /// 動作の説明:  
+    /// Tor Hidden Serviceの秘密鍵を保存するファイルを指定します  
+    /// Hidden Serviceはこの鍵を使ってADD_ONIONで作られます  
+    /// 別のマシンに移る場合は、secretkeyのファイルと一緒にこのファイルを移してください  
+    /// 初期値はDO_NOT_SEND_TO_OTHER_PEOPLE_onionkey.ykrです  
+    pub fn onion_key_file(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.onion_key_file = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// Hidden Serviceのクライアント認証に使うx25519の秘密鍵を保存するファイルを指定します  
+    /// 公開鍵は自分のアドレスの#以降に入ります  
+    /// 初期値はDO_NOT_SEND_TO_OTHER_PEOPLE_clientauth.ykrです  
+    pub fn client_auth_key_file(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.client_auth_key_file = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// rotate_identityで使わなくなった秘密鍵を保存するファイルを指定します  
+    /// 新しいIDをまだ伝えられていない連絡先に、resend_identity_rotationで送り直すために使います  
+    /// 初期値はDO_NOT_SEND_TO_OTHER_PEOPLE_previouskeys.ykrです  
+    /// 注意点:  
+    /// 全ての連絡先に新しいIDを伝え終わると、ファイルは消されます  
+    pub fn previous_key_file(mut self, path: impl Into<PathBuf>) -> SessionBuilder {
+        self.previous_key_file = path.into();
+        self
+    }
+
+    /// 動作の説明:  
+    /// Hidden Serviceのクライアント認証を使うかどうかを指定します  
+    /// 有効にした場合、連絡先リストにいるユーザー以外はHidden Serviceの情報を取得できず、接続もできなくなります  
+    /// 初期値はtrueです  
+    /// 注意点:  
+    /// #以降が無い古い形式のアドレスで追加した連絡先も接続できなくなるため、新しいアドレスで追加し直してもらってください  
+    pub fn client_auth(mut self, enabled: bool) -> SessionBuilder {
+        self.client_auth = enabled;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 相手に接続するときに、どの単位でTorの回線を分けるかを指定します  
+    /// 初期値はCircuitIsolation::PerContactです  
+    /// 注意点:  
+    /// 既に動いているTorを使う場合は、SocksPortでIsolateSOCKSAuthが無効になっていると効果がありません  
+    pub fn circuit_isolation(mut self, isolation: CircuitIsolation) -> SessionBuilder {
+        self.circuit_isolation = isolation;
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torを経由して自分のHidden Serviceに接続できるかを確かめる間隔を指定します  
+    /// 接続できなくなった場合はMessage::Offlineが届き、Hidden Serviceが作り直されます  
+    /// それでも接続できない状態が続くと、新しい回線を作らせ、組み込みのTorであれば起動し直します  
+    /// 初期値は5分で、0を指定した場合は確かめません  
+    /// 注意点:  
+    /// Torを使っていない場合は使われません  
+    pub fn health_check_interval(mut self, interval: Duration) -> SessionBuilder {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 組み込みのTorに渡すリレーの選び方やパディングなどの設定を指定します  
+    /// 設定はTorを起動する前に確かめられ、正しくない場合はbuildがError::Configを返します  
+    /// 注意点:  
+    /// 既に動いているTorを使う場合や、Transportを指定した場合は使われません  
+    pub fn tor_config(mut self, config: TorConfig) -> SessionBuilder {
+        self.tor_config = config;
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torの起動とHidden Serviceの準備を待つ時間を指定します  
+    /// これを過ぎた場合、buildはError::TorBootstrapTimeoutを返します  
+    /// 初期値は5分です  
+    pub fn bootstrap_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.bootstrap_timeout = timeout;
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torの起動の進捗がこの時間変わらなかった場合に、Message::BootstrapStuckを送ります  
+    /// 初期値は1分で、0を指定した場合は送りません  
+    /// 注意点:  
+    /// Torが起動中の問題を報告した場合は、この時間に関係なく送られます  
+    pub fn bootstrap_stuck_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.bootstrap_stuck_timeout = timeout;
+        self
+    }
+
+    /// 動作の説明:  
+    /// subscribeで作ったStreamごとに、未読の通知をいくつまで溜めておくかを指定します  
+    /// これを超えた場合は古い通知から捨てられます  
+    /// 初期値は64で、0を指定した場合は1になります  
+    /// 注意点:  
+    /// このメソッドを呼ぶ前にSessionBuilder::subscribeで作ったStreamには通知が届かなくなります  
+    pub fn event_capacity(mut self, capacity: usize) -> SessionBuilder {
+        self.event_capacity = capacity.max(1);
+        self.events = broadcast::channel(self.event_capacity).0;
+        self
+    }
+
+    /// 動作の説明:  
+    /// RYOKUCHATSession::subscribeと同じように、通知を受け取るためのStreamを作ります  
+    /// buildする前に呼ぶことで、Torの起動の進み具合などを受け取ることができます  
+    /// build後に作られたRYOKUCHATSessionの通知も、そのまま受け取ることができます  
+    pub fn subscribe(&self) -> impl Stream<Item = Message> + Send + Unpin + 'static {
+        event_stream(self.events.subscribe())
+    }
+
+    /// 動作の説明:  
+    /// 相手との通信に使うTransportを指定します  
+    /// 指定した場合はTorを起動せず、ポートやTor関係の設定は使われません  
+    /// 指定しなかった場合はTorを起動し、TorTransportが使われます  
+    pub fn transport(mut self, transport: impl Transport) -> SessionBuilder {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// 動作の説明:  
+    /// 設定した内容でRYOKUCHATSessionを作ります  
+    /// 返り値について:  
+    /// 成功ならばRYOKUCHATSessionが、失敗ならばErrorが返ってきます  
+    /// 同じポートが複数の用途に指定されていた場合はError::Configになります  
+    pub async fn build(self) -> Result<RYOKUCHATSession, Error> {
+        RYOKUCHATSession::start(self).await
+    }
+}
@@ -0,0 +1,109 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+
+/// libteaの操作が失敗したときに返るエラーです  
+/// 今後バリアントが増える可能性があります  
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// アドレスやユーザーIDの形式が正しくありません
+    Address(String),
+    /// 追加しようとしたユーザーは既に連絡先リストに存在します
+    UserExists,
+    /// 指定されたユーザーは連絡先リストに存在しません
+    UnknownUser,
+    /// 空のメッセージは送信できません
+    EmptyMessage,
+    /// SQLiteの操作に失敗しました
+    Storage(sqlx::Error),
+    /// 相手との通信路(TorのSocksプロキシなど)でエラーが発生しました
+    Transport(String),
+    /// 接続時の認証に失敗しました
+    Handshake(String),
+    /// 相手から送られてきたデータが正しくありません
+    Protocol(String),
+    /// 設定値が正しくありません
+    Config(String),
+    /// ファイルやディレクトリの操作に失敗しました
+    Io(std::io::Error),
+    /// 秘密鍵のファイルを読み書きできないか、内容が壊れています
+    KeyFile(String),
+    /// 秘密鍵のファイルがパスフレーズで暗号化されていますが、パスフレーズが指定されていないか間違っています  
+    /// SessionBuilder::passphraseで正しいパスフレーズを指定してから、もう一度buildしてください  
+    Locked,
+    /// Torの起動や操作に失敗しました
+    Tor(String),
+    /// 時間内にTorの起動が終わりませんでした  
+    /// 最後に受け取った進捗(%)と段階の説明が入ります  
+    TorBootstrapTimeout(u8, String),
+    /// セッションが既に終了しています
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Address(e) => write!(f, "invalid address: {}", e),
+            Error::UserExists => write!(f, "the user already exists"),
+            Error::UnknownUser => write!(f, "unknown user"),
+            Error::EmptyMessage => write!(f, "empty message is not allowed"),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::Handshake(e) => write!(f, "handshake failed: {}", e),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::KeyFile(e) => write!(f, "key file error: {}", e),
+            Error::Locked => write!(f, "the key file is locked by a passphrase"),
+            Error::Tor(e) => write!(f, "tor error: {}", e),
+            Error::TorBootstrapTimeout(p, s) => write!(
+                f,
+                "Tor did not finish bootstrapping in time (stopped at {}%: {})",
+                p, s
+            ),
+            Error::Closed => write!(f, "the session is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
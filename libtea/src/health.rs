@@ -0,0 +1,211 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// 自分のHidden Serviceに接続できるかを監視し、接続できなくなったら作り直す
+// HS_DESCイベントは別のControlPortの接続で受け取るので、RYOKUCHATSessionが使っている接続はロックしない
+// 接続できない状態が続いた場合は、新しい回線を作らせ、組み込みのTorであれば起動し直す
+
+use std::{sync::Weak, time::Duration};
+
+use tokio::{
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::{
+    inside::structs::{ErrInto, HandleWrapper},
+    tor_control::{ControlConnection, Event},
+    ControlAuth, Error, Message, RYOKUCHATSession, SessionInner,
+};
+
+// 自分に接続するときに待つ最大の時間
+const DIAL_TIMEOUT: Duration = Duration::from_secs(120);
+// HS_DESCイベントはHSDirごとに届くので、最初のイベントから少し待ってまとめて1回だけ確かめる
+const DEBOUNCE: Duration = Duration::from_secs(5);
+// 続けて何回接続できなかったらHidden Serviceを作り直すか
+const REPUBLISH_AFTER: u32 = 2;
+// 続けて何回接続できなかったらTorに新しい回線を作らせるか
+const NEWNYM_AFTER: u32 = 4;
+// 続けて何回接続できなかったら組み込みのTorを起動し直すか
+#[cfg(feature = "embedded-tor")]
+const RESTART_AFTER: u32 = 6;
+
+// Hidden Serviceを監視するスレッドの本体
+pub(crate) async fn monitor(
+    weak: Weak<SessionInner>,
+    control_address: String,
+    control_auth: ControlAuth,
+    interval: Duration,
+) {
+    trace!("health::monitor() is called");
+    defer!(trace!("returning from health::monitor()"));
+
+    let (sender, mut events) = mpsc::channel(16);
+    let mut _reader = watch(control_address.clone(), control_auth, sender.clone());
+
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut failures = 0;
+    let mut online = None;
+    loop {
+        // 定期的に確かめるほか、記述子の公開に成功したときや失敗したときにも確かめる
+        tokio::select! {
+            _ = ticker.tick() => {}
+            Some(event) = events.recv() => {
+                let session = match RYOKUCHATSession::upgrade(&weak) {
+                    Ok(o) => o,
+                    Err(_) => return,
+                };
+                let mut words = event.body.split(' ');
+                let (action, address) = (words.next(), words.next());
+                let own = session.inner.onion.read().await.as_ref().map(|o| o.service_id.clone());
+                drop(session);
+                if address.is_none() || address != own.as_deref() {
+                    continue;
+                }
+                match action {
+                    Some("UPLOADED") => debug!("the onion service descriptor is uploaded"),
+                    Some("FAILED") => warn!("failed to upload the onion service descriptor: {}", &event.body),
+                    _ => continue,
+                }
+                tokio::time::sleep(DEBOUNCE).await;
+            }
+        }
+
+        let result = check(&weak, interval).await;
+        // 確かめている間に届いたイベントの分も確かめたことにする
+        while events.try_recv().is_ok() {}
+        ticker.reset();
+
+        let session = match RYOKUCHATSession::upgrade(&weak) {
+            Ok(o) => o,
+            Err(_) => return,
+        };
+        match result {
+            Ok(_) => {
+                failures = 0;
+                if online != Some(true) {
+                    info!("the onion service is reachable");
+                    session.notify(Message::Online);
+                    online = Some(true);
+                }
+            }
+            Err(Error::Closed) => return,
+            Err(e) => {
+                failures += 1;
+                warn!(
+                    "the onion service is unreachable ({} times): {}",
+                    failures, e
+                );
+                if online != Some(false) {
+                    session.notify(Message::Offline(e.to_string()));
+                    online = Some(false);
+                }
+                match recover(&session, failures).await {
+                    // Torを起動し直した場合は、HS_DESCイベントも新しいTorから受け取る
+                    Ok(Some(auth)) => {
+                        _reader = watch(control_address.clone(), auth, sender.clone());
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("failed to recover the onion service: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// HS_DESCイベントを受け取るスレッドを起動する
+fn watch(
+    control_address: String,
+    control_auth: ControlAuth,
+    sender: mpsc::Sender<Event>,
+) -> HandleWrapper {
+    HandleWrapper(tokio::spawn(async move {
+        if let Err(e) = read_hs_desc(&control_address, &control_auth, sender).await {
+            warn!("stopped watching HS_DESC events: {}", e);
+        }
+    }))
+}
+
+// Torを経由して自分のHidden Serviceに接続してみる
+// 接続を待っている間にshutdownできるように、セッションは持ったままにしない
+async fn check(weak: &Weak<SessionInner>, interval: Duration) -> Result<(), Error> {
+    let transport = RYOKUCHATSession::upgrade(weak)?.inner.transport.clone();
+    let hostname = transport.hostname();
+    let stream = tokio::time::timeout(DIAL_TIMEOUT.min(interval), transport.dial(&hostname))
+        .await
+        .err_into(|_| Error::Transport(format!("connecting to {} timed out", &hostname)))??;
+    drop(stream);
+    Ok(())
+}
+
+// 接続できなかった回数に応じて、Hidden Serviceを作り直したり、新しい回線を作らせたり、Torを起動し直したりする
+// Torを起動し直した場合は、ControlPortの新しい認証情報を返す
+async fn recover(session: &RYOKUCHATSession, failures: u32) -> Result<Option<ControlAuth>, Error> {
+    if failures < REPUBLISH_AFTER {
+        return Ok(None);
+    }
+    #[cfg(feature = "embedded-tor")]
+    if failures.is_multiple_of(RESTART_AFTER) {
+        if let Some(auth) = session.restart_tor().await? {
+            return Ok(Some(auth));
+        }
+    }
+    let mut control = session.inner.control.lock().await;
+    let onion = session.inner.onion.read().await;
+    let onion = match onion.as_ref() {
+        Some(o) => o,
+        None => return Ok(None),
+    };
+    let control = control.as_mut().err_into(|_| Error::Closed)?;
+    if failures.is_multiple_of(NEWNYM_AFTER) {
+        match &session.inner.newnym {
+            // 共有しているTorでは他のプロフィールの回線も作り直されるので、ProfileManagerに任せる
+            Some(s) => {
+                info!("asking ProfileManager to build new circuits");
+                let _ = s.try_send(());
+            }
+            None => {
+                info!("asking Tor to build new circuits");
+                control.signal_newnym().await?;
+            }
+        }
+    }
+    session.republish_onion(control, onion).await?;
+    Ok(None)
+}
+
+// HS_DESCイベントを受け取り、monitorに送る
+async fn read_hs_desc(
+    control_address: &str,
+    control_auth: &ControlAuth,
+    sender: mpsc::Sender<Event>,
+) -> Result<(), Error> {
+    let mut control = ControlConnection::connect(control_address).await?;
+    control.authenticate(control_auth).await?;
+    control.set_events(&["HS_DESC"]).await?;
+    loop {
+        let event = control.next_event().await?;
+        if event.name == "HS_DESC" && sender.send(event).await.is_err() {
+            return Ok(());
+        }
+    }
+}
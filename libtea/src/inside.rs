@@ -26,3 +26,7 @@ pub(crate) mod macros;
 pub(crate) mod functions;
 // 構造体
 pub(crate) mod structs;
+// 秘密鍵のファイル
+pub(crate) mod keyfile;
+// 自分のIDとデータをまとめたバックアップ
+pub(crate) mod backup;
@@ -0,0 +1,142 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// 別のマシンに移るためのバックアップの中身を作ったり取り出したりする
+// バックアップはKeyType::Backupの.ykrとして、必ずパスフレーズで暗号化して保存する
+//
+// 中身の形式
+// 0: 中身のバージョン
+// 1..: (種類 u8)(長さ u64のビッグエンディアン)(内容) の繰り返し
+
+use crate::{consts::KEY_LENGTH, Error};
+
+const VERSION: u8 = 1;
+const IDENTITY: u8 = 1;
+const ONION_SERVICE: u8 = 2;
+const CLIENT_AUTH: u8 = 3;
+const DATABASE: u8 = 4;
+const PREVIOUS_IDENTITY: u8 = 5;
+
+// バックアップに入れるもの
+pub struct Backup {
+    pub identity: [u8; KEY_LENGTH],
+    // Torを使っていない場合は無い
+    pub onion_key: Option<Vec<u8>>,
+    pub client_auth_key: Option<Vec<u8>>,
+    // VACUUM INTOで書き出したデータベースのファイル
+    pub database: Vec<u8>,
+    // rotate_identityの宣言をまだ送れていない連絡先のための古い秘密鍵
+    pub previous_keys: Vec<[u8; KEY_LENGTH]>,
+}
+
+impl Backup {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![VERSION];
+        let mut push = |kind: u8, value: &[u8]| {
+            data.push(kind);
+            data.extend_from_slice(&(value.len() as u64).to_be_bytes());
+            data.extend_from_slice(value);
+        };
+        push(IDENTITY, &self.identity);
+        if let Some(s) = &self.onion_key {
+            push(ONION_SERVICE, s);
+        }
+        if let Some(s) = &self.client_auth_key {
+            push(CLIENT_AUTH, s);
+        }
+        push(DATABASE, &self.database);
+        if !self.previous_keys.is_empty() {
+            push(PREVIOUS_IDENTITY, &self.previous_keys.concat());
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Backup, Error> {
+        let broken = |reason: &str| {
+            error!("the backup is broken: {}", reason);
+            Error::KeyFile(format!("the backup is broken: {}", reason))
+        };
+
+        match data.first() {
+            Some(&VERSION) => (),
+            Some(v) => {
+                error!("unsupported backup version {}", v);
+                return Err(Error::KeyFile(format!("unsupported backup version {}", v)));
+            }
+            None => return Err(broken("empty")),
+        }
+
+        let mut identity = None;
+        let mut onion_key = None;
+        let mut client_auth_key = None;
+        let mut database = None;
+        let mut previous_keys = Vec::new();
+        let mut rest = &data[1..];
+        while !rest.is_empty() {
+            if rest.len() < 9 {
+                return Err(broken("truncated"));
+            }
+            let kind = rest[0];
+            let mut length = [0; 8];
+            length.copy_from_slice(&rest[1..9]);
+            let length = usize::try_from(u64::from_be_bytes(length))
+                .ok()
+                .filter(|l| *l <= rest.len() - 9)
+                .ok_or_else(|| broken("truncated"))?;
+            let value = &rest[9..9 + length];
+            rest = &rest[9 + length..];
+
+            match kind {
+                IDENTITY => {
+                    identity = Some(
+                        <[u8; KEY_LENGTH]>::try_from(value)
+                            .map_err(|_| broken("wrong identity key length"))?,
+                    )
+                }
+                ONION_SERVICE => onion_key = Some(value.to_vec()),
+                CLIENT_AUTH => client_auth_key = Some(value.to_vec()),
+                DATABASE => database = Some(value.to_vec()),
+                PREVIOUS_IDENTITY => {
+                    if value.is_empty() || !value.len().is_multiple_of(KEY_LENGTH) {
+                        return Err(broken("wrong previous identity key length"));
+                    }
+                    previous_keys = value
+                        .chunks_exact(KEY_LENGTH)
+                        .map(|k| {
+                            <[u8; KEY_LENGTH]>::try_from(k)
+                                .map_err(|_| broken("wrong previous identity key length"))
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                // 新しいバージョンで増えたものは読み飛ばす
+                k => warn!("unknown entry {} in the backup is ignored", k),
+            }
+        }
+
+        Ok(Backup {
+            identity: identity.ok_or_else(|| broken("no identity key"))?,
+            onion_key,
+            client_auth_key,
+            database: database.ok_or_else(|| broken("no database"))?,
+            previous_keys,
+        })
+    }
+}
@@ -19,23 +19,42 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{future::Future, io::Cursor};
+use std::{
+    io::Cursor,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Duration,
+};
 
 use byteorder::BigEndian;
-use ed448_rust::{PublicKey, SIG_LENGTH};
+use ed448_rust::{PrivateKey, PublicKey, SIG_LENGTH};
+#[cfg(feature = "embedded-tor")]
 use rand::Rng;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::{
     fs,
     io::{AsyncRead, AsyncWrite},
-    sync::Mutex,
+    sync::{broadcast, Mutex},
+    time::Instant,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
 };
 
-use crate::inside::structs::ErrMsg;
+use crate::inside::structs::{ErrInto, ErrMsg};
 use crate::{
-    consts::MAXMSGLEN,
-    inside::structs::{HandleWrapper, MessageForNetwork, UserDataRaw, UserDataTemp},
-    Message, RYOKUCHATSession, UserData,
+    consts::{KEY_LENGTH, MAXMSGLEN},
+    inside::{
+        keyfile::{read_key_file, write_key_file, KeyType},
+        structs::{
+            ClientAuthKey, ClientAuthUpdate, HandleWrapper, MessageForNetwork, UserDataRaw,
+            UserDataTemp,
+        },
+    },
+    tor_control::{parse_bootstrap, parse_keywords, ControlConnection},
+    Error, Message, RYOKUCHATSession, UserData,
 };
 
 pub async fn process_message<
@@ -48,23 +67,47 @@ pub async fn process_message<
     trace!("process_message() is called.");
     defer!(trace!("reterning from process_message()"));
 
-    let session = unsafe { std::mem::transmute::<&RYOKUCHATSession, &RYOKUCHATSession>(session) };
+    let weak = session.downgrade();
     let (mut read, write) = tokio::io::split(stream);
 
-    session.user_data_temp.write().await.insert(
+    let mut user_data_temp = session.inner.user_data_temp.write().await;
+    // shutdown中に接続してきた場合は何もせずに閉じる
+    if session.inner.closed.load(Ordering::SeqCst) {
+        info!("the session is closed");
+        return;
+    }
+    session.notify(Message::Connected(userid.clone()));
+    user_data_temp.insert(
         userid.as_byte(),
         UserDataTemp {
             send: Mutex::new(Box::new(write)),
             handle: HandleWrapper(tokio::spawn(async move {
                 defer!(warn!("connection closed"));
+                // 接続が切れたことを通知する
+                // shutdownで閉じた場合はshutdown側で通知する
+                let notify_closed = |session: &RYOKUCHATSession, e: &Error| {
+                    session.notify_storage_error(e);
+                    session.notify(Message::Disconnected(userid.clone()));
+                };
                 loop {
-                    let a = process_message2(session, &userid, &mut read).await;
-                    if a.is_none() {
-                        session
-                            .user_data_temp
-                            .write()
-                            .await
-                            .remove(&userid.as_byte());
+                    // 受信を待っている間はセッションを保持しないようにする
+                    let a = match receive_message(&userid, &mut read).await {
+                        Ok(msg) => match RYOKUCHATSession::upgrade(&weak) {
+                            Ok(session) => process_message2(&session, &userid, msg).await,
+                            Err(_) => return,
+                        },
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = a {
+                        if let Ok(session) = RYOKUCHATSession::upgrade(&weak) {
+                            notify_closed(&session, &e);
+                            session
+                                .inner
+                                .user_data_temp
+                                .write()
+                                .await
+                                .remove(&userid.as_byte());
+                        }
                         return;
                     }
                 }
@@ -73,132 +116,485 @@ pub async fn process_message<
     );
 }
 
-async fn process_message2<
+// 相手にメッセージを1つ送信する(長さ､メッセージ本体､keyでの署名の順)
+pub async fn write_message<T: AsyncWrite + std::marker::Unpin + ?Sized>(
+    write: &mut T,
+    key: &PrivateKey,
+    data: &[u8],
+) -> Result<(), Error> {
+    trace!("write_message() is called.");
+    defer!(trace!("reterning from write_message()"));
+
+    let data_sign = key.sign(data, None).err_into(Error::Protocol)?;
+    write
+        .write_all(&(data.len() as u64).to_be_bytes())
+        .await
+        .err_into(Error::Transport)?;
+    write.write_all(data).await.err_into(Error::Transport)?;
+    write
+        .write_all(&data_sign)
+        .await
+        .err_into(Error::Transport)?;
+    write.flush().await.err_into(Error::Transport)?;
+    Ok(())
+}
+
+// 相手からのメッセージを1つ受信し､署名を検証する
+async fn receive_message<
     T: AsyncRead + std::marker::Send + std::marker::Sync + std::marker::Unpin,
 >(
-    session: &RYOKUCHATSession,
     userid: &PublicKey,
     read: &mut T,
-) -> Option<()> {
-    trace!("process_message2() is called.");
-    defer!(trace!("reterning from process_message2()"));
+) -> Result<MessageForNetwork, Error> {
+    trace!("receive_message() is called.");
+    defer!(trace!("reterning from receive_message()"));
 
     // メッセージのサイズを受信
     let mut len = [0; 8];
-    read.read_exact(&mut len).await.ok()?;
+    // 相手が接続を閉じた場合もここでエラーになるため、ログには出さない
+    read.read_exact(&mut len)
+        .await
+        .map_err(|e| Error::Transport(e.to_string()))?;
     debug!("new message come");
 
     // 受け取ったデータを処理
     let mut len = Cursor::new(len);
-    let len = byteorder::ReadBytesExt::read_u64::<BigEndian>(&mut len)
-        .err_exec(|e| error!("{}", e))
-        .ok()?;
+    let len = byteorder::ReadBytesExt::read_u64::<BigEndian>(&mut len).err_into(Error::Protocol)?;
     let len: usize = TryFrom::try_from(len)
-        .err_exec(|_| error!("32bit CPUs are not officially supported"))
-        .ok()?;
+        .err_into(|_| Error::Protocol("32bit CPUs are not officially supported".to_string()))?;
     debug!("new message's size is {} byte", len);
 
     // メッセージのサイズが最大値を超えていたらエラー
     if len >= MAXMSGLEN {
-        error!("message's size must be under 126000");
-        return None;
+        error!("message's size must be under {}", MAXMSGLEN);
+        return Err(Error::Protocol(format!(
+            "message's size must be under {}",
+            MAXMSGLEN
+        )));
     }
 
     // メッセージを受信(lenバイトはメッセージ本体､SIG_LENGTHバイトは署名)
     let mut msg = vec![0; len + SIG_LENGTH];
-    read.read_exact(&mut msg).await.ok()?;
+    read.read_exact(&mut msg).await.err_into(Error::Transport)?;
     // 署名を検証
     userid
         .verify(&msg[..len], &msg[len..], None)
-        .err_exec(|e| error!("{}", e))
-        .ok()?;
+        .err_into(Error::Protocol)?;
     // メッセージをデシリアライズ
-    let msg: MessageForNetwork = bincode::deserialize(&msg[..len])
-        .err_exec(|_| error!("wrong message format"))
-        .ok()?;
+    bincode::deserialize(&msg[..len])
+        .err_into(|_| Error::Protocol("wrong message format".to_string()))
+}
+
+// 受信したメッセージを処理する
+async fn process_message2(
+    session: &RYOKUCHATSession,
+    userid: &PublicKey,
+    msg: MessageForNetwork,
+) -> Result<(), Error> {
+    trace!("process_message2() is called.");
+    defer!(trace!("reterning from process_message2()"));
 
     match msg {
         MessageForNetwork::DirectMsg(msg) => {
             // stub: メッセージ履歴の保存を実装
             if msg.is_empty() {
                 error!("empty message is not allowed");
-                return None;
+                return Err(Error::EmptyMessage);
             }
             session.new_lastupdate(userid).await?;
 
-            match &mut *session.notify.lock().await {
-                Some(s) => {
-                    let _ = s.send(Message::DirectMsg(userid.clone(), msg)).await;
-                }
-                None => {
-                    warn!("session.notify is not set");
-                }
+            session.notify(Message::DirectMsg(userid.clone(), msg));
+
+            Ok(())
+        }
+        MessageForNetwork::MigrateHostname(hostname, client_auth) => {
+            check_hostname(&hostname, session.inner.tor_transport.is_some())?;
+            let client_auth = client_auth.as_deref().map(check_client_auth).transpose()?;
+            info!("the other party moved to {}", &hostname);
+
+            session.migrate_user(userid, &hostname, client_auth).await
+        }
+        MessageForNetwork::RotateIdentity(new_id, old_sign, new_sign) => {
+            let new_id = <[u8; KEY_LENGTH]>::try_from(new_id.as_slice())
+                .err_into(|_| Error::Protocol("the new ID is invalid".to_string()))?;
+            let new_id = PublicKey::try_from(&new_id).err_into(Error::Protocol)?;
+            if new_id.as_byte() == userid.as_byte() {
+                error!("the new ID is the same as the old one");
+                return Err(Error::Protocol("the new ID is not new".to_string()));
             }
+            // 古い鍵と新しい鍵の両方が同じ宣言に署名していることを確かめる
+            let statement = rotation_statement(userid, &new_id);
+            userid
+                .verify(&statement, &old_sign, None)
+                .err_into(Error::Protocol)?;
+            new_id
+                .verify(&statement, &new_sign, None)
+                .err_into(Error::Protocol)?;
+            info!("the other party rotated the identity key");
 
-            Some(())
+            session.rotate_user(userid, &new_id).await
         }
     }
 }
 
-pub fn decode_address(address: &str) -> Option<UserData> {
+pub fn decode_address(address: &str) -> Result<UserData, Error> {
     trace!("RYOKUCHATSession::decode_address() is called");
     defer!(trace!("returning from RYOKUCHATSession::decode_address()"));
 
     let address = address.trim();
     debug!("address is {}", address);
 
-    let mut address = address.split('@');
+    let mut address = address.splitn(2, '@');
 
     let key = address
         .next()
-        .err_exec(|_| error!("something went wrong"))?;
-    let key = base64::decode_config(key, base64::URL_SAFE_NO_PAD)
-        .err_exec(|e| error!("{}", e))
-        .ok()?;
+        .err_into(|_| Error::Address("something went wrong".to_string()))?;
+    let key = base64::decode_config(key, base64::URL_SAFE_NO_PAD).err_into(Error::Address)?;
 
     let hostname = address
         .next()
-        .err_exec(|_| error!("wrong format"))?
-        .to_string();
+        .err_into(|_| Error::Address("wrong format".to_string()))?;
+
+    // #の後ろはHidden Serviceのクライアント認証の公開鍵
+    let (hostname, client_auth) = match hostname.split_once('#') {
+        Some((hostname, client_auth)) => (hostname, Some(check_client_auth(client_auth)?)),
+        None => (hostname, None),
+    };
+    check_hostname(hostname, false)?;
 
     UserDataRaw {
         id: key,
-        hostname,
+        hostname: hostname.to_string(),
         username: None,
+        client_auth,
     }
     .to_userdata()
 }
 
-pub fn greeting_auth(auth: &[u8]) -> Option<[u8; 16]> {
+// 連絡先のホスト名として使えるか確かめる
+// アドレスの一部になり、ControlPortのコマンドにも使われるため、区切りに使う文字や空白は受け付けない
+// onionがtrueの場合は、Socksプロキシで任意の場所に接続させられないようにv3の.onionに限る
+pub fn check_hostname(hostname: &str, onion: bool) -> Result<(), Error> {
+    if hostname.is_empty()
+        || hostname
+            .chars()
+            .any(|c| c == '@' || c == '#' || c.is_whitespace() || c.is_control())
+        || (onion && !is_onion_v3(hostname))
+    {
+        error!("the hostname is invalid: {:?}", hostname);
+        return Err(Error::Address("the hostname is invalid".to_string()));
+    }
+    Ok(())
+}
+
+// v3のHidden Serviceのホスト名か確かめる
+// ServiceIDは公開鍵(32バイト)、チェックサム(2バイト)、バージョン(3)をBase32にした56文字
+pub fn is_onion_v3(hostname: &str) -> bool {
+    let service_id = match hostname.strip_suffix(".onion") {
+        Some(s) if s.len() == 56 => s.to_ascii_uppercase(),
+        _ => return false,
+    };
+    match base32::decode(base32::Alphabet::RFC4648 { padding: false }, &service_id) {
+        Some(o) => o.len() == 35 && o[34] == 3,
+        None => false,
+    }
+}
+
+// クライアント認証の公開鍵がBase32の32バイトになっているか確かめ、大文字にして返す
+pub fn check_client_auth(client_auth: &str) -> Result<String, Error> {
+    let client_auth = client_auth.to_ascii_uppercase();
+    match base32::decode(base32::Alphabet::RFC4648 { padding: false }, &client_auth) {
+        Some(o) if o.len() == 32 => Ok(client_auth),
+        _ => {
+            error!("the client authorization key is invalid");
+            Err(Error::Address(
+                "the client authorization key is invalid".to_string(),
+            ))
+        }
+    }
+}
+
+pub fn greeting_auth(auth: &[u8]) -> Result<[u8; 16], Error> {
     trace!("greeting_auth() is called");
     defer!(trace!("returning from greeting_auth()"));
 
     let mut auth = Cursor::new(auth);
-    let auth = byteorder::ReadBytesExt::read_u128::<BigEndian>(&mut auth).ok()?;
+    let auth =
+        byteorder::ReadBytesExt::read_u128::<BigEndian>(&mut auth).err_into(Error::Handshake)?;
     debug!("authentication message is {}", auth);
 
-    Some(auth.to_le_bytes())
+    Ok(auth.to_le_bytes())
 }
 
-pub async fn try_open_read<
-    F: Fn(fs::File) -> R,
-    R: Future<Output = Result<(), Box<dyn std::error::Error>>>,
->(
-    path: &std::path::Path,
-    initfn: F,
-) -> Result<fs::File, Box<dyn std::error::Error>> {
-    if let Ok(o) = fs::File::open(&path).await {
-        return Ok(o);
+// IDを変えるときに古い鍵と新しい鍵の両方で署名する宣言
+// 他の用途の署名と取り違えないように、先頭に目的を表す文字列を付ける
+pub fn rotation_statement(old_id: &PublicKey, new_id: &PublicKey) -> Vec<u8> {
+    let mut statement = b"RYOKUCHAT identity rotation v1\0".to_vec();
+    statement.extend_from_slice(&old_id.as_byte());
+    statement.extend_from_slice(&new_id.as_byte());
+    statement
+}
+
+// Torやsqlxに渡すためにパスを文字列に変換する
+pub fn path_to_str(path: &std::path::Path) -> Result<&str, Error> {
+    path.to_str()
+        .err_into(|_| Error::Config(format!("{:?} is not a valid UTF-8 path", path)))
+}
+
+// 一時ファイルに書き込んでから置き換え、途中で失敗しても元の内容が残るようにする
+pub async fn replace_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".new");
+    let temp = PathBuf::from(temp);
+
+    let mut f = fs::File::create(&temp)
+        .await
+        .err_into(|e| Error::KeyFile(format!("could not create {:?}: {}", &temp, e)))?;
+    f.write_all(data).await?;
+    f.sync_all().await?;
+    drop(f);
+    fs::rename(&temp, path)
+        .await
+        .err_into(|e| Error::KeyFile(format!("could not replace {:?}: {}", path, e)))?;
+
+    // 名前の変更もディスクに書き出す
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(s) if !s.as_os_str().is_empty() => s,
+            _ => Path::new("."),
+        };
+        if let Err(e) = fs::File::open(dir).await?.sync_all().await {
+            warn!("could not sync {:?}: {}", dir, e);
+        }
     }
+    Ok(())
+}
+
+// 空いているポートをcount個探す
+// 一度bindしてすぐに閉じるので、Torが使うまでの間に他のプログラムに取られる可能性はある
+#[cfg(feature = "embedded-tor")]
+pub fn free_ports(address: std::net::IpAddr, count: usize) -> Result<Vec<u16>, Error> {
+    let listeners = (0..count)
+        .map(|_| std::net::TcpListener::bind((address, 0)))
+        .collect::<Result<Vec<_>, _>>()
+        .err_exec(|e| error!("{}", e))?;
+    let ports = listeners
+        .iter()
+        .map(|l| Ok(l.local_addr()?.port()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    debug!("free ports are {:?}", &ports);
+    Ok(ports)
+}
+
+// 全てのsubscribeしているStreamに通知を送る
+pub fn send_event(events: &broadcast::Sender<Message>, message: Message) {
+    if events.send(message).is_err() {
+        debug!("nobody is subscribing");
+    }
+}
 
-    if let Ok(o) = fs::File::create(&path).await {
-        initfn(o).await?;
-        return Ok(fs::File::open(&path).await?);
+// 通知を受け取るためのStreamを作る
+// 受け取りが遅れて捨てられた通知がある場合は、その数をMessage::Laggedで知らせる
+pub fn event_stream(
+    receiver: broadcast::Receiver<Message>,
+) -> impl Stream<Item = Message> + Send + Unpin + 'static {
+    BroadcastStream::new(receiver).map(|m| match m {
+        Ok(o) => o,
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("{} events are dropped", n);
+            Message::Lagged(n)
+        }
+    })
+}
+
+// TorのHiddenServiceDirにあるhs_ed25519_secret_keyを読み、ADD_ONIONで使える形式にする
+// ファイルが無い場合はNoneを返す
+// ファイルは32バイトのヘッダーと64バイトの秘密鍵からなる
+pub async fn migrate_onion_key(path: &Path) -> Result<Option<String>, Error> {
+    const HEADER: &[u8] = b"== ed25519v1-secret: type0 ==";
+
+    let data = match fs::read(path).await {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            error!("{}", e);
+            return Err(Error::KeyFile(format!("could not read {:?}: {}", path, e)));
+        }
+    };
+    if data.len() != 96 || !data.starts_with(HEADER) {
+        error!("{:?} is not an ed25519 secret key of Tor", path);
+        return Err(Error::KeyFile(format!(
+            "{:?} is not an ed25519 secret key of Tor",
+            path
+        )));
     }
 
-    panic!("could not open and create {:?}", path);
+    Ok(Some(format!("ED25519-V3:{}", base64::encode(&data[32..]))))
+}
+
+// Hidden Serviceのクライアント認証に使う鍵を読み出す
+// ファイルが無い場合は新しく作る
+// パスフレーズが指定されている場合は暗号化して保存する
+pub async fn load_client_auth_key(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<ClientAuthKey, Error> {
+    trace!("load_client_auth_key() is called");
+    defer!(trace!("returning from load_client_auth_key()"));
+
+    let secret = match read_key_file(path, KeyType::ClientAuth, passphrase).await? {
+        // 長さはread_key_fileで確かめてある
+        Some(s) => <[u8; 32]>::try_from(s.as_slice())
+            .err_into(|_| Error::KeyFile(format!("{:?} has a wrong length", path)))?,
+        None => {
+            info!("generating new client authorization key");
+            let secret = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+            write_key_file(path, KeyType::ClientAuth, secret.as_bytes(), passphrase).await?;
+            secret.to_bytes()
+        }
+    };
+
+    let secret = x25519_dalek::StaticSecret::from(secret);
+    let public = x25519_dalek::PublicKey::from(&secret);
+    Ok(ClientAuthKey {
+        secret: base64::encode(secret.as_bytes()),
+        public: base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            public.as_bytes(),
+        ),
+    })
+}
+
+// 自分のHidden Serviceへの接続を許可するクライアント認証の公開鍵を並べる
+// 連絡先がいない場合もHidden Serviceの情報を公開しないように、自分の鍵を必ず含める
+pub fn authorized_clients<'a>(
+    own: &'a ClientAuthKey,
+    users: impl Iterator<Item = &'a Option<String>>,
+) -> Vec<&'a str> {
+    let mut clients = vec![own.public.as_str()];
+    for client_auth in users.flatten() {
+        if !clients.contains(&client_auth.as_str()) {
+            clients.push(client_auth);
+        }
+    }
+    clients
+}
+
+// ClientAuthRegistryを変えた結果をTorに送る
+pub async fn apply_client_auth<T: AsyncRead + AsyncWrite + std::marker::Unpin>(
+    control: &mut ControlConnection<T>,
+    service_id: &str,
+    update: ClientAuthUpdate,
+) -> Result<(), Error> {
+    match update {
+        ClientAuthUpdate::Keep => Ok(()),
+        ClientAuthUpdate::Add(secret) => control.add_client_auth(service_id, &secret).await,
+        ClientAuthUpdate::Remove => control.remove_client_auth(service_id).await,
+    }
+}
+
+// 各ポートをbindするアドレスと、それを(アドレス):(ポート)の形式で使うための文字列を返す
+// 指定されていない場合はlocalhostを名前解決する
+pub async fn local_address(bind_address: Option<IpAddr>) -> Result<(IpAddr, String), Error> {
+    let bind_address = match bind_address {
+        Some(s) => s,
+        None => tokio::net::lookup_host("localhost:1")
+            .await
+            .err_exec(|e| error!("{}", e))?
+            .next()
+            .err_into(|_| Error::Config("could not resolve localhost".to_string()))?
+            .ip(),
+    };
+
+    let localhost = match bind_address {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => "[".to_string() + &v6.to_string() + "]",
+    };
+    debug!("localhost is {}", localhost);
+    Ok((bind_address, localhost))
+}
+
+// STATUS_CLIENTイベントを受け取り、Torの起動が終わるまで待つ
+// 進み具合はprogressに入れ、Message::TorBootstrapで通知する
+// stuck_afterの間進捗が変わらない場合や、Torが問題を報告した場合はMessage::BootstrapStuckを送る
+// proxyがtrueの場合、プロキシに接続する段階でTorが報告した問題はMessage::ProxyErrorでも送る
+pub async fn wait_bootstrap<T: AsyncRead + AsyncWrite + std::marker::Unpin>(
+    control: &mut ControlConnection<T>,
+    events: &broadcast::Sender<Message>,
+    progress: &mut (u8, String),
+    stuck_after: Duration,
+    proxy: bool,
+) -> Result<(), Error> {
+    trace!("wait_bootstrap() is called");
+    defer!(trace!("returning from wait_bootstrap()"));
+
+    control.set_events(&["STATUS_CLIENT"]).await?;
+    let mut status = control.get_info("status/bootstrap-phase").await?;
+    let mut changed = Instant::now();
+    let mut stuck = stuck_after.is_zero();
+    loop {
+        if let Some((p, summary)) = parse_bootstrap(&status) {
+            debug!("bootstrapped {}%: {}", p, &summary);
+            if p != progress.0 {
+                changed = Instant::now();
+                stuck = stuck_after.is_zero();
+            }
+            *progress = (p, summary.clone());
+            send_event(events, Message::TorBootstrap(p, summary));
+            if p >= 100 {
+                break;
+            }
+
+            // ブリッジに接続できない場合などは、WARNとその理由が送られてくる
+            if let Some((_, keywords)) = status
+                .strip_prefix("WARN ")
+                .and_then(|s| s.split_once("BOOTSTRAP "))
+            {
+                let mut keywords = parse_keywords(keywords);
+                if keywords.get("RECOMMENDATION").map(|s| s.as_str()) == Some("warn") {
+                    let warning = keywords
+                        .remove("WARNING")
+                        .unwrap_or_else(|| progress.1.clone());
+                    warn!("Tor has a problem while bootstrapping: {}", &warning);
+                    // リレーやブリッジそのものの問題は、プロキシのせいにしない
+                    let proxy_phase = keywords.get("TAG").is_some_and(|tag| {
+                        matches!(
+                            tag.as_str(),
+                            "conn_proxy"
+                                | "conn_done_proxy"
+                                | "ap_conn_proxy"
+                                | "ap_conn_done_proxy"
+                        )
+                    });
+                    if proxy && proxy_phase {
+                        send_event(events, Message::ProxyError(warning.clone()));
+                    }
+                    send_event(events, Message::BootstrapStuck(p, warning));
+                }
+            }
+        }
+
+        // next_eventは途中で止めると読みかけの応答が失われるため、時間が過ぎても待ち続ける
+        let next = control.next_event();
+        tokio::pin!(next);
+        let event = loop {
+            tokio::select! {
+                event = &mut next => break event?,
+                _ = tokio::time::sleep_until(changed + stuck_after), if !stuck => {
+                    stuck = true;
+                    warn!("bootstrapping is stuck at {}%", progress.0);
+                    send_event(events, Message::BootstrapStuck(progress.0, progress.1.clone()));
+                }
+            }
+        };
+        status = event.body;
+    }
+    control.set_events(&[]).await?;
+    Ok(())
 }
 
+#[cfg(feature = "embedded-tor")]
 pub fn passwd_gen() -> String {
     let mut passwd = String::with_capacity(32);
     for _ in 0..32 {
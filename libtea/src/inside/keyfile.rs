@@ -0,0 +1,426 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// 秘密鍵のファイル(.ykr)を読み書きする
+// 書き込みは一時ファイルに書いてディスクに書き出してから置き換えるので、途中で止まっても壊れたファイルは残らない
+//
+// ファイルの形式
+// 0..8: マジックナンバー "RYOKUKEY"
+// 8: バージョン
+// 9: 鍵の種類 (KeyType)
+// 10: 暗号化の方式 (0: 暗号化しない、1: Argon2idとXChaCha20-Poly1305)
+// 11..15: 中身の長さ (u32のビッグエンディアン)
+// 15..: 中身
+// 最後の32バイト: それより前の全体のBLAKE2s-256 (壊れたファイルを、パスフレーズの間違いと区別して見つけるため)
+//
+// 暗号化した場合の中身
+// 0..12: Argon2idのメモリ(KiB)、繰り返し回数、並列度 (それぞれu32のビッグエンディアン)
+// 12..28: ソルト
+// 28..52: ノンス
+// 52..: 暗号化した鍵と認証タグ
+// 中身のここまでとヘッダーを書き換えられないように、追加の認証データとして使う
+//
+// 以前のバージョンで保存された、ヘッダーの無い57バイトの秘密鍵も読み込み、今の形式で保存し直す
+
+use std::path::Path;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use ed448_rust::PrivateKey;
+use rand::RngCore;
+use tokio::fs;
+
+use crate::{
+    consts::KEY_LENGTH,
+    inside::{functions::replace_file, structs::ErrInto},
+    Error,
+};
+
+const MAGIC: &[u8; 8] = b"RYOKUKEY";
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + 1 + 1 + 4;
+const CHECKSUM_LENGTH: usize = 32;
+// 中身が大きすぎるファイルは鍵ではない
+const MAX_PAYLOAD_LENGTH: usize = 4096;
+// バックアップにはデータベースが丸ごと入る
+const MAX_BACKUP_LENGTH: usize = 1024 * 1024 * 1024;
+const NOT_ENCRYPTED: u8 = 0;
+const ENCRYPTED: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+const KDF_LENGTH: usize = 12 + SALT_LENGTH + NONCE_LENGTH;
+// 新しく暗号化するときのArgon2idの設定
+const MEMORY_COST: u32 = 64 * 1024;
+const TIME_COST: u32 = 3;
+const PARALLELISM: u32 = 1;
+// ファイルに書かれた設定で、メモリを使い果たしたりいつまでも終わらなかったりしないようにする上限
+const MAX_MEMORY_COST: u32 = 1024 * 1024;
+const MAX_TIME_COST: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+// ファイルに入っている鍵の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    // 自分のIDになるEd448の秘密鍵
+    Identity = 1,
+    // ADD_ONIONに渡す形式のHidden Serviceの秘密鍵
+    OnionService = 2,
+    // Hidden Serviceのクライアント認証に使うx25519の秘密鍵
+    ClientAuth = 3,
+    // export_backupで作るバックアップ
+    Backup = 4,
+    // rotate_identityで使わなくなったEd448の秘密鍵を並べたもの
+    PreviousIdentity = 5,
+}
+
+impl KeyType {
+    fn name(self) -> &'static str {
+        match self {
+            KeyType::Identity => "identity",
+            KeyType::OnionService => "onion service",
+            KeyType::ClientAuth => "client authorization",
+            KeyType::Backup => "backup",
+            KeyType::PreviousIdentity => "previous identity",
+        }
+    }
+
+    fn max_length(self) -> usize {
+        match self {
+            KeyType::Backup => MAX_BACKUP_LENGTH,
+            _ => MAX_PAYLOAD_LENGTH,
+        }
+    }
+
+    // 鍵として正しい内容かを確かめる
+    fn is_valid(self, payload: &[u8]) -> bool {
+        match self {
+            KeyType::Identity => payload.len() == KEY_LENGTH,
+            KeyType::OnionService => {
+                payload.starts_with(b"ED25519-V3:") && std::str::from_utf8(payload).is_ok()
+            }
+            KeyType::ClientAuth => payload.len() == 32,
+            // 中身はinside::backupで確かめる
+            KeyType::Backup => !payload.is_empty(),
+            KeyType::PreviousIdentity => {
+                !payload.is_empty() && payload.len().is_multiple_of(KEY_LENGTH)
+            }
+        }
+    }
+}
+
+// ファイルの中から取り出したもの
+enum Decoded<'a> {
+    Plain(&'a [u8]),
+    // 追加の認証データと、暗号化された鍵
+    Encrypted { aad: &'a [u8], ciphertext: &'a [u8] },
+    // ヘッダーの無い以前の形式の秘密鍵
+    Legacy(&'a [u8]),
+}
+
+// 鍵を読み出す
+// ファイルが無い場合はNoneを返す
+// 以前の形式のファイルや、パスフレーズが指定されたのに暗号化されていない鍵は、今の形式で保存し直す
+pub async fn read_key_file(
+    path: &Path,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<Option<Vec<u8>>, Error> {
+    trace!("read_key_file() is called");
+    defer!(trace!("returning from read_key_file()"));
+
+    let data = match fs::read(path).await {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            error!("{}", e);
+            return Err(Error::KeyFile(format!("could not read {:?}: {}", path, e)));
+        }
+    };
+    let corrupted = |reason: &str| {
+        error!("{:?} is corrupted: {}", path, reason);
+        Error::KeyFile(format!("{:?} is corrupted: {}", path, reason))
+    };
+
+    let (payload, rewrite) = match decode(&data, key_type).map_err(|e| corrupted(&e))? {
+        Decoded::Plain(payload) => (payload.to_vec(), passphrase.is_some()),
+        Decoded::Encrypted { aad, ciphertext } => {
+            let passphrase = passphrase.err_into(|_| Error::Locked)?;
+            (decrypt(aad, ciphertext, passphrase).await?, false)
+        }
+        Decoded::Legacy(payload) => (payload.to_vec(), true),
+    };
+    if !key_type.is_valid(&payload) {
+        return Err(corrupted(&format!("not a valid {} key", key_type.name())));
+    }
+
+    if rewrite {
+        info!("rewriting {:?} in the current format", path);
+        write_key_file(path, key_type, &payload, passphrase).await?;
+    }
+    Ok(Some(payload))
+}
+
+// 鍵を書き込む
+// パスフレーズがNoneの場合は暗号化しない
+pub async fn write_key_file(
+    path: &Path,
+    key_type: KeyType,
+    payload: &[u8],
+    passphrase: Option<&str>,
+) -> Result<(), Error> {
+    trace!("write_key_file() is called");
+    defer!(trace!("returning from write_key_file()"));
+
+    let data = encode(key_type, payload, passphrase).await?;
+    replace_file(path, &data).await?;
+    debug!("{:?} is written", path);
+    Ok(())
+}
+
+// 自分のIDになる秘密鍵を読み出す
+// ファイルが無い場合は新しく作り、パスフレーズがあれば暗号化して保存する
+pub async fn load_secret_key(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<[u8; KEY_LENGTH], Error> {
+    trace!("load_secret_key() is called");
+    defer!(trace!("returning from load_secret_key()"));
+
+    if let Some(key) = read_key_file(path, KeyType::Identity, passphrase).await? {
+        // 長さはread_key_fileで確かめてある
+        return <[u8; KEY_LENGTH]>::try_from(key.as_slice())
+            .err_into(|_| Error::KeyFile(format!("{:?} has a wrong length", path)));
+    }
+
+    info!("generating new secretkey");
+    let key = *PrivateKey::new(&mut rand::rngs::OsRng).as_bytes();
+    write_key_file(path, KeyType::Identity, &key, passphrase).await?;
+    Ok(key)
+}
+
+// rotate_identityで使わなくなった秘密鍵を読み出す
+// ファイルが無い場合は空のVecを返す
+pub async fn read_previous_keys(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<Vec<[u8; KEY_LENGTH]>, Error> {
+    trace!("read_previous_keys() is called");
+    defer!(trace!("returning from read_previous_keys()"));
+
+    let keys = match read_key_file(path, KeyType::PreviousIdentity, passphrase).await? {
+        Some(o) => o,
+        None => return Ok(Vec::new()),
+    };
+    // 長さはread_key_fileで確かめてある
+    keys.chunks_exact(KEY_LENGTH)
+        .map(|k| {
+            <[u8; KEY_LENGTH]>::try_from(k)
+                .err_into(|_| Error::KeyFile(format!("{:?} has a wrong length", path)))
+        })
+        .collect()
+}
+
+// rotate_identityで使わなくなった秘密鍵を書き込む
+// 残す鍵が無い場合はファイルを消す
+pub async fn write_previous_keys(
+    path: &Path,
+    keys: &[[u8; KEY_LENGTH]],
+    passphrase: Option<&str>,
+) -> Result<(), Error> {
+    trace!("write_previous_keys() is called");
+    defer!(trace!("returning from write_previous_keys()"));
+
+    if keys.is_empty() {
+        return match fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("{}", e);
+                Err(Error::KeyFile(format!(
+                    "could not remove {:?}: {}",
+                    path, e
+                )))
+            }
+            _ => Ok(()),
+        };
+    }
+    write_key_file(path, KeyType::PreviousIdentity, &keys.concat(), passphrase).await
+}
+
+// ファイルに書く内容を作る
+async fn encode(
+    key_type: KeyType,
+    payload: &[u8],
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>, Error> {
+    let (encryption, length) = match passphrase {
+        Some("") => {
+            error!("the passphrase is empty");
+            return Err(Error::Config("the passphrase is empty".to_string()));
+        }
+        Some(_) => (ENCRYPTED, KDF_LENGTH + payload.len() + TAG_LENGTH),
+        None => (NOT_ENCRYPTED, payload.len()),
+    };
+    if length > key_type.max_length() {
+        error!("the {} is too large", key_type.name());
+        return Err(Error::KeyFile(format!(
+            "the {} is too large",
+            key_type.name()
+        )));
+    }
+
+    let mut data = Vec::with_capacity(HEADER_LENGTH + length + CHECKSUM_LENGTH);
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.push(key_type as u8);
+    data.push(encryption);
+    data.extend_from_slice(&(length as u32).to_be_bytes());
+
+    match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0; SALT_LENGTH];
+            rand::rngs::OsRng.fill_bytes(&mut salt);
+            let mut nonce = [0; NONCE_LENGTH];
+            rand::rngs::OsRng.fill_bytes(&mut nonce);
+            data.extend_from_slice(&MEMORY_COST.to_be_bytes());
+            data.extend_from_slice(&TIME_COST.to_be_bytes());
+            data.extend_from_slice(&PARALLELISM.to_be_bytes());
+            data.extend_from_slice(&salt);
+            data.extend_from_slice(&nonce);
+
+            let cipher_key =
+                derive_key(passphrase, salt, MEMORY_COST, TIME_COST, PARALLELISM).await?;
+            let encrypted = XChaCha20Poly1305::new(&cipher_key.into())
+                .encrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: payload,
+                        aad: &data,
+                    },
+                )
+                .err_into(|e| Error::KeyFile(format!("could not encrypt the key: {}", e)))?;
+            data.extend_from_slice(&encrypted);
+        }
+        None => data.extend_from_slice(payload),
+    }
+
+    let checksum = Blake2s256::digest(&data);
+    data.extend_from_slice(&checksum);
+    Ok(data)
+}
+
+// ファイルの内容を確かめて、鍵やその暗号文を取り出す
+// 壊れている理由を返す
+fn decode(data: &[u8], key_type: KeyType) -> Result<Decoded<'_>, String> {
+    if !data.starts_with(MAGIC) {
+        // 以前のバージョンでは、秘密鍵だけをそのまま保存していた
+        if key_type == KeyType::Identity && key_type.is_valid(data) {
+            return Ok(Decoded::Legacy(data));
+        }
+        return Err("unknown format".to_string());
+    }
+
+    match data.get(MAGIC.len()) {
+        Some(&VERSION) => (),
+        Some(v) => return Err(format!("unsupported version {}", v)),
+        None => return Err("truncated".to_string()),
+    }
+    if data.len() < HEADER_LENGTH + CHECKSUM_LENGTH {
+        return Err("truncated".to_string());
+    }
+
+    let (body, checksum) = data.split_at(data.len() - CHECKSUM_LENGTH);
+    if Blake2s256::digest(body).as_slice() != checksum {
+        return Err("checksum mismatch".to_string());
+    }
+    let length = u32::from_be_bytes([body[11], body[12], body[13], body[14]]) as usize;
+    if body.len() != HEADER_LENGTH + length || length > key_type.max_length() {
+        return Err("wrong length".to_string());
+    }
+    if body[9] != key_type as u8 {
+        return Err(format!("not a {} key", key_type.name()));
+    }
+
+    match body[10] {
+        NOT_ENCRYPTED => Ok(Decoded::Plain(&body[HEADER_LENGTH..])),
+        ENCRYPTED if length >= KDF_LENGTH + TAG_LENGTH => {
+            let (aad, ciphertext) = body.split_at(HEADER_LENGTH + KDF_LENGTH);
+            Ok(Decoded::Encrypted { aad, ciphertext })
+        }
+        ENCRYPTED => Err("wrong length".to_string()),
+        e => Err(format!("unknown encryption {}", e)),
+    }
+}
+
+// 暗号化された鍵を取り出す
+// 追加の認証データの最後にArgon2idの設定、ソルト、ノンスが入っている
+// パスフレーズが間違っている場合はError::Lockedになる
+async fn decrypt(aad: &[u8], ciphertext: &[u8], passphrase: &str) -> Result<Vec<u8>, Error> {
+    let kdf = &aad[aad.len() - KDF_LENGTH..];
+    let number = |i: usize| u32::from_be_bytes([kdf[i], kdf[i + 1], kdf[i + 2], kdf[i + 3]]);
+    let (memory_cost, time_cost, parallelism) = (number(0), number(4), number(8));
+    if memory_cost > MAX_MEMORY_COST || time_cost > MAX_TIME_COST || parallelism > MAX_PARALLELISM {
+        error!("the KDF parameters in the key file are too large");
+        return Err(Error::KeyFile(
+            "the KDF parameters in the key file are too large".to_string(),
+        ));
+    }
+    let mut salt = [0; SALT_LENGTH];
+    salt.copy_from_slice(&kdf[12..12 + SALT_LENGTH]);
+    let nonce = &kdf[12 + SALT_LENGTH..];
+
+    let cipher_key = derive_key(passphrase, salt, memory_cost, time_cost, parallelism).await?;
+    XChaCha20Poly1305::new(&cipher_key.into())
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .err_into(|_| Error::Locked)
+}
+
+// パスフレーズからArgon2idで暗号化の鍵を作る
+// 時間がかかるので、別のスレッドで計算する
+async fn derive_key(
+    passphrase: &str,
+    salt: [u8; SALT_LENGTH],
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+) -> Result<[u8; 32], Error> {
+    let passphrase = passphrase.as_bytes().to_vec();
+    tokio::task::spawn_blocking(move || {
+        let params = Params::new(memory_cost, time_cost, parallelism, Some(32))
+            .err_into(|e| Error::KeyFile(format!("invalid KDF parameters: {}", e)))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&passphrase, &salt, &mut key)
+            .err_into(|e| Error::KeyFile(format!("could not derive the key: {}", e)))?;
+        Ok(key)
+    })
+    .await
+    .err_into(Error::KeyFile)?
+}
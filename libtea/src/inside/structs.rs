@@ -19,10 +19,20 @@ You should have received a copy of the GNU General Public License
 along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex as StdMutex, PoisonError},
+};
+
 use ed448_rust::PublicKey;
-use tokio::{io::AsyncWrite, sync::Mutex, task::JoinHandle};
+use tokio::{io::AsyncWrite, net::TcpStream, sync::Mutex, task::JoinHandle};
 
-use crate::UserData;
+#[cfg(feature = "embedded-tor")]
+use crate::SessionBuilder;
+use crate::{
+    tor_control::ControlConnection, transport::TorTransport, ControlAuth, Error, UserData,
+};
 
 // SQLiteに入れておける形式のUserData
 #[derive(sqlx::FromRow)]
@@ -30,19 +40,183 @@ pub struct UserDataRaw {
     pub id: Vec<u8>,
     pub hostname: String,
     pub username: Option<String>,
+    pub client_auth: Option<String>,
 }
 
 impl UserDataRaw {
     // UserDataに変換する
-    pub fn to_userdata(&self) -> Option<UserData> {
-        Some(UserData {
-            id: PublicKey::try_from(self.id.as_slice()).ok()?,
+    pub fn to_userdata(&self) -> Result<UserData, Error> {
+        Ok(UserData {
+            id: PublicKey::try_from(self.id.as_slice()).err_into(Error::Address)?,
             hostname: self.hostname.clone(),
             username: self.username.clone(),
+            client_auth: self.client_auth.clone(),
         })
     }
 }
 
+// Hidden Serviceのクライアント認証に使うx25519の鍵
+// secretはONION_CLIENT_AUTH_ADDで使うBase64、publicはClientAuthV3で使うBase32になる
+#[derive(Clone)]
+pub struct ClientAuthKey {
+    pub secret: String,
+    pub public: String,
+}
+
+// 同じTorに登録したクライアント認証の鍵
+// Torには1つのServiceIDにつき1つの鍵しか登録できず、同じTorを使う全てのセッションで共有されるため、
+// ServiceIDごとに鍵を使っているセッションを覚えておき、最後のセッションが外すまで消さない
+#[derive(Debug, Default)]
+pub struct ClientAuthRegistry {
+    // ServiceIDごとの鍵。先頭の鍵がTorに登録されている
+    keys: StdMutex<HashMap<String, Vec<String>>>,
+}
+
+// ClientAuthRegistryを変えた後に、Torに送るもの
+pub enum ClientAuthUpdate {
+    Keep,
+    Add(String),
+    Remove,
+}
+
+impl ClientAuthRegistry {
+    // service_idにsecretを使うセッションを加える
+    // 他のセッションの鍵が既に登録されている場合は、それをそのまま使う
+    pub fn add(&self, service_id: &str, secret: &str) -> ClientAuthUpdate {
+        let mut keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        let secrets = keys.entry(service_id.to_string()).or_default();
+        if !secrets.iter().any(|s| s == secret) {
+            secrets.push(secret.to_string());
+        }
+        match secrets.first() {
+            Some(s) if s == secret => ClientAuthUpdate::Add(secret.to_string()),
+            _ => ClientAuthUpdate::Keep,
+        }
+    }
+
+    // service_idからsecretを使うセッションを外す
+    // 登録されている鍵だった場合は、残っている他のセッションの鍵に置き換える
+    pub fn remove(&self, service_id: &str, secret: &str) -> ClientAuthUpdate {
+        let mut keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        let secrets = match keys.get_mut(service_id) {
+            Some(o) => o,
+            None => return ClientAuthUpdate::Keep,
+        };
+        let index = match secrets.iter().position(|s| s == secret) {
+            Some(o) => o,
+            None => return ClientAuthUpdate::Keep,
+        };
+        secrets.remove(index);
+        match secrets.first() {
+            None => {
+                keys.remove(service_id);
+                ClientAuthUpdate::Remove
+            }
+            Some(s) if index == 0 => ClientAuthUpdate::Add(s.clone()),
+            Some(_) => ClientAuthUpdate::Keep,
+        }
+    }
+
+    // secretを使っている全てのServiceIDから外す
+    // セッションを終了するときに使う
+    pub fn release(&self, secret: &str) -> Vec<(String, ClientAuthUpdate)> {
+        let service_ids: Vec<_> = self
+            .keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, secrets)| secrets.iter().any(|s| s == secret))
+            .map(|(service_id, _)| service_id.clone())
+            .collect();
+        service_ids
+            .into_iter()
+            .map(|service_id| {
+                let update = self.remove(&service_id, secret);
+                (service_id, update)
+            })
+            .collect()
+    }
+
+    // 登録されている全てのServiceIDと鍵
+    // Torを起動し直したときに登録し直すために使う
+    #[cfg(feature = "embedded-tor")]
+    pub fn registered(&self) -> Vec<(String, String)> {
+        self.keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|(service_id, secrets)| {
+                Some((service_id.clone(), secrets.first()?.clone()))
+            })
+            .collect()
+    }
+}
+
+// ADD_ONIONで公開している自分のHidden Service
+// 連絡先が変わったときに同じ鍵で作り直すために使う
+pub struct OnionService {
+    pub key: String,
+    // 鍵を保存しているファイル
+    // アドレスを変えたときに新しい鍵で上書きする
+    pub key_file: PathBuf,
+    pub service_id: String,
+    pub virtual_port: u16,
+    pub target: String,
+    pub client_key: ClientAuthKey,
+    // クライアント認証を有効にしているかどうか
+    pub client_auth: bool,
+}
+
+// 秘密鍵のファイルのパスフレーズ
+// SessionBuilderのログに出ないように、Debugでは中身を表示しない
+#[derive(Clone)]
+pub struct Passphrase(pub String);
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+// 起動したTorや接続したTorと、そこで公開したHidden Service
+pub struct TorInstance {
+    pub transport: TorTransport,
+    // 組み込みのTorを起動した場合のみSome
+    pub process: Option<JoinHandle<()>>,
+    pub control: ControlConnection<TcpStream>,
+    // 状態を監視するためにControlPortにもう1つ接続するときに使う
+    pub control_address: String,
+    pub control_auth: ControlAuth,
+    pub onion: OnionService,
+    // 組み込みのTorを起動した場合のみSome
+    #[cfg(feature = "embedded-tor")]
+    pub restart: Option<TorRestart>,
+}
+
+// 起動した組み込みのTor
+#[cfg(feature = "embedded-tor")]
+pub struct EmbeddedTor {
+    pub process: JoinHandle<()>,
+    // Torの所有権を持っている接続
+    pub control: ControlConnection<TcpStream>,
+    pub control_address: String,
+    pub control_passwd: String,
+    pub socks_address: String,
+    pub socks_port: u16,
+    pub control_port: u16,
+}
+
+// 組み込みのTorを起動し直すときに使う設定
+#[cfg(feature = "embedded-tor")]
+pub struct TorRestart {
+    // SocksPortとControlPortは、最初に起動したときのポートに固定してある
+    pub builder: SessionBuilder,
+    pub tor_dir: PathBuf,
+    pub tor_config: PathBuf,
+    // Hidden Serviceの転送先になっているポート
+    pub ryokuchat_port: u16,
+}
+
 // ユーザー情報のうち､ストレージに保存する必要が無いもの
 #[allow(dead_code)]
 pub struct UserDataTemp {
@@ -54,16 +228,45 @@ pub struct UserDataTemp {
 // drop時にスレッドを終了するラッパー
 pub struct HandleWrapper(pub JoinHandle<()>);
 
+impl HandleWrapper {
+    // スレッドを終了させ、実際に終了するまで待つ
+    pub async fn stop(mut self) {
+        self.0.abort();
+        let _ = (&mut self.0).await;
+    }
+}
+
 impl std::ops::Drop for HandleWrapper {
     fn drop(&mut self) {
         self.0.abort();
     }
 }
 
+// drop時にファイルを消すラッパー
+// 途中でエラーになっても一時ファイルが残らないようにする
+pub struct TempFile(pub PathBuf);
+
+impl std::ops::Drop for TempFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                warn!("could not remove {:?}: {}", &self.0, e)
+            }
+            _ => (),
+        }
+    }
+}
+
 // 通信用の構造体
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum MessageForNetwork {
     DirectMsg(String),
+    // Hidden Serviceのアドレスを変えたことを連絡先に知らせる
+    // 1つ目に新しいホスト名、2つ目にクライアント認証の公開鍵が入る
+    MigrateHostname(String, Option<String>),
+    // 自分のIDを新しい鍵のものに変えたことを連絡先に知らせる
+    // 1つ目に新しいID、2つ目と3つ目に古い鍵と新しい鍵での移行の宣言への署名が入る
+    RotateIdentity(Vec<u8>, Vec<u8>, Vec<u8>),
 }
 
 // デバッグメッセージの表示を簡略化するためのトレイト
@@ -95,6 +298,31 @@ impl<F: FnOnce(&str), T> ErrMsg<F> for std::option::Option<T> {
     }
 }
 
+// エラーをログに出力しつつlibtea::Errorに変換するためのトレイト
+pub trait ErrInto<T> {
+    fn err_into<F: FnOnce(String) -> Error>(self, function: F) -> Result<T, Error>;
+}
+
+impl<T, E: std::fmt::Display> ErrInto<T> for std::result::Result<T, E> {
+    fn err_into<F: FnOnce(String) -> Error>(self, function: F) -> Result<T, Error> {
+        self.map_err(|e| {
+            let e = function(e.to_string());
+            error!("{}", e);
+            e
+        })
+    }
+}
+
+impl<T> ErrInto<T> for std::option::Option<T> {
+    fn err_into<F: FnOnce(String) -> Error>(self, function: F) -> Result<T, Error> {
+        self.ok_or_else(|| {
+            let e = function(String::new());
+            error!("{}", e);
+            e
+        })
+    }
+}
+
 pub struct DeferWrapper<F: FnMut()> {
     pub f: F,
 }
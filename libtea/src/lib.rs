@@ -21,7 +21,16 @@ along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 #[macro_use]
 mod inside;
+mod builder;
 pub mod consts;
+mod error;
+mod health;
+mod profile;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+mod tor_config;
+pub mod tor_control;
+pub mod transport;
 
 #[macro_use]
 extern crate log;
@@ -29,158 +38,677 @@ extern crate log;
 use crate::{
     consts::{KEY_LENGTH, SIG_LENGTH},
     inside::{
-        functions::{decode_address, greeting_auth, passwd_gen, process_message, try_open_read},
-        structs::{ErrMsg, HandleWrapper, MessageForNetwork, UserDataRaw, UserDataTemp},
+        backup::Backup,
+        functions::{
+            apply_client_auth, authorized_clients, check_hostname, decode_address, event_stream,
+            greeting_auth, load_client_auth_key, local_address, migrate_onion_key, path_to_str,
+            process_message, replace_file, rotation_statement, send_event, wait_bootstrap,
+            write_message,
+        },
+        keyfile::{
+            load_secret_key, read_key_file, read_previous_keys, write_key_file,
+            write_previous_keys, KeyType,
+        },
+        structs::{
+            ClientAuthKey, ClientAuthRegistry, ErrInto, ErrMsg, HandleWrapper, MessageForNetwork,
+            OnionService, Passphrase, TempFile, TorInstance, UserDataRaw, UserDataTemp,
+        },
     },
 };
 
-use std::{collections::HashMap, convert::TryFrom, net::IpAddr, path::PathBuf, time::Duration};
+#[cfg(feature = "embedded-tor")]
+use crate::inside::{
+    functions::{free_ports, passwd_gen},
+    structs::{EmbeddedTor, TorRestart},
+};
+pub use crate::{
+    builder::{ControlAuth, SessionBuilder},
+    error::Error,
+    profile::ProfileManager,
+    tor_config::{Bridge, Padding, Proxy, TorConfig},
+};
+use crate::{
+    tor_control::ControlConnection,
+    transport::{BoxedConnection, TorTransport, Transport},
+};
+#[cfg(feature = "embedded-tor")]
+use libtor::{Tor, TorAddress, TorFlag};
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock as StdRwLock, Weak,
+    },
+    time::Duration,
+};
 
 use ed448_rust::{PrivateKey, PublicKey};
-use libtor::{HiddenServiceVersion, Tor, TorAddress, TorFlag};
 use rand::Rng;
 use tokio::{
     fs,
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    io::{AsyncReadExt, AsyncWriteExt, BufStream},
     net::{TcpListener, TcpStream},
     process::Command,
-    sync::{mpsc::Sender, Mutex, RwLock},
+    sync::{broadcast, mpsc, MappedMutexGuard, Mutex, MutexGuard, RwLock},
+    task::JoinHandle,
 };
 
 use sqlx::{Connection, Executor};
+use tokio_stream::Stream;
 
 /// libteaのセッションです  
 /// newメソッドを使うことで生成できます  
-/// notifyのMutexの中身を書き換えることで新規メッセージの通知を受け取ることができます  
+/// 中身はArcで共有されているため、cloneしたものを別のタスクに渡すことができます  
+/// 全てのcloneがdropされるとTorや受信用のスレッドも終了します  
+/// subscribeメソッドを使うことで新規メッセージなどの通知を受け取ることができます  
 /// myaddressには自分のアドレスが入っており、共有することで他の人と通信することができます  
+#[derive(Clone)]
 pub struct RYOKUCHATSession {
-    handles: Vec<HandleWrapper>,
-    myprivkey: PrivateKey,
-    localhost: String,
-    socks_port: u16,
-    user_database: Mutex<sqlx::SqliteConnection>,
+    inner: Arc<SessionInner>,
+}
+
+// RYOKUCHATSessionの実体
+// 内部のスレッドはWeakで参照し、使うときだけupgradeするので循環参照にはならない
+struct SessionInner {
+    handles: Mutex<Vec<HandleWrapper>>,
+    tor: Mutex<Option<JoinHandle<()>>>,
+    control: Mutex<Option<ControlConnection<TcpStream>>>,
+    // Torを使っていない場合はNone
+    // controlと両方ロックする場合は、先にcontrolをロックする
+    onion: RwLock<Option<OnionService>>,
+    // shutdownが呼ばれたかどうか
+    closed: AtomicBool,
+    // 送信中のメッセージがある間はreadロックが取られる
+    sending: RwLock<()>,
+    // rotate_identityで置き換えるため、使うときはmyprivkey()で取り出す
+    myprivkey: StdRwLock<Arc<PrivateKey>>,
+    // バックアップを作るときの一時ファイルを置く
+    // 権限を絞ってあるので、暗号化していないデータを置いても他のユーザーからは読めない
+    data_dir: PathBuf,
+    // パスフレーズを変更するときに書き換える
+    key_file: PathBuf,
+    // 秘密鍵のファイルを暗号化しているパスフレーズ
+    // 鍵を保存し直すときに使い、change_passphraseで書き換える
+    passphrase: StdRwLock<Option<Passphrase>>,
+    previous_key_file: PathBuf,
+    // バックアップを作るときに読み出す
+    onion_key_file: PathBuf,
+    client_auth_key_file: PathBuf,
+    // 同じTorを使う他のセッションと共有する、登録したクライアント認証の鍵
+    client_auth_keys: Arc<ClientAuthRegistry>,
+    // ProfileManagerが作ったセッションの場合は、NEWNYMを送る代わりにここで頼む
+    newnym: Option<mpsc::Sender<()>>,
+    // 組み込みのTorを起動した場合のみSome
+    // Hidden Serviceに接続できない状態が続いたときに、Torを起動し直すために使う
+    #[cfg(feature = "embedded-tor")]
+    tor_restart: Option<TorRestart>,
+    transport: Arc<dyn Transport>,
+    // アドレスを変えるときにホスト名を書き換えるため、Torを使っている場合は別に持っておく
+    tor_transport: Option<Arc<TorTransport>>,
+    user_database: Mutex<Option<sqlx::SqliteConnection>>,
     user_data_temp: RwLock<HashMap<[u8; KEY_LENGTH], UserDataTemp>>,
-    pub notify: Mutex<Option<Sender<Message>>>,
-    myaddress: String,
+    events: broadcast::Sender<Message>,
+    myaddress: StdRwLock<String>,
 }
 
 impl RYOKUCHATSession {
     /// 動作の説明:  
     /// 新しくRYOKUCHATSessionを作ります  
+    /// ポートなどを細かく設定したい場合はSessionBuilderを使ってください  
     /// 引数について:  
     /// 1: libteaのデータを設置する場所をPathBufで指定します  
-    /// 2: Torが使うSocksプロキシのポートを指定します  
-    /// 3: Tor Hidden Serviceを経由して送られてきたリクエストを受け付けるためのポートを指定します  
+    /// 2: Tor Hidden Serviceを経由して送られてきたリクエストを受け付けるためのポートを指定します  
+    /// Torが使うSocksプロキシとControlPortには、それぞれ+1、+2したポートが使われます  
     /// 返り値について:  
-    /// Boxで包まれたRYOKUCHATSessionが返ってきます  
-    pub async fn new(mut data_dir: PathBuf, port: u16) -> Box<RYOKUCHATSession> {
+    /// 成功ならばRYOKUCHATSessionが、失敗ならばErrorが返ってきます  
+    /// 失敗した場合、途中まで起動したTorやポートは片付けられるので、そのまま再試行できます  
+    pub async fn new(data_dir: PathBuf, port: u16) -> Result<RYOKUCHATSession, Error> {
         trace!("RYOKUCHATSession::new() is called.");
         defer!(trace!("reterning from RYOKUCHATSession::new()"));
-        debug!("data_dir is {:?}", &data_dir);
 
-        // それぞれの用途のポート番号を決める
-        let ryokuchat_port = port;
-        let socks_port = port + 1;
-        let control_port = port + 2;
-        debug!("ryokuchat_port is {}", ryokuchat_port);
-        debug!("socks_port is {}", socks_port);
-        debug!("control_port is {}", control_port);
-
-        // localhostのアドレスを取得
-        let localhost = match tokio::net::lookup_host("localhost:1")
+        let socks_port = port
+            .checked_add(1)
+            .err_into(|_| Error::Config(format!("port {} is too large", port)))?;
+        let control_port = port
+            .checked_add(2)
+            .err_into(|_| Error::Config(format!("port {} is too large", port)))?;
+
+        SessionBuilder::new(data_dir)
+            .port(port)
+            .socks_port(socks_port)
+            .control_port(control_port)
+            .build()
             .await
-            .unwrap()
-            .next()
-            .unwrap()
-            .ip()
-        {
-            IpAddr::V4(v4) => v4.to_string(),
-            IpAddr::V6(v6) => "[".to_string() + &v6.to_string() + "]",
-        };
-        debug!("localhost is {}", localhost);
+    }
+
+    // SessionBuilderの設定からRYOKUCHATSessionを作る
+    async fn start(builder: SessionBuilder) -> Result<RYOKUCHATSession, Error> {
+        trace!("RYOKUCHATSession::start() is called.");
+        defer!(trace!("reterning from RYOKUCHATSession::start()"));
+        debug!("builder is {:?}", &builder);
+
+        // 各ファイルの場所を決める
+        let data_dir = builder.data_dir.clone();
+        let tor_dir = data_dir.join(&builder.tor_dir);
+        let tor_config = tor_dir.join("torrc");
+        let database_file = data_dir.join(&builder.database_file);
+        let key_file = data_dir.join(&builder.key_file);
+        let client_auth_key_file = data_dir.join(&builder.client_auth_key_file);
+        let events = builder.events.clone();
 
         // ディレクトリを作成
-        data_dir.push("tor");
-        data_dir.push("hidden");
-        let _ = fs::create_dir_all(&data_dir).await;
-        info!("directory {:?} is created", &data_dir);
-
-        data_dir.pop();
-        data_dir.push("torrc");
-        let _ = std::fs::OpenOptions::new()
+        for dir in [&data_dir, &tor_dir] {
+            fs::create_dir_all(dir)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+            info!("directory {:?} is created", dir);
+        }
+        for file in [
+            &database_file,
+            &key_file,
+            &data_dir.join(&builder.onion_key_file),
+            &client_auth_key_file,
+            &data_dir.join(&builder.previous_key_file),
+        ] {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir)
+                    .await
+                    .err_exec(|e| error!("{}", e))?;
+            }
+        }
+
+        std::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
-            .open(&data_dir);
-        info!("file {:?} is created", &data_dir);
-        data_dir.pop();
-        data_dir.pop();
+            .open(&tor_config)
+            .err_exec(|e| error!("{}", e))?;
+        info!("file {:?} is created", &tor_config);
 
         // SQLiteの初期化
-        data_dir.push("sqlite.db");
-        let _ = std::fs::OpenOptions::new()
+        std::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
-            .open(&data_dir);
-        info!("file {:?} is created", &data_dir);
+            .open(&database_file)
+            .err_exec(|e| error!("{}", e))?;
+        info!("file {:?} is created", &database_file);
         let mut sqlite =
-            sqlx::SqliteConnection::connect(&format!("sqlite://{}", data_dir.to_str().unwrap()))
+            sqlx::SqliteConnection::connect(&format!("sqlite://{}", path_to_str(&database_file)?))
                 .await
-                .unwrap();
+                .err_exec(|e| error!("{}", e))?;
         sqlite
-            .execute("CREATE TABLE IF NOT EXISTS users (lastupdate INTEGER NOT NULL, id BLOB NOT NULL, hostname TEXT NOT NULL, username TEXT);")
+            .execute("CREATE TABLE IF NOT EXISTS users (lastupdate INTEGER NOT NULL, id BLOB NOT NULL, hostname TEXT NOT NULL, username TEXT, client_auth TEXT);")
             .await
-            .unwrap();
+            .err_exec(|e| error!("{}", e))?;
+        // 以前のバージョンで作られたテーブルにはclient_authが無い
+        let columns =
+            sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info('users');")
+                .fetch_all(&mut sqlite)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+        if !columns.iter().any(|c| c == "client_auth") {
+            sqlite
+                .execute("ALTER TABLE users ADD COLUMN client_auth TEXT;")
+                .await
+                .err_exec(|e| error!("{}", e))?;
+            info!("client_auth column is added to users");
+        }
         sqlite
             .execute("CREATE INDEX IF NOT EXISTS search ON users(lastupdate, id);")
             .await
-            .unwrap();
-        data_dir.pop();
+            .err_exec(|e| error!("{}", e))?;
+        // rotate_identityで新しいIDをまだ伝えられていない連絡先と、その連絡先が知っている自分のID
+        sqlite
+            .execute("CREATE TABLE IF NOT EXISTS pending_rotations (id BLOB NOT NULL PRIMARY KEY, old_id BLOB NOT NULL);")
+            .await
+            .err_exec(|e| error!("{}", e))?;
 
         #[cfg(not(target_os = "windows"))]
         {
-            Command::new("chmod")
+            let status = Command::new("chmod")
                 .arg("-R")
                 .arg("1700")
-                .arg(data_dir.to_str().unwrap())
-                .spawn()
-                .unwrap()
-                .wait()
+                .arg(path_to_str(&data_dir)?)
+                .arg(path_to_str(&tor_dir)?)
+                .status()
                 .await
-                .unwrap();
+                .err_exec(|e| error!("{}", e))?;
+            if !status.success() {
+                error!("chmod exited with {}", status);
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("could not set permission of {:?}", &data_dir),
+                )));
+            }
             info!("permission of directory {:?} is set to 1700", &data_dir);
         }
 
+        // 秘密鍵を読み出し､鍵のペアを用意する
+        // パスフレーズで暗号化されている場合は、ここで復号する
+        let passphrase = builder.passphrase.as_ref().map(|p| p.0.as_str());
+        let secretkey = load_secret_key(&key_file, passphrase).await?;
+        info!("{:?} is read", &key_file);
+        let secretkey = PrivateKey::try_from(&secretkey).err_into(Error::KeyFile)?;
+        let publickey = PublicKey::try_from(&secretkey).err_into(Error::KeyFile)?;
+
+        // Hidden Serviceのクライアント認証のために連絡先を読み出しておく
+        let contacts =
+            sqlx::query_as::<_, UserDataRaw>("SELECT id,hostname,username,client_auth FROM users;")
+                .fetch_all(&mut sqlite)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+
+        // 相手との通信路を用意する
+        // Transportもsystem_torも指定されていない場合は組み込みのTorを起動する
+        let mut instance = match (builder.transport.clone(), builder.system_tor.clone()) {
+            (Some(s), _) => {
+                info!("using {:?} instead of Tor", s);
+                Err(s)
+            }
+            (None, Some((address, auth))) => {
+                let client_key = load_client_auth_key(&client_auth_key_file, passphrase).await?;
+                Ok(RYOKUCHATSession::connect_system_tor(
+                    &builder, &address, &auth, client_key, &contacts,
+                )
+                .await?)
+            }
+            #[cfg(feature = "embedded-tor")]
+            (None, None) => {
+                let client_key = load_client_auth_key(&client_auth_key_file, passphrase).await?;
+                Ok(RYOKUCHATSession::launch_tor(
+                    &builder,
+                    &tor_dir,
+                    &tor_config,
+                    client_key,
+                    &contacts,
+                )
+                .await?)
+            }
+            #[cfg(not(feature = "embedded-tor"))]
+            (None, None) => {
+                error!("embedded Tor is disabled");
+                return Err(Error::Config(
+                        "embedded Tor is disabled, use SessionBuilder::system_tor or SessionBuilder::transport".to_string(),
+                    ));
+            }
+        };
+        #[cfg(feature = "embedded-tor")]
+        let tor_restart = instance.as_mut().ok().and_then(|o| o.restart.take());
+        let (transport, tor_transport, tor, control, onion, control_login): (
+            Arc<dyn Transport>,
+            _,
+            _,
+            _,
+            _,
+            _,
+        ) = match instance {
+            Ok(o) => {
+                let tor_transport = Arc::new(o.transport);
+                (
+                    tor_transport.clone(),
+                    Some(tor_transport),
+                    o.process,
+                    Some(o.control),
+                    Some(o.onion),
+                    Some((o.control_address, o.control_auth)),
+                )
+            }
+            Err(transport) => (transport, None, None, None, None, None),
+        };
+
+        // 公開鍵とホスト名から自分のアドレスを生成する
+        // クライアント認証を使っている場合は、接続に必要な公開鍵も渡してもらう
+        let address = UserData {
+            id: publickey,
+            hostname: transport.hostname(),
+            username: None,
+            client_auth: onion
+                .as_ref()
+                .filter(|o| o.client_auth)
+                .map(|o| o.client_key.public.clone()),
+        }
+        .get_address();
+        debug!("myaddress is {}", &address);
+        send_event(&events, Message::OnionReady(address.clone()));
+
+        let inner = Arc::new_cyclic(|weak: &Weak<SessionInner>| {
+            // Hidden Serviceに接続できるかを監視するスレッド
+            let mut handles = Vec::new();
+            if let Some((address, auth)) = control_login {
+                if !builder.health_check_interval.is_zero() {
+                    handles.push(HandleWrapper(tokio::spawn(health::monitor(
+                        weak.clone(),
+                        address,
+                        auth,
+                        builder.health_check_interval,
+                    ))));
+                }
+            }
+
+            // メッセージを受信するスレッド
+            let weak = weak.clone();
+            let transport2 = transport.clone();
+            let handle = tokio::spawn(async move {
+                debug!("listen loop started");
+                loop {
+                    let o = match transport2.accept().await {
+                        Ok(o) => o,
+                        Err(Error::Closed) => break,
+                        // ファイルディスクリプタが足りない場合などは、すぐに再試行しても失敗し続ける
+                        Err(e) => {
+                            error!("failed to accept a connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    let weak = weak.clone();
+                    tokio::spawn(async move {
+                        info!("new connection come");
+
+                        let mut stream = BufStream::new(o);
+
+                        // 57バイトの公開鍵(ID)
+                        // 公開鍵を送る前に閉じられた接続は、自分のHidden Serviceを確かめるためのものなので無視する
+                        let mut key = [0; KEY_LENGTH];
+                        match stream.read_exact(&mut key).await {
+                            Ok(_) => {}
+                            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                                debug!("the connection is closed before the handshake");
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                error!("{}", e);
+                                return Err(Error::Transport(e.to_string()));
+                            }
+                        }
+                        let key = PublicKey::try_from(&key).err_into(Error::Handshake)?;
+
+                        // 連絡先リストに相手のアドレスがあることを確認
+                        let session = RYOKUCHATSession::upgrade(&weak)?;
+                        let user = match session.get_user_from_id(&key).await {
+                            Ok(o) => o,
+                            Err(e) => {
+                                if let Error::UnknownUser = e {
+                                    error!("This connection is from an unknown source.");
+                                    session.notify(Message::HandshakeRejected(key));
+                                } else {
+                                    session.notify_storage_error(&e);
+                                }
+                                return Err(e);
+                            }
+                        };
+                        drop(session);
+
+                        // 16バイトの認証用メッセージ
+                        let auth = rand::rngs::OsRng.gen::<u128>().to_be_bytes();
+                        stream.write_all(&auth).await.err_into(Error::Transport)?;
+                        stream.flush().await.err_into(Error::Transport)?;
+                        let mut sign = [0; SIG_LENGTH];
+                        stream
+                            .read_exact(&mut sign)
+                            .await
+                            .err_into(Error::Transport)?;
+                        let verify = user
+                            .id
+                            .verify(&greeting_auth(&auth)?, &sign, None)
+                            .err_into(|_| {
+                                Error::Handshake(
+                                    "failed to verify the connection source".to_string(),
+                                )
+                            });
+                        if let Err(e) = verify {
+                            RYOKUCHATSession::upgrade(&weak)?
+                                .notify(Message::HandshakeRejected(key));
+                            return Err(e);
+                        }
+
+                        info!("this connection is from {}", user.get_address());
+
+                        process_message(&RYOKUCHATSession::upgrade(&weak)?, key, stream).await;
+                        Ok::<(), Error>(())
+                    });
+                }
+            });
+
+            SessionInner {
+                handles: {
+                    handles.push(HandleWrapper(handle));
+                    Mutex::const_new(handles)
+                },
+                tor: Mutex::const_new(tor),
+                control: Mutex::const_new(control),
+                onion: RwLock::const_new(onion),
+                closed: AtomicBool::new(false),
+                sending: RwLock::const_new(()),
+                myprivkey: StdRwLock::new(Arc::new(secretkey)),
+                data_dir: data_dir.clone(),
+                key_file,
+                passphrase: StdRwLock::new(builder.passphrase.clone()),
+                previous_key_file: data_dir.join(&builder.previous_key_file),
+                onion_key_file: data_dir.join(&builder.onion_key_file),
+                client_auth_key_file,
+                client_auth_keys: builder.client_auth_keys.clone(),
+                newnym: builder.newnym.clone(),
+                #[cfg(feature = "embedded-tor")]
+                tor_restart,
+                transport,
+                tor_transport,
+                user_database: Mutex::const_new(Some(sqlite)),
+                user_data_temp: RwLock::const_new(HashMap::new()),
+                events,
+                myaddress: StdRwLock::new(address),
+            }
+        });
+
+        Ok(RYOKUCHATSession::from_inner(inner))
+    }
+
+    // 組み込みのTorを起動し、Hidden Serviceの準備ができるまで待つ
+    // SessionBuilder::bootstrap_timeoutを過ぎても準備ができない場合はError::TorBootstrapTimeoutになる
+    #[cfg(feature = "embedded-tor")]
+    async fn launch_tor(
+        builder: &SessionBuilder,
+        tor_dir: &std::path::Path,
+        tor_config: &std::path::Path,
+        client_key: ClientAuthKey,
+        contacts: &[UserDataRaw],
+    ) -> Result<TorInstance, Error> {
+        trace!("RYOKUCHATSession::launch_tor() is called.");
+        defer!(trace!("reterning from RYOKUCHATSession::launch_tor()"));
+
+        // 間違った設定でTorを起動しないように、先に確かめておく
+        builder.tor_config.options()?;
+        let (bind_address, localhost) = local_address(builder.bind_address).await?;
+
+        // Torを起動する前にポートを確保しておく
+        let listen = TcpListener::bind(SocketAddr::new(bind_address, builder.port))
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        let ryokuchat_port = listen.local_addr()?.port();
+        debug!("ryokuchat_port is {}", ryokuchat_port);
+
+        // 失敗した場合はControlPortとの接続が閉じられ、Torも終了する
+        let mut tor =
+            RYOKUCHATSession::start_embedded_tor(builder, tor_dir, tor_config, &[ryokuchat_port])
+                .await?;
+        let target = format!("{}:{}", localhost, ryokuchat_port);
+        let onion = RYOKUCHATSession::publish_onion(
+            &mut tor.control,
+            builder,
+            &target,
+            client_key,
+            contacts,
+        )
+        .await?;
+
+        // 起動し直したときも同じアドレスで接続できるように、ポートを固定しておく
+        // 鍵は読み直さないので、パスフレーズは持っておかない
+        let mut restart = builder.clone();
+        restart.socks_port = tor.socks_port;
+        restart.control_port = tor.control_port;
+        restart.passphrase = None;
+
+        let transport = TorTransport::new(
+            listen,
+            tor.socks_address,
+            builder.virtual_port,
+            format!("{}.onion", onion.service_id),
+        )
+        .isolation(builder.circuit_isolation);
+        Ok(TorInstance {
+            transport,
+            process: Some(tor.process),
+            control: tor.control,
+            control_address: tor.control_address,
+            control_auth: ControlAuth::Password(tor.control_passwd),
+            onion,
+            restart: Some(TorRestart {
+                builder: restart,
+                tor_dir: tor_dir.to_path_buf(),
+                tor_config: tor_config.to_path_buf(),
+                ryokuchat_port,
+            }),
+        })
+    }
+
+    // 組み込みのTorを終了させて同じポートで起動し直し、連絡先の鍵とHidden Serviceを登録し直す
+    // 組み込みのTorを使っていない場合はNoneを、起動し直した場合はControlPortの新しい認証情報を返す
+    // 古いTorが30秒以内に終了しない場合はError::Torになる
+    #[cfg(feature = "embedded-tor")]
+    pub(crate) async fn restart_tor(&self) -> Result<Option<ControlAuth>, Error> {
+        trace!("RYOKUCHATSession::restart_tor() is called");
+        defer!(trace!("returning from RYOKUCHATSession::restart_tor()"));
+
+        let restart = match &self.inner.tor_restart {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut control = self.inner.control.lock().await;
+        let onion = self.inner.onion.read().await;
+        let onion = match onion.as_ref() {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+        let mut tor = self.inner.tor.lock().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+
+        // 古いTorが終了するまで待たないと、同じポートで起動できない
+        info!("restarting Tor");
+        if let Some(mut old) = control.take() {
+            if let Err(e) = old.signal("SHUTDOWN").await {
+                warn!("failed to send SHUTDOWN to Tor: {}", e);
+            }
+        }
+        if let Some(process) = tor.as_mut() {
+            if tokio::time::timeout(Duration::from_secs(30), process)
+                .await
+                .is_err()
+            {
+                error!("Tor did not exit in time");
+                return Err(Error::Tor("Tor did not exit in time".to_string()));
+            }
+            *tor = None;
+        }
+
+        let mut started = RYOKUCHATSession::start_embedded_tor(
+            &restart.builder,
+            &restart.tor_dir,
+            &restart.tor_config,
+            &[restart.ryokuchat_port],
+        )
+        .await?;
+
+        // 新しいTorには連絡先の鍵もHidden Serviceも残っていない
+        for (service_id, secret) in self.inner.client_auth_keys.registered() {
+            started
+                .control
+                .add_client_auth(&service_id, &secret)
+                .await?;
+        }
+        let users = self.get_users().await?;
+        let clients = match onion.client_auth {
+            true => authorized_clients(&onion.client_key, users.iter().map(|u| &u.client_auth)),
+            false => Vec::new(),
+        };
+        started
+            .control
+            .add_onion(&onion.key, onion.virtual_port, &onion.target, &clients)
+            .await?;
+        info!("Tor is restarted");
+
+        *control = Some(started.control);
+        *tor = Some(started.process);
+        Ok(Some(ControlAuth::Password(started.control_passwd)))
+    }
+
+    // 組み込みのTorを起動し、起動が終わるまで待つ
+    // 返り値のControlPortとの接続がTorの所有権を持ち、dropされるとTorも終了する
+    // reservedには既に他の用途でbindしているポートを指定する
+    // SessionBuilder::bootstrap_timeoutを過ぎても終わらない場合はError::TorBootstrapTimeoutになる
+    #[cfg(feature = "embedded-tor")]
+    pub(crate) async fn start_embedded_tor(
+        builder: &SessionBuilder,
+        tor_dir: &std::path::Path,
+        tor_config: &std::path::Path,
+        reserved: &[u16],
+    ) -> Result<EmbeddedTor, Error> {
+        trace!("RYOKUCHATSession::start_embedded_tor() is called.");
+        defer!(trace!(
+            "reterning from RYOKUCHATSession::start_embedded_tor()"
+        ));
+
+        let options = builder.tor_config.options()?;
+        let (bind_address, localhost) = local_address(builder.bind_address).await?;
+        let needed = [builder.socks_port, builder.control_port]
+            .iter()
+            .filter(|p| **p == 0)
+            .count();
+        let mut free = free_ports(bind_address, needed)?.into_iter();
+        let socks_port = match builder.socks_port {
+            0 => free.next().unwrap_or_default(),
+            p => p,
+        };
+        let control_port = match builder.control_port {
+            0 => free.next().unwrap_or_default(),
+            p => p,
+        };
+        debug!("socks_port is {}", socks_port);
+        debug!("control_port is {}", control_port);
+        if reserved.contains(&socks_port)
+            || reserved.contains(&control_port)
+            || socks_port == control_port
+        {
+            error!("the same port is used for multiple purposes");
+            return Err(Error::Config(
+                "the same port is used for multiple purposes".to_string(),
+            ));
+        }
+
         // Torを起動
-        let mut tor_dir = data_dir.clone();
-        tor_dir.push("tor");
-        let mut hidden_dir = tor_dir.clone();
-        hidden_dir.push("hidden");
-        let mut tor_config = tor_dir.clone();
-        tor_config.push("torrc");
         debug!("DataDirectory of Tor is {:?}", &tor_dir);
-        debug!("HiddenServiceDir of Tor is {:?}", &hidden_dir);
         debug!("ConfigFile of Tor is {:?}", &tor_config);
+        let tor_dir = path_to_str(tor_dir)?.to_string();
+        let tor_config = path_to_str(tor_config)?.to_string();
         let control_passwd = passwd_gen();
         let control_passwd2 = control_passwd.clone();
         debug!("control_passwd is {:?}", &control_passwd);
         let localhost2 = localhost.clone();
-        let torhandle = tokio::task::spawn_blocking(move || {
-            Tor::new()
-                .flag(TorFlag::Quiet())
-                .flag(TorFlag::DataDirectory(
-                    tor_dir.to_str().unwrap().to_string(),
-                ))
-                .flag(TorFlag::ConfigFile(
-                    tor_config.to_str().unwrap().to_string(),
-                ))
-                .flag(TorFlag::HiddenServiceDir(
-                    hidden_dir.to_str().unwrap().to_string(),
-                ))
-                .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
-                .flag(TorFlag::HiddenServicePort(
-                    TorAddress::Port(4545),
-                    Some(TorAddress::AddressPort(localhost2.clone(), ryokuchat_port)).into(),
-                ))
+        send_event(
+            &builder.events,
+            Message::TorBootstrap(0, "starting Tor".to_string()),
+        );
+        let mut torhandle = tokio::task::spawn_blocking(move || {
+            let mut tor = Tor::new();
+            tor.flag(TorFlag::Quiet())
+                .flag(TorFlag::DataDirectory(tor_dir))
+                .flag(TorFlag::ConfigFile(tor_config))
                 .flag(TorFlag::SocksPortAddress(
                     TorAddress::AddressPort(localhost2.clone(), socks_port),
                     None.into(),
@@ -192,181 +720,1070 @@ impl RYOKUCHATSession {
                 ))
                 .flag(TorFlag::HashedControlPassword(
                     libtor::generate_hashed_password(&control_passwd2),
-                ))
-                .flag(TorFlag::ExcludeNodes(vec!["SlowServer".to_string()].into()))
-                .flag(TorFlag::StrictNodes(true.into()))
-                .flag(TorFlag::ConnectionPadding(true.into()))
-                .flag(TorFlag::ReducedConnectionPadding(false.into()))
-                .flag(TorFlag::CircuitPadding(true.into()))
-                .flag(TorFlag::ReducedCircuitPadding(false.into()))
-                .start()
-                .unwrap();
+                ));
+            // TorConfigで指定された設定
+            for (key, value) in options {
+                tor.flag(TorFlag::Custom(format!("{} {}", key, value)));
+            }
+            let result = tor.start();
+            match result {
+                Ok(o) => info!("Tor exited with {}", o),
+                Err(e) => error!("Tor exited with an error: {:?}", e),
+            }
         });
 
-        // 秘密鍵を読み出し､鍵のペアを用意する
-        data_dir.push("DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr");
-        let mut secretkey = [0; KEY_LENGTH];
-        try_open_read(&data_dir, |mut f| async move {
-            info!("generating new secretkey");
-            f.write_all(PrivateKey::new(&mut rand::rngs::OsRng).as_bytes())
+        // ControlPortに接続してTorの所有権を取り、起動の進み具合を受け取る
+        let control_address = format!("{}:{}", localhost, control_port);
+        let mut progress = (0, "starting Tor".to_string());
+        let bootstrap = async {
+            let mut control = loop {
+                match ControlConnection::connect(&control_address).await {
+                    Ok(o) => break o,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            };
+            control.authenticate_password(&control_passwd).await?;
+            control.take_ownership().await?;
+            info!("took ownership of Tor");
+
+            wait_bootstrap(
+                &mut control,
+                &builder.events,
+                &mut progress,
+                builder.bootstrap_stuck_timeout,
+                builder.tor_config.uses_proxy(),
+            )
+            .await?;
+            Ok::<_, Error>(control)
+        };
+        let result = tokio::select! {
+            result = tokio::time::timeout(builder.bootstrap_timeout, bootstrap) => result,
+            _ = &mut torhandle => {
+                error!("Tor exited before the bootstrap finished");
+                return Err(Error::Tor(
+                    "Tor exited before the bootstrap finished".to_string(),
+                ));
+            }
+        };
+        let control = match result {
+            Ok(o) => o?,
+            Err(_) => {
+                let e = Error::TorBootstrapTimeout(progress.0, progress.1);
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+        Ok(EmbeddedTor {
+            process: torhandle,
+            control,
+            control_address,
+            control_passwd,
+            socks_address: format!("{}:{}", localhost, socks_port),
+            socks_port,
+            control_port,
+        })
+    }
+
+    // 既に動いているTorのControlPortに接続し、ADD_ONIONでHidden Serviceを作る
+    // Hidden ServiceはControlPortとの接続が切れると消える
+    async fn connect_system_tor(
+        builder: &SessionBuilder,
+        control_address: &str,
+        auth: &ControlAuth,
+        client_key: ClientAuthKey,
+        contacts: &[UserDataRaw],
+    ) -> Result<TorInstance, Error> {
+        trace!("RYOKUCHATSession::connect_system_tor() is called.");
+        defer!(trace!(
+            "reterning from RYOKUCHATSession::connect_system_tor()"
+        ));
+
+        let (bind_address, localhost) = local_address(builder.bind_address).await?;
+        let listen = TcpListener::bind(SocketAddr::new(bind_address, builder.port))
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        let ryokuchat_port = listen.local_addr()?.port();
+        debug!("ryokuchat_port is {}", ryokuchat_port);
+
+        // ControlPortに接続して認証する
+        debug!("control_address is {}", control_address);
+        let mut control = ControlConnection::connect(control_address)
+            .await
+            .err_exec(|e| error!("could not connect to the control port: {}", e))?;
+        control.authenticate(auth).await?;
+        info!("authenticated to Tor");
+
+        // TorのSocksプロキシのアドレスを取得する
+        let socks_address = control.get_info("net/listeners/socks").await?;
+        let socks_address = socks_address
+            .split(' ')
+            .map(|a| a.trim_matches('"'))
+            .find(|a| !a.is_empty() && !a.starts_with("unix:"))
+            .err_into(|_| Error::Tor("Tor has no SocksPort".to_string()))?
+            .to_string();
+        debug!("socks_address is {}", &socks_address);
+
+        // リレーへの接続がプロキシを経由しているかを調べる
+        let mut proxy = false;
+        for key in ["HTTPSProxy", "Socks4Proxy", "Socks5Proxy"] {
+            match control.get_conf(key).await {
+                Ok(o) => proxy |= !o.is_empty(),
+                Err(e) => warn!("could not get {}: {}", key, e),
+            }
+        }
+        debug!("proxy is {}", proxy);
+
+        // Torの起動が終わるまで待つ
+        let mut progress = (0, "connecting to Tor".to_string());
+        let bootstrap = tokio::time::timeout(
+            builder.bootstrap_timeout,
+            wait_bootstrap(
+                &mut control,
+                &builder.events,
+                &mut progress,
+                builder.bootstrap_stuck_timeout,
+                proxy,
+            ),
+        )
+        .await;
+        match bootstrap {
+            Ok(o) => o?,
+            Err(_) => {
+                let e = Error::TorBootstrapTimeout(progress.0, progress.1);
+                error!("{}", e);
+                return Err(e);
+            }
+        }
+
+        let target = format!("{}:{}", localhost, ryokuchat_port);
+        let onion =
+            RYOKUCHATSession::publish_onion(&mut control, builder, &target, client_key, contacts)
                 .await?;
-            Ok(())
+
+        let transport = TorTransport::new(
+            listen,
+            socks_address,
+            builder.virtual_port,
+            format!("{}.onion", onion.service_id),
+        )
+        .isolation(builder.circuit_isolation);
+        Ok(TorInstance {
+            transport,
+            process: None,
+            control,
+            control_address: control_address.to_string(),
+            control_auth: auth.clone(),
+            onion,
+            #[cfg(feature = "embedded-tor")]
+            restart: None,
         })
-        .await
-        .unwrap()
-        .read_exact(&mut secretkey)
-        .await
-        .unwrap();
-        info!("{:?} is read", &data_dir);
-        let secretkey = PrivateKey::try_from(&secretkey).unwrap();
-        let publickey = PublicKey::try_from(&secretkey).unwrap();
-        data_dir.pop();
-
-        // 公開鍵とTorのホスト名から自分のアドレスを生成する
-        data_dir.push("tor");
-        data_dir.push("hidden");
-        data_dir.push("hostname");
-        let mut address = base64::encode_config(publickey.as_byte(), base64::URL_SAFE_NO_PAD);
-        address.push('@');
-        let mut hostname = String::new();
-        loop {
-            if let Ok(mut o) = fs::File::open(&data_dir).await {
-                if o.read_to_string(&mut hostname).await.is_ok()
-                    && hostname.trim().ends_with(".onion")
-                {
-                    break;
-                }
+    }
+
+    // libteaが保存している鍵を使い、ADD_ONIONでHidden Serviceを作る
+    // 鍵が無い場合は以前のHiddenServiceDirの鍵を移行するか、新しく作ってもらって保存する
+    // クライアント認証を有効にしている場合は、連絡先と自分だけが接続できるようにする
+    async fn publish_onion(
+        control: &mut ControlConnection<TcpStream>,
+        builder: &SessionBuilder,
+        target: &str,
+        client_key: ClientAuthKey,
+        contacts: &[UserDataRaw],
+    ) -> Result<OnionService, Error> {
+        trace!("RYOKUCHATSession::publish_onion() is called.");
+        defer!(trace!("reterning from RYOKUCHATSession::publish_onion()"));
+
+        let key_file = builder.data_dir.join(&builder.onion_key_file);
+        let passphrase = builder.passphrase.as_ref().map(|p| p.0.as_str());
+        let mut key = match read_key_file(&key_file, KeyType::OnionService, passphrase).await? {
+            // UTF-8であることはread_key_fileで確かめてある
+            Some(s) => Some(
+                String::from_utf8(s)
+                    .err_into(|e| Error::KeyFile(format!("{:?} is broken: {}", &key_file, e)))?,
+            ),
+            None => None,
+        };
+        if key.is_none() {
+            // 以前のバージョンでTorが作った鍵があれば引き継ぐ
+            let old_key = builder
+                .data_dir
+                .join(&builder.hidden_service_dir)
+                .join("hs_ed25519_secret_key");
+            if let Some(old_key) = migrate_onion_key(&old_key).await? {
+                info!("migrating the onion service key from {:?}", &old_key);
+                write_key_file(
+                    &key_file,
+                    KeyType::OnionService,
+                    old_key.as_bytes(),
+                    passphrase,
+                )
+                .await?;
+                key = Some(old_key);
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
-        address.push_str(hostname.trim());
-        debug!("myaddress is {}", &address);
 
-        let mut session = Box::new(RYOKUCHATSession {
-            handles: vec![HandleWrapper(torhandle)],
-            myprivkey: secretkey,
-            localhost: localhost.clone(),
-            socks_port,
-            user_database: Mutex::const_new(sqlite),
-            user_data_temp: RwLock::const_new(HashMap::new()),
-            notify: Mutex::const_new(None),
-            myaddress: address,
-        });
+        // 連絡先のHidden Serviceに接続するための鍵を登録する
+        let registry = &builder.client_auth_keys;
+        for user in contacts {
+            if let Some(service_id) = user.hostname.strip_suffix(".onion") {
+                let update = registry.add(service_id, &client_key.secret);
+                apply_client_auth(control, service_id, update).await?;
+            }
+        }
 
-        // RYOKUCHATSessionがdropされたときにTorを終了するためのスレッド
+        let clients = match builder.client_auth {
+            true => authorized_clients(&client_key, contacts.iter().map(|u| &u.client_auth)),
+            false => Vec::new(),
+        };
+        debug!("{} clients are authorized", clients.len());
+        let added = control
+            .add_onion(
+                key.as_deref().unwrap_or("NEW:ED25519-V3"),
+                builder.virtual_port,
+                target,
+                &clients,
+            )
+            .await?;
+        let service_id = added.service_id;
+        let key = match (key, added.private_key) {
+            (_, Some(new_key)) => {
+                info!("generating new onion service key");
+                write_key_file(
+                    &key_file,
+                    KeyType::OnionService,
+                    new_key.as_bytes(),
+                    passphrase,
+                )
+                .await?;
+                new_key
+            }
+            (Some(key), None) => key,
+            (None, None) => {
+                error!("ADD_ONION returned no PrivateKey");
+                return Err(Error::Tor("ADD_ONION returned no PrivateKey".to_string()));
+            }
+        };
+        debug!("hostname is {}.onion", &service_id);
+
+        // 自分のHidden Serviceに接続できるかを確かめるために、自分の鍵も登録する
+        if builder.client_auth {
+            let update = registry.add(&service_id, &client_key.secret);
+            apply_client_auth(control, &service_id, update).await?;
+        }
+
+        Ok(OnionService {
+            key,
+            key_file,
+            service_id,
+            virtual_port: builder.virtual_port,
+            target: target.to_string(),
+            client_key,
+            client_auth: builder.client_auth,
+        })
+    }
+
+    // 連絡先の変更をHidden Serviceのクライアント認証に反映する
+    // addedには追加した連絡先、removedには削除した連絡先のホスト名を指定する
+    async fn update_onion_clients(
+        &self,
+        added: Option<&str>,
+        removed: Option<&str>,
+    ) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::update_onion_clients() is called");
+        defer!(trace!(
+            "returning from RYOKUCHATSession::update_onion_clients()"
+        ));
+
+        let mut control = self.inner.control.lock().await;
+        let onion = self.inner.onion.read().await;
+        let onion = match onion.as_ref() {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let control = control.as_mut().err_into(|_| Error::Closed)?;
+
+        // 相手のHidden Serviceに接続するための鍵を登録する
+        // 同じTorを使う他のセッションも同じ相手を連絡先にしている場合は、鍵を消さずに残す
+        let registry = &self.inner.client_auth_keys;
+        let secret = &onion.client_key.secret;
+        if let Some(service_id) = added.and_then(|h| h.strip_suffix(".onion")) {
+            apply_client_auth(control, service_id, registry.add(service_id, secret)).await?;
+        }
+        if let Some(service_id) = removed.and_then(|h| h.strip_suffix(".onion")) {
+            apply_client_auth(control, service_id, registry.remove(service_id, secret)).await?;
+        }
+        if !onion.client_auth {
+            return Ok(());
+        }
+
+        // 接続を許可する公開鍵はADD_ONIONでしか指定できないため、同じ鍵で作り直す
+        self.republish_onion(control, onion).await
+    }
+
+    // Hidden Serviceを同じ鍵で作り直す
+    // 同時に連絡先が変更されても古いリストで上書きしないように、ControlPortのロックを取ったまま呼ぶ
+    async fn republish_onion(
+        &self,
+        control: &mut ControlConnection<TcpStream>,
+        onion: &OnionService,
+    ) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::republish_onion() is called");
+        defer!(trace!("returning from RYOKUCHATSession::republish_onion()"));
+
+        let users = self.get_users().await?;
+        let clients = match onion.client_auth {
+            true => authorized_clients(&onion.client_key, users.iter().map(|u| &u.client_auth)),
+            false => Vec::new(),
+        };
+        control.del_onion(&onion.service_id).await?;
+        control
+            .add_onion(&onion.key, onion.virtual_port, &onion.target, &clients)
+            .await?;
+        info!("onion service is republished for {} clients", clients.len());
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// 新しい鍵でHidden Serviceを作り、自分のアドレスを変更します  
+    /// 全ての連絡先に新しいホスト名を署名付きで送り、受け取った相手の連絡先リストは自動で書き換えられます  
+    /// 引数について:  
+    /// 古いHidden Serviceを残しておく時間を指定します  
+    /// その間は古いアドレスでも接続を受け付けるので、オフラインだった連絡先も移行できます  
+    /// 返り値について:  
+    /// 成功ならば、新しいホスト名を送れなかった連絡先のIDが入ったVecが返ります  
+    /// それらの連絡先には新しいアドレスを別の方法で伝えてください  
+    /// Torを使っていない場合はError::Configになります  
+    /// 注意点:  
+    /// 新しいアドレスはMessage::OnionReadyでも通知されます  
+    /// 古いHidden Serviceを残しておく時間が過ぎる前にセッションを終了すると、その時点で古いアドレスは使えなくなります  
+    pub async fn rotate_onion(&self, grace_period: Duration) -> Result<Vec<PublicKey>, Error> {
+        trace!("RYOKUCHATSession::rotate_onion() is called");
+        defer!(trace!("returning from RYOKUCHATSession::rotate_onion()"));
+
+        // shutdownが呼ばれた場合は、連絡先に送り終わるまで待ってもらう
+        let _sending = self.inner.sending.read().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        let tor_transport = self.inner.tor_transport.as_ref().err_into(|_| {
+            Error::Config("the address can be rotated only when Tor is used".to_string())
+        })?;
+
+        let mut control_lock = self.inner.control.lock().await;
+        let control = control_lock.as_mut().err_into(|_| Error::Closed)?;
+        let mut onion_lock = self.inner.onion.write().await;
+        let onion = onion_lock.as_mut().err_into(|_| {
+            Error::Config("the address can be rotated only when Tor is used".to_string())
+        })?;
+
+        // 古いHidden Serviceを残したまま、同じ連絡先を許可した新しいものを作る
+        let users = self.get_users().await?;
+        let clients = match onion.client_auth {
+            true => authorized_clients(&onion.client_key, users.iter().map(|u| &u.client_auth)),
+            false => Vec::new(),
+        };
+        let added = control
+            .add_onion(
+                "NEW:ED25519-V3",
+                onion.virtual_port,
+                &onion.target,
+                &clients,
+            )
+            .await?;
+        let key = added
+            .private_key
+            .err_into(|_| Error::Tor("ADD_ONION returned no PrivateKey".to_string()))?;
+        if onion.client_auth {
+            let update = self
+                .inner
+                .client_auth_keys
+                .add(&added.service_id, &onion.client_key.secret);
+            apply_client_auth(control, &added.service_id, update).await?;
+        }
+        let passphrase = self.passphrase();
+        write_key_file(
+            &onion.key_file,
+            KeyType::OnionService,
+            key.as_bytes(),
+            passphrase.as_ref().map(|p| p.0.as_str()),
+        )
+        .await?;
+        info!("the onion service key is replaced");
+
+        let old_service_id = std::mem::replace(&mut onion.service_id, added.service_id);
+        let old_secret = onion.client_key.secret.clone();
+        onion.key = key;
+        let hostname = format!("{}.onion", &onion.service_id);
+        let client_auth = match onion.client_auth {
+            true => Some(onion.client_key.public.clone()),
+            false => None,
+        };
+        tor_transport.set_hostname(hostname.clone());
+        let address = UserData {
+            id: PublicKey::from(&*self.myprivkey()),
+            hostname: hostname.clone(),
+            username: None,
+            client_auth: client_auth.clone(),
+        }
+        .get_address();
+        match self.inner.myaddress.write() {
+            Ok(mut o) => *o = address.clone(),
+            Err(e) => *e.into_inner() = address.clone(),
+        }
+        drop(onion_lock);
+        drop(control_lock);
+        info!("myaddress is changed to {}", &address);
+        self.notify(Message::OnionReady(address));
+
+        // 猶予が過ぎたら古いHidden Serviceを消す
+        let weak = self.downgrade();
         let handle = tokio::spawn(async move {
-            let mut stream;
-            loop {
-                stream = match TcpStream::connect(format!("{}:{}", localhost, control_port)).await {
-                    Ok(o) => BufStream::new(o),
-                    Err(_) => continue,
-                };
-                break;
-            }
-            stream.write_all(b"AUTHENTICATE \"").await.unwrap();
-            stream.write_all(control_passwd.as_bytes()).await.unwrap();
-            stream.write_all(b"\"\r\n").await.unwrap();
-            stream.flush().await.unwrap();
-            let mut a = String::new();
-            stream.read_line(&mut a).await.unwrap();
-            stream.write_all(b"TAKEOWNERSHIP\r\n").await.unwrap();
-            stream.flush().await.unwrap();
-            stream.read_line(&mut a).await.unwrap();
-            loop {
-                tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+            tokio::time::sleep(grace_period).await;
+            let result = async {
+                let session = RYOKUCHATSession::upgrade(&weak)?;
+                let mut control = session.inner.control.lock().await;
+                let control = control.as_mut().err_into(|_| Error::Closed)?;
+                control.del_onion(&old_service_id).await?;
+                let update = session
+                    .inner
+                    .client_auth_keys
+                    .remove(&old_service_id, &old_secret);
+                apply_client_auth(control, &old_service_id, update).await?;
+                info!("the old onion service {} is removed", &old_service_id);
+                Ok::<(), Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("failed to remove the old onion service: {}", e);
             }
         });
-        session.handles.push(HandleWrapper(handle));
+        self.inner.handles.lock().await.push(HandleWrapper(handle));
+
+        // 連絡先に新しいホスト名を送る
+        let data = MessageForNetwork::MigrateHostname(hostname, client_auth);
+        let data = bincode::serialize(&data).err_into(Error::Protocol)?;
+        let mut failed = Vec::new();
+        for user in users {
+            if let Err(e) = self.send(&user.id, &data).await {
+                warn!(
+                    "could not tell {} the new address: {}",
+                    user.get_address(),
+                    e
+                );
+                failed.push(user.id);
+            }
+        }
+        Ok(failed)
+    }
 
-        // メッセージを受信するスレッド
-        // ライフタイムエラーを消すためにtransmuteを使っているが、RYOKUCHATSessionには書き換えられうる値にはMutexやRwLockを使っており、RYOKUCHATSessionの実体はヒープ上にあるので安全
-        let s = unsafe { std::mem::transmute::<&RYOKUCHATSession, &RYOKUCHATSession>(&*session) };
-        let handle = tokio::spawn(async move {
-            let session = s;
-            let listen = TcpListener::bind(format!("{}:{}", &session.localhost, ryokuchat_port))
+    /// 動作の説明:  
+    /// 新しい秘密鍵を作り、自分のIDを変更します  
+    /// 古い鍵と新しい鍵の両方で署名した移行の宣言を全ての連絡先に送り、受け取った相手の連絡先リストは自動で書き換えられます  
+    /// 返り値について:  
+    /// 成功ならば、移行の宣言を送れなかった連絡先のIDが入ったVecが返ります  
+    /// それらの連絡先にはresend_identity_rotationで後から送り直せます  
+    /// 注意点:  
+    /// 新しい秘密鍵は今のパスフレーズで暗号化して保存されます  
+    /// 新しいアドレスはMessage::OnionReadyでも通知されます  
+    /// 古いIDで張られていた接続は全て閉じられます  
+    /// 古い秘密鍵は、全ての連絡先に宣言を送り終えるまでSessionBuilder::previous_key_fileに残ります  
+    pub async fn rotate_identity(&self) -> Result<Vec<PublicKey>, Error> {
+        trace!("RYOKUCHATSession::rotate_identity() is called");
+        defer!(trace!("returning from RYOKUCHATSession::rotate_identity()"));
+
+        // 古い鍵で署名したメッセージと新しい鍵で署名したメッセージが混ざらないように、他の送信を止める
+        let sending = self.inner.sending.write().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        let passphrase = self.passphrase();
+        let passphrase = passphrase.as_ref().map(|p| p.0.as_str());
+
+        let old_key = self.myprivkey();
+        let old_id = PublicKey::from(&*old_key);
+        let new_key = PrivateKey::new(&mut rand::rngs::OsRng);
+        let address = UserData {
+            id: PublicKey::from(&new_key),
+            ..decode_address(&self.myaddress())?
+        }
+        .get_address();
+
+        // 途中で終了しても送り直せるように、新しい鍵を保存する前に古い鍵と送り先を残しておく
+        let mut previous = read_previous_keys(&self.inner.previous_key_file, passphrase).await?;
+        previous.push(*old_key.as_bytes());
+        write_previous_keys(&self.inner.previous_key_file, &previous, passphrase).await?;
+        let mut users = self.database().await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO pending_rotations (id, old_id) SELECT id, ? FROM users;",
+        )
+        .bind(old_id.as_byte().as_slice())
+        .execute(&mut *users)
+        .await
+        .err_exec(|e| error!("{}", e))?;
+        drop(users);
+
+        write_key_file(
+            &self.inner.key_file,
+            KeyType::Identity,
+            new_key.as_bytes(),
+            passphrase,
+        )
+        .await?;
+        info!("the identity key is replaced");
+
+        match self.inner.myprivkey.write() {
+            Ok(mut o) => *o = Arc::new(new_key),
+            Err(e) => *e.into_inner() = Arc::new(new_key),
+        }
+        match self.inner.myaddress.write() {
+            Ok(mut o) => *o = address.clone(),
+            Err(e) => *e.into_inner() = address.clone(),
+        }
+        info!("myaddress is changed to {}", &address);
+
+        // 古いIDで張られた接続は使えないので閉じる
+        self.close_connections().await;
+        self.notify(Message::OnionReady(address));
+
+        let failed = self.deliver_rotations().await;
+        drop(sending);
+        failed
+    }
+
+    /// 動作の説明:  
+    /// rotate_identityで移行の宣言を送れなかった連絡先に、もう一度送ります  
+    /// 返り値について:  
+    /// 成功ならば、今回も送れなかった連絡先のIDが入ったVecが返ります  
+    /// 注意点:  
+    /// 送れなかった連絡先が無い場合は何もせずに空のVecが返ります  
+    /// 宣言に署名する古い秘密鍵が見つからない場合は、Message::StorageErrorが届き、その連絡先は送れなかったものとして残ります  
+    pub async fn resend_identity_rotation(&self) -> Result<Vec<PublicKey>, Error> {
+        trace!("RYOKUCHATSession::resend_identity_rotation() is called");
+        defer!(trace!(
+            "returning from RYOKUCHATSession::resend_identity_rotation()"
+        ));
+
+        let _sending = self.inner.sending.write().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        self.deliver_rotations().await
+    }
+
+    // pending_rotationsに残っている連絡先に、相手が知っている古い鍵で移行の宣言を送る
+    // 送れた連絡先はpending_rotationsから消し、どの連絡先にも要らなくなった古い鍵は捨てる
+    async fn deliver_rotations(&self) -> Result<Vec<PublicKey>, Error> {
+        trace!("RYOKUCHATSession::deliver_rotations() is called");
+        defer!(trace!(
+            "returning from RYOKUCHATSession::deliver_rotations()"
+        ));
+
+        let passphrase = self.passphrase();
+        let passphrase = passphrase.as_ref().map(|p| p.0.as_str());
+        let new_key = self.myprivkey();
+        let new_id = PublicKey::from(&*new_key);
+        let previous = read_previous_keys(&self.inner.previous_key_file, passphrase).await?;
+        let previous: Vec<_> = previous.iter().map(PrivateKey::from).collect();
+
+        let mut database = self.database().await?;
+        let pending =
+            sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>("SELECT id, old_id FROM pending_rotations;")
+                .fetch_all(&mut *database)
                 .await
-                .unwrap();
-            debug!("listen loop started");
-            loop {
-                if let Ok((o, _)) = listen.accept().await {
-                    tokio::spawn(async move {
-                        info!("new connection come");
+                .err_exec(|e| error!("{}", e))?;
+        drop(database);
 
-                        let mut stream = BufStream::new(o);
+        let mut failed = Vec::new();
+        for (id, old_id) in pending {
+            let delivered = async {
+                let user = match self
+                    .get_user_from_id(&PublicKey::try_from(id.as_slice()).err_into(Error::Address)?)
+                    .await
+                {
+                    Ok(o) => o,
+                    // 連絡先から消した場合は送らない
+                    Err(Error::UnknownUser) => return Ok(true),
+                    Err(e) => return Err(e),
+                };
+                let old_key = previous
+                    .iter()
+                    .find(|k| PublicKey::from(*k).as_byte().as_slice() == old_id.as_slice());
+                let old_key = match old_key {
+                    Some(o) => o,
+                    // 鍵のファイルを失った場合は送れないが、戻せば送り直せるように残しておく
+                    None => {
+                        error!("the previous identity key for a pending rotation is not found");
+                        self.notify(Message::StorageError(format!(
+                            "the previous identity key for {} is not found in {:?}",
+                            user.get_address(),
+                            &self.inner.previous_key_file
+                        )));
+                        failed.push(user.id);
+                        return Ok(false);
+                    }
+                };
 
-                        // 57バイトの公開鍵(ID)
-                        let mut key = [0; KEY_LENGTH];
-                        stream.read_exact(&mut key).await.ok()?;
-                        let key = PublicKey::try_from(&key)
-                            .err_exec(|e| error!("{}", e))
-                            .ok()?;
+                let statement = rotation_statement(&PublicKey::from(old_key), &new_id);
+                let old_sign = old_key.sign(&statement, None).err_into(Error::Protocol)?;
+                let new_sign = new_key.sign(&statement, None).err_into(Error::Protocol)?;
+                let data = MessageForNetwork::RotateIdentity(
+                    new_id.as_byte().to_vec(),
+                    old_sign.to_vec(),
+                    new_sign.to_vec(),
+                );
+                let data = bincode::serialize(&data).err_into(Error::Protocol)?;
+                match self.send_once(old_key, &user.hostname, &data).await {
+                    Ok(_) => Ok(true),
+                    Err(e) => {
+                        warn!(
+                            "could not tell {} the new identity: {}",
+                            user.get_address(),
+                            e
+                        );
+                        failed.push(user.id);
+                        Ok(false)
+                    }
+                }
+            }
+            .await?;
 
-                        // 連絡先リストに相手のアドレスがあることを確認
-                        let user = session
-                            .get_user_from_id(&key)
-                            .await
-                            .err_exec(|_| error!("This connection is from an unknown source."))?;
+            if delivered {
+                let mut database = self.database().await?;
+                sqlx::query("DELETE FROM pending_rotations WHERE id=?;")
+                    .bind(&id)
+                    .execute(&mut *database)
+                    .await
+                    .err_exec(|e| error!("{}", e))?;
+            }
+        }
 
-                        // 16バイトの認証用メッセージ
-                        let auth = rand::rngs::OsRng.gen::<u128>().to_be_bytes();
-                        stream.write_all(&auth).await.ok()?;
-                        stream.flush().await.ok()?;
-                        let mut sign = [0; SIG_LENGTH];
-                        stream.read_exact(&mut sign).await.ok()?;
-                        user.id
-                            .verify(&greeting_auth(&auth)?, &sign, None)
-                            .err_exec(|_| error!("failed to verify the connection source"))
-                            .ok()?;
+        // まだ送れていない連絡先が知っている鍵だけを残す
+        let mut database = self.database().await?;
+        let needed =
+            sqlx::query_scalar::<_, Vec<u8>>("SELECT DISTINCT old_id FROM pending_rotations;")
+                .fetch_all(&mut *database)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+        drop(database);
+        let previous: Vec<_> = previous
+            .iter()
+            .filter(|k| {
+                needed
+                    .iter()
+                    .any(|id| id.as_slice() == PublicKey::from(*k).as_byte().as_slice())
+            })
+            .map(|k| *k.as_bytes())
+            .collect();
+        write_previous_keys(&self.inner.previous_key_file, &previous, passphrase).await?;
 
-                        info!("this connection is from {}", user.get_address());
+        Ok(failed)
+    }
 
-                        process_message(session, key, stream).await;
-                        Some(())
-                    });
+    // 今のパスフレーズを取り出す
+    fn passphrase(&self) -> Option<Passphrase> {
+        match self.inner.passphrase.read() {
+            Ok(o) => o.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    // 今の秘密鍵を取り出す
+    fn myprivkey(&self) -> Arc<PrivateKey> {
+        match self.inner.myprivkey.read() {
+            Ok(o) => o.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    // 相手との接続を全て閉じる
+    async fn close_connections(&self) {
+        let connections = std::mem::take(&mut *self.inner.user_data_temp.write().await);
+        for (id, connection) in connections {
+            if let Err(e) = connection.send.lock().await.shutdown().await {
+                warn!("failed to close the connection: {}", e);
+            }
+            connection.handle.stop().await;
+            self.notify(Message::Disconnected(PublicKey::from(id)));
+        }
+    }
+
+    // 内部のスレッドが持っているWeakからRYOKUCHATSessionを取り出す
+    // 既にdropされていた場合はError::Closedになる
+    pub(crate) fn upgrade(weak: &Weak<SessionInner>) -> Result<RYOKUCHATSession, Error> {
+        Ok(RYOKUCHATSession::from_inner(
+            weak.upgrade().err_into(|_| Error::Closed)?,
+        ))
+    }
+
+    pub(crate) fn downgrade(&self) -> Weak<SessionInner> {
+        Arc::downgrade(&self.inner)
+    }
+
+    fn from_inner(inner: Arc<SessionInner>) -> RYOKUCHATSession {
+        RYOKUCHATSession { inner }
+    }
+
+    /// 動作の説明:  
+    /// セッションを終了します  
+    /// 新しい接続の受け付けを止め、送信中のメッセージを送り終えてから相手との接続を順番に閉じます  
+    /// その後SQLiteを閉じてTorを終了させ、全て終わってから戻ります  
+    /// 注意点:  
+    /// 終了後のRYOKUCHATSessionのメソッドはError::Closedを返します  
+    /// 既に終了している場合は何もせずにOk(())が返ります  
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::shutdown() is called");
+        defer!(trace!("returning from RYOKUCHATSession::shutdown()"));
+
+        if self.inner.closed.swap(true, Ordering::SeqCst) {
+            info!("already shut down");
+            return Ok(());
+        }
+        let mut result = Ok(());
+
+        // 新しい接続の受け付けを止める
+        let handles = std::mem::take(&mut *self.inner.handles.lock().await);
+        for handle in handles {
+            handle.stop().await;
+        }
+        info!("stopped accepting connections");
+
+        // 送信中のメッセージを送り終えるまで待つ
+        let sending = self.inner.sending.write().await;
+        info!("pending messages are sent");
+
+        // 相手との接続を閉じる
+        self.close_connections().await;
+        drop(sending);
+        info!("all connections are closed");
+
+        // SQLiteを閉じる
+        if let Some(database) = self.inner.user_database.lock().await.take() {
+            if let Err(e) = database.close().await {
+                error!("{}", e);
+                result = Err(Error::Storage(e));
+            }
+        }
+        info!("database is closed");
+
+        // 組み込みのTorを終了させる
+        // SIGNAL SHUTDOWNに失敗しても、ControlPortとの接続が切れればTorは終了する
+        // 既に動いているTorを使っている場合は、接続を閉じてHidden Serviceを消すだけにする
+        let mut control = self.inner.control.lock().await.take();
+        let tor = self.inner.tor.lock().await.take();
+
+        // 同じTorを使う他のセッションのために、登録したクライアント認証の鍵を外すか、他のセッションの鍵に置き換える
+        let secret = self
+            .inner
+            .onion
+            .read()
+            .await
+            .as_ref()
+            .map(|o| o.client_key.secret.clone());
+        if let Some(secret) = secret {
+            let updates = self.inner.client_auth_keys.release(&secret);
+            if let (Some(control), None) = (control.as_mut(), &tor) {
+                for (service_id, update) in updates {
+                    if let Err(e) = apply_client_auth(control, &service_id, update).await {
+                        warn!("failed to update the client authorization: {}", e);
+                    }
                 }
             }
-        });
-        session.handles.push(HandleWrapper(handle));
+        }
 
-        session
+        if let (Some(mut control), Some(_)) = (control, &tor) {
+            if let Err(e) = control.signal("SHUTDOWN").await {
+                warn!("failed to send SIGNAL SHUTDOWN: {}", e);
+            }
+        }
+        if let Some(tor) = tor {
+            match tokio::time::timeout(Duration::from_secs(30), tor).await {
+                Ok(Ok(_)) => info!("Tor is stopped"),
+                Ok(Err(e)) => {
+                    error!("{}", e);
+                    result = result.and(Err(Error::Tor(e.to_string())));
+                }
+                Err(_) => {
+                    error!("Tor did not exit in time");
+                    result = result.and(Err(Error::Tor("Tor did not exit in time".to_string())));
+                }
+            }
+        }
+
+        result
+    }
+
+    /// 動作の説明:  
+    /// 新規メッセージなどの通知を受け取るためのStreamを作ります  
+    /// 何回でも呼ぶことができ、それぞれのStreamには全ての通知が届きます  
+    /// 注意点:  
+    /// 受け取りが遅れて未読の通知がSessionBuilder::event_capacityを超えた場合、古いものから捨てられます  
+    /// その場合は捨てられた数がMessage::Laggedで届き、その後は続きの通知が届きます  
+    /// 通知はsubscribeした後に起きたものだけが届きます  
+    pub fn subscribe(&self) -> impl Stream<Item = Message> + Send + Unpin + 'static {
+        trace!("RYOKUCHATSession::subscribe() is called");
+        defer!(trace!("returning from RYOKUCHATSession::subscribe()"));
+
+        event_stream(self.inner.events.subscribe())
+    }
+
+    // 全てのsubscribeしているStreamに通知を送る
+    pub(crate) fn notify(&self, message: Message) {
+        send_event(&self.inner.events, message);
+    }
+
+    // ストレージのエラーだった場合は通知を送る
+    pub(crate) fn notify_storage_error(&self, e: &Error) {
+        if let Error::Storage(e) = e {
+            self.notify(Message::StorageError(e.to_string()));
+        }
+    }
+
+    // SQLiteとの接続を取得する
+    // shutdown後はError::Closedになる
+    async fn database(&self) -> Result<MappedMutexGuard<'_, sqlx::SqliteConnection>, Error> {
+        MutexGuard::try_map(self.inner.user_database.lock().await, |d| d.as_mut())
+            .map_err(|_| Error::Closed)
     }
 
     /// 動作の説明:  
     /// 自分自身のアドレスを取得します  
     /// これを相手に渡すことで通信が出来ます  
-    pub fn myaddress(&self) -> &str {
+    /// 注意点:  
+    /// rotate_onionを呼ぶと変わります  
+    pub fn myaddress(&self) -> String {
         trace!("RYOKUCHATSession::myaddress() is called");
         defer!(trace!("returning from RYOKUCHATSession::myaddress()"));
 
-        debug!("self.myaddress is {}", &self.myaddress);
-        &self.myaddress
+        let myaddress = match self.inner.myaddress.read() {
+            Ok(o) => o.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        debug!("self.inner.myaddress is {}", &myaddress);
+        myaddress
+    }
+
+    /// 動作の説明:  
+    /// 秘密鍵のファイルのパスフレーズを変更します  
+    /// Hidden Serviceの鍵とクライアント認証の鍵も、新しいパスフレーズで保存し直します  
+    /// 引数について:  
+    /// 新しいパスフレーズを指定します  
+    /// Noneを指定した場合はパスフレーズを外し、暗号化せずに保存します  
+    /// 返り値について:  
+    /// 空のパスフレーズを指定した場合はError::Configになります  
+    /// 注意点:  
+    /// 次にセッションを作るときは、SessionBuilder::passphraseで新しいパスフレーズを指定してください  
+    /// 書き込みに失敗した場合は、書き換えたファイルを元のパスフレーズで保存し直し、元のパスフレーズのまま使えます  
+    pub async fn change_passphrase(&self, passphrase: Option<&str>) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::change_passphrase() is called");
+        defer!(trace!(
+            "returning from RYOKUCHATSession::change_passphrase()"
+        ));
+
+        // rotate_identityで鍵を置き換えている間は、終わるまで待つ
+        let _sending = self.inner.sending.write().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        let old_passphrase = self.passphrase();
+        let old_passphrase = old_passphrase.as_ref().map(|p| p.0.as_str());
+
+        // 書き換える前に全ての鍵を読み出しておく
+        let mut files = vec![(
+            &self.inner.key_file,
+            KeyType::Identity,
+            self.myprivkey().as_bytes().to_vec(),
+        )];
+        let previous = read_previous_keys(&self.inner.previous_key_file, old_passphrase).await?;
+        if !previous.is_empty() {
+            files.push((
+                &self.inner.previous_key_file,
+                KeyType::PreviousIdentity,
+                previous.concat(),
+            ));
+        }
+        for (path, key_type) in [
+            (&self.inner.onion_key_file, KeyType::OnionService),
+            (&self.inner.client_auth_key_file, KeyType::ClientAuth),
+        ] {
+            if let Some(s) = read_key_file(path, key_type, old_passphrase).await? {
+                files.push((path, key_type, s));
+            }
+        }
+
+        for (i, (path, key_type, payload)) in files.iter().enumerate() {
+            if let Err(e) = write_key_file(path, *key_type, payload, passphrase).await {
+                // 次に起動するときに全ての鍵が同じパスフレーズで開けるように、書き換えたものを元に戻す
+                for (path, key_type, payload) in &files[..i] {
+                    if let Err(e) = write_key_file(path, *key_type, payload, old_passphrase).await {
+                        error!("could not restore {:?}: {}", path, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        let new_passphrase = passphrase.map(|p| Passphrase(p.to_string()));
+        match self.inner.passphrase.write() {
+            Ok(mut o) => *o = new_passphrase,
+            Err(e) => *e.into_inner() = new_passphrase,
+        }
+        match passphrase {
+            Some(_) => info!("the passphrase of {:?} is changed", &self.inner.key_file),
+            None => info!("the passphrase of {:?} is removed", &self.inner.key_file),
+        }
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// 自分の秘密鍵、Hidden Serviceの鍵、連絡先などのデータベースを1つのファイルにまとめて保存します  
+    /// IDの移行の宣言をまだ送れていない連絡先がいる場合は、送り直すための古い秘密鍵も入ります  
+    /// 別のマシンに移るときは、このファイルをimport_backupで復元してください  
+    /// 引数について:  
+    /// 1: 保存するファイルを指定します。既にある場合は上書きされます  
+    /// 2: バックアップを暗号化するパスフレーズを指定します  
+    /// 返り値について:  
+    /// 空のパスフレーズを指定した場合はError::Configになります  
+    /// 注意点:  
+    /// バックアップは必ず暗号化されます。秘密鍵のファイルのパスフレーズとは別のものを指定できます  
+    pub async fn export_backup(&self, path: &Path, passphrase: &str) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::export_backup() is called");
+        defer!(trace!("returning from RYOKUCHATSession::export_backup()"));
+
+        // rotate_identityで鍵を置き換えている間は、終わるまで待つ
+        let _sending = self.inner.sending.read().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        if passphrase.is_empty() {
+            error!("the passphrase is empty");
+            return Err(Error::Config("the passphrase is empty".to_string()));
+        }
+
+        // 使用中のデータベースを、データディレクトリの中の一時ファイルに書き出してから読み込む
+        // 暗号化されていないため、バックアップの保存先には置かない
+        let temp = TempFile(self.inner.data_dir.join(format!(
+            "export-{:016x}.sqlite",
+            rand::rngs::OsRng.gen::<u64>()
+        )));
+        let mut database = self.database().await?;
+        let result = sqlx::query("VACUUM INTO ?;")
+            .bind(path_to_str(&temp.0)?)
+            .execute(&mut *database)
+            .await;
+        drop(database);
+        result.err_exec(|e| error!("{}", e))?;
+        let database = fs::read(&temp.0).await;
+        drop(temp);
+
+        let key_passphrase = self.passphrase();
+        let key_passphrase = key_passphrase.as_ref().map(|p| p.0.as_str());
+        let backup = Backup {
+            identity: *self.myprivkey().as_bytes(),
+            onion_key: read_key_file(
+                &self.inner.onion_key_file,
+                KeyType::OnionService,
+                key_passphrase,
+            )
+            .await?,
+            client_auth_key: read_key_file(
+                &self.inner.client_auth_key_file,
+                KeyType::ClientAuth,
+                key_passphrase,
+            )
+            .await?,
+            database: database?,
+            previous_keys: read_previous_keys(&self.inner.previous_key_file, key_passphrase)
+                .await?,
+        };
+        write_key_file(path, KeyType::Backup, &backup.encode(), Some(passphrase)).await?;
+        info!("the backup is saved to {:?}", path);
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// export_backupで作ったバックアップを、builderの設定で使われる場所に復元します  
+    /// 復元した後にbuilderでセッションを作ると、バックアップを作ったときと同じIDとアドレスで使えます  
+    /// 引数について:  
+    /// 1: 復元する先のSessionBuilderを指定します  
+    /// 2: バックアップのファイルを指定します  
+    /// 3: バックアップを作ったときのパスフレーズを指定します  
+    /// 返り値について:  
+    /// パスフレーズが間違っている場合はError::Lockedになります  
+    /// 復元する先に既に秘密鍵やデータベースなどのファイルがある場合は、上書きせずにError::Configになります  
+    /// 注意点:  
+    /// builderにpassphraseが指定されている場合は、復元した秘密鍵やHidden Serviceの鍵をそのパスフレーズで暗号化して保存します  
+    pub async fn import_backup(
+        builder: &SessionBuilder,
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::import_backup() is called");
+        defer!(trace!("returning from RYOKUCHATSession::import_backup()"));
+
+        let backup = read_key_file(path, KeyType::Backup, Some(passphrase))
+            .await?
+            .err_into(|_| Error::KeyFile(format!("{:?} is not found", path)))?;
+        let backup = Backup::decode(&backup)?;
+
+        let key_file = builder.data_dir.join(&builder.key_file);
+        let database_file = builder.data_dir.join(&builder.database_file);
+        let onion_key_file = builder.data_dir.join(&builder.onion_key_file);
+        let client_auth_key_file = builder.data_dir.join(&builder.client_auth_key_file);
+        let previous_key_file = builder.data_dir.join(&builder.previous_key_file);
+        let files = [
+            &database_file,
+            &key_file,
+            &onion_key_file,
+            &client_auth_key_file,
+            &previous_key_file,
+        ];
+        // 他のIDのデータと混ざらないように、1つでもある場合は何も書き込まない
+        for file in files {
+            if fs::symlink_metadata(file).await.is_ok() {
+                error!("{:?} already exists", file);
+                return Err(Error::Config(format!("{:?} already exists", file)));
+            }
+        }
+        for file in files {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir)
+                    .await
+                    .err_exec(|e| error!("{}", e))?;
+            }
+        }
+
+        // 途中で失敗してもやり直せるように、秘密鍵は最後に書き込む
+        let passphrase = builder.passphrase.as_ref().map(|p| p.0.as_str());
+        replace_file(&database_file, &backup.database).await?;
+        if let Some(s) = &backup.onion_key {
+            write_key_file(&onion_key_file, KeyType::OnionService, s, passphrase).await?;
+        }
+        if let Some(s) = &backup.client_auth_key {
+            write_key_file(&client_auth_key_file, KeyType::ClientAuth, s, passphrase).await?;
+        }
+        write_previous_keys(&previous_key_file, &backup.previous_keys, passphrase).await?;
+        write_key_file(&key_file, KeyType::Identity, &backup.identity, passphrase).await?;
+        info!("the backup is restored to {:?}", &builder.data_dir);
+        Ok(())
     }
 
     /// 動作の説明:  
     /// 実行された時点での連絡先リストを取得します  
     /// 注意点:  
     /// 内部の連絡先リストと同期はされないため自分で変更を適用するか定期的に再取得してください  
-    pub async fn get_users(&self) -> Option<Vec<UserData>> {
+    pub async fn get_users(&self) -> Result<Vec<UserData>, Error> {
         trace!("RYOKUCHATSession::get_users() is called");
         defer!(trace!("returning from RYOKUCHATSession::get_users()"));
 
-        let mut database = self.user_database.lock().await;
+        let mut database = self.database().await?;
 
         let users = sqlx::query_as::<_, UserDataRaw>(
-            "SELECT id,hostname,username FROM users ORDER BY lastupdate DESC;",
+            "SELECT id,hostname,username,client_auth FROM users ORDER BY lastupdate DESC;",
         )
         .fetch_all(&mut *database)
         .await
-        .err_exec(|e| error!("{}", e))
-        .ok()?;
+        .err_exec(|e| error!("{}", e))?;
         drop(database);
 
-        let users = users
-            .into_iter()
-            .map(|u| u.to_userdata().unwrap())
-            .collect();
-
-        Some(users)
+        users.iter().map(|u| u.to_userdata()).collect()
     }
 
     /// 動作の説明:  
@@ -374,25 +1791,25 @@ impl RYOKUCHATSession {
     /// 引数について:  
     /// 引数にはIDを入れてください
     /// 返り値について:  
-    /// 成功ならばSomeに包まれたユーザー情報が、失敗ならばNoneが返ります  
-    pub async fn get_user_from_id(&self, id: &PublicKey) -> Option<UserData> {
+    /// 成功ならばOkに包まれたユーザー情報が、失敗ならばErrorが返ります  
+    /// 連絡先リストに存在しないIDの場合はError::UnknownUserになります  
+    pub async fn get_user_from_id(&self, id: &PublicKey) -> Result<UserData, Error> {
         trace!("RYOKUCHATSession::get_user_from_id() is called");
         defer!(trace!(
             "returning from RYOKUCHATSession::get_user_from_id()"
         ));
 
-        let mut users = self.user_database.lock().await;
+        let mut users = self.database().await?;
 
         let users = sqlx::query_as::<_, UserDataRaw>(
-            "SELECT id,hostname,username FROM users WHERE id=? LIMIT 1;",
+            "SELECT id,hostname,username,client_auth FROM users WHERE id=? LIMIT 1;",
         )
         .bind(id.as_byte().as_slice())
         .fetch_optional(&mut *users)
         .await
-        .err_exec(|e| error!("{}", e))
-        .ok()?;
+        .err_exec(|e| error!("{}", e))?;
 
-        users.err_exec(|_| error!("unknown id"))?.to_userdata()
+        users.err_into(|_| Error::UnknownUser)?.to_userdata()
     }
 
     /// 動作の説明:  
@@ -400,35 +1817,44 @@ impl RYOKUCHATSession {
     /// 引数について:  
     /// 引数には&str型でアドレスを入れてください  
     /// アドレスは以下のような形式になります  
-    /// (ユーザーID)@(Tor Hidden Serviceのドメイン名)  
+    /// (ユーザーID)@(Tor Hidden Serviceのドメイン名)#(クライアント認証の公開鍵)  
+    /// #以降が無い古い形式のアドレスも追加できます  
     /// 返り値について:  
-    /// 成功ならばSome(())、失敗ならばNoneが返ります  
-    pub async fn add_user(&self, address: &str) -> Option<()> {
+    /// 成功ならばOk(())、失敗ならばErrorが返ります  
+    /// 既に追加されているユーザーの場合はError::UserExistsになります  
+    /// ホスト名が正しくない場合や、Torを使っているのにv3の.onionでない場合はError::Addressになります  
+    /// 注意点:  
+    /// Torを使っている場合は、追加したユーザーが自分のHidden Serviceに接続できるように作り直します  
+    /// 古い形式のアドレスで追加したユーザーは、クライアント認証を無効にしない限り自分に接続できません  
+    pub async fn add_user(&self, address: &str) -> Result<(), Error> {
         trace!("RYOKUCHATSession::add_user() is called");
         defer!(trace!("returning from RYOKUCHATSession::add_user()"));
         debug!("address is {}", address);
 
         let user = decode_address(address)?;
+        // Torを使っている場合は、v3の.onionにしか接続しない
+        check_hostname(&user.hostname, self.inner.tor_transport.is_some())?;
 
         match self.get_user_from_id(&user.id).await {
-            None => {
-                let mut users = self.user_database.lock().await;
+            Err(Error::UnknownUser) => {
+                let mut users = self.database().await?;
 
-                match sqlx::query("INSERT INTO users (lastupdate, id, hostname) VALUES (?, ?, ?);")
-                    .bind(chrono::Local::now().timestamp())
-                    .bind(user.id.as_byte().as_slice())
-                    .bind(user.hostname)
-                    .execute(&mut *users)
-                    .await
-                {
-                    Ok(_) => Some(()),
-                    Err(e) => {
-                        error!("{}", e);
-                        None
-                    }
-                }
+                sqlx::query(
+                    "INSERT INTO users (lastupdate, id, hostname, client_auth) VALUES (?, ?, ?, ?);",
+                )
+                .bind(chrono::Local::now().timestamp())
+                .bind(user.id.as_byte().as_slice())
+                .bind(&user.hostname)
+                .bind(&user.client_auth)
+                .execute(&mut *users)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+                drop(users);
+
+                self.update_onion_clients(Some(&user.hostname), None).await
             }
-            Some(_) => None,
+            Err(e) => Err(e),
+            Ok(_) => Err(Error::UserExists),
         }
     }
 
@@ -437,23 +1863,112 @@ impl RYOKUCHATSession {
     /// 引数について:  
     /// 引数にはIDを入れてください  
     /// 返り値について:  
-    /// 成功ならばSome(())が、失敗ならばNoneが返ります  
-    pub async fn del_user(&self, id: &PublicKey) -> Option<()> {
+    /// 成功ならばOk(())が、失敗ならばErrorが返ります  
+    /// 連絡先リストに存在しないIDの場合はError::UnknownUserになります  
+    /// 注意点:  
+    /// Torを使っている場合は、削除したユーザーが自分のHidden Serviceに接続できないように作り直します  
+    pub async fn del_user(&self, id: &PublicKey) -> Result<(), Error> {
         trace!("RYOKUCHATSession::del_user() is called");
         defer!(trace!("returning from RYOKUCHATSession::del_user()"));
 
-        let mut users = self.user_database.lock().await;
-        match sqlx::query("DELETE FROM users WHERE id=?;")
+        let user = self.get_user_from_id(id).await?;
+        let mut users = self.database().await?;
+        let result = sqlx::query("DELETE FROM users WHERE id=?;")
             .bind(id.as_byte().as_slice())
             .execute(&mut *users)
             .await
-        {
-            Ok(_) => Some(()),
-            Err(e) => {
-                error!("{}", e);
-                None
+            .err_exec(|e| error!("{}", e))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::UnknownUser);
+        }
+        drop(users);
+
+        self.update_onion_clients(None, Some(&user.hostname)).await
+    }
+
+    // 連絡先から届いた新しいホスト名とクライアント認証の公開鍵で連絡先リストを書き換える
+    pub(crate) async fn migrate_user(
+        &self,
+        id: &PublicKey,
+        hostname: &str,
+        client_auth: Option<String>,
+    ) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::migrate_user() is called");
+        defer!(trace!("returning from RYOKUCHATSession::migrate_user()"));
+
+        let user = self.get_user_from_id(id).await?;
+        let mut users = self.database().await?;
+        sqlx::query("UPDATE users SET hostname=?, client_auth=? WHERE id=?;")
+            .bind(hostname)
+            .bind(&client_auth)
+            .bind(id.as_byte().as_slice())
+            .execute(&mut *users)
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        drop(users);
+
+        // 接続するための鍵の登録先と、接続を許可する公開鍵を新しいものにする
+        if user.hostname != hostname {
+            self.update_onion_clients(Some(hostname), Some(&user.hostname))
+                .await?;
+        } else if user.client_auth != client_auth {
+            self.update_onion_clients(None, None).await?;
+        }
+
+        let user = UserData {
+            hostname: hostname.to_string(),
+            client_auth,
+            ..user
+        };
+        self.notify(Message::ContactMoved(id.clone(), user.get_address()));
+        Ok(())
+    }
+
+    // 連絡先から届いた移行の宣言に従って、連絡先のIDを新しいものに書き換える
+    // ホスト名などの他の情報はそのまま残す
+    pub(crate) async fn rotate_user(
+        &self,
+        id: &PublicKey,
+        new_id: &PublicKey,
+    ) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::rotate_user() is called");
+        defer!(trace!("returning from RYOKUCHATSession::rotate_user()"));
+
+        // 新しいIDが既に連絡先にある場合は書き換えない
+        let mut users = self.database().await?;
+        let result = sqlx::query(
+            "UPDATE users SET id=? WHERE id=? AND NOT EXISTS (SELECT 1 FROM users WHERE id=?);",
+        )
+        .bind(new_id.as_byte().as_slice())
+        .bind(id.as_byte().as_slice())
+        .bind(new_id.as_byte().as_slice())
+        .execute(&mut *users)
+        .await
+        .err_exec(|e| error!("{}", e))?;
+        // 自分の新しいIDをまだ伝えていない場合は、相手の新しいIDに送り直す
+        if result.rows_affected() != 0 {
+            sqlx::query("UPDATE pending_rotations SET id=? WHERE id=?;")
+                .bind(new_id.as_byte().as_slice())
+                .bind(id.as_byte().as_slice())
+                .execute(&mut *users)
+                .await
+                .err_exec(|e| error!("{}", e))?;
+        }
+        drop(users);
+
+        if result.rows_affected() == 0 {
+            if self.get_user_from_id(new_id).await.is_ok() {
+                error!("the new ID is already in the addressbook");
+                return Err(Error::Protocol(
+                    "the new ID is already in the addressbook".to_string(),
+                ));
             }
+            return Err(Error::UnknownUser);
         }
+
+        self.notify(Message::ContactRotated(id.clone(), new_id.clone()));
+        Ok(())
     }
 
     /// 動作の説明:  
@@ -462,31 +1977,45 @@ impl RYOKUCHATSession {
     /// 第1引数にはIDを入れてください  
     /// 第2引数には送信したいメッセージを入れます  
     /// 返り値について:  
-    /// 成功ならばSome(())が、失敗ならばNoneが返ります  
-    pub async fn send_dm(&self, id: &PublicKey, msg: &str) -> Option<()> {
+    /// 成功ならばOk(())が、失敗ならばErrorが返ります  
+    pub async fn send_dm(&self, id: &PublicKey, msg: &str) -> Result<(), Error> {
         trace!("RYOKUCHATSession::send_dm() is called");
         defer!(trace!("returning from RYOKUCHATSession::send_dm()"));
 
+        // shutdownが呼ばれた場合は、送信し終わるまで待ってもらう
+        let _sending = self.inner.sending.read().await;
+        if self.inner.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+
         let msg = msg.trim();
         info!("msg is {}", &msg);
         if msg.is_empty() {
             error!("tried to send a blank message");
-            return None;
+            return Err(Error::EmptyMessage);
         }
 
         let send_data = MessageForNetwork::DirectMsg(msg.to_string());
-        let send_data = bincode::serialize(&send_data)
-            .err_exec(|e| error!("{}", e))
-            .ok()?;
+        let send_data = bincode::serialize(&send_data).err_into(Error::Protocol)?;
 
-        self.send(id, &send_data).await?;
+        match self.send(id, &send_data).await {
+            Ok(_) => self.notify(Message::DeliverySucceeded(id.clone(), msg.to_string())),
+            Err(e) => {
+                self.notify(Message::DeliveryFailed(
+                    id.clone(),
+                    msg.to_string(),
+                    e.to_string(),
+                ));
+                return Err(e);
+            }
+        }
         self.new_lastupdate(id).await?;
 
-        Some(())
+        Ok(())
     }
 
     // 相手にデータを送信する
-    async fn send(&self, id: &PublicKey, data: &[u8]) -> Option<()> {
+    async fn send(&self, id: &PublicKey, data: &[u8]) -> Result<(), Error> {
         trace!("RYOKUCHATSession::send() is called");
         defer!(trace!("returning from RYOKUCHATSession::send()"));
 
@@ -494,36 +2023,74 @@ impl RYOKUCHATSession {
             .await
             .err_exec(|_| error!("failed to connect"))?;
 
-        let user_data_temp = self.user_data_temp.read().await;
+        let user_data_temp = self.inner.user_data_temp.read().await;
         let user_data_temp = user_data_temp
             .get(&id.as_byte())
-            .err_exec(|_| error!("something went wrong"))?;
-
-        let data_sign = self
-            .myprivkey
-            .sign(data, None)
-            .err_exec(|e| error!("{}", e))
-            .ok()?;
+            .err_into(|_| Error::Transport("the connection was closed".to_string()))?;
 
         let mut sender = user_data_temp.send.lock().await;
-        sender
-            .write_all(&(data.len() as u64).to_be_bytes())
-            .await
-            .ok()?;
-        sender.write_all(data).await.ok()?;
-        sender.write_all(&data_sign).await.ok()?;
-        sender.flush().await.ok()?;
+        write_message(&mut *sender, &self.myprivkey(), data).await?;
         drop(sender);
 
-        Some(())
+        Ok(())
+    }
+
+    // 接続を1つだけ張ってkeyで認証し、データを送ってから閉じる
+    // rotate_identityで、相手が知っている古いIDで移行の宣言を送るために使う
+    async fn send_once(&self, key: &PrivateKey, hostname: &str, data: &[u8]) -> Result<(), Error> {
+        trace!("RYOKUCHATSession::send_once() is called");
+        defer!(trace!("returning from RYOKUCHATSession::send_once()"));
+
+        let mut stream = self.dial(key, hostname).await?;
+        write_message(&mut stream, key, data).await?;
+        stream.shutdown().await.err_into(Error::Transport)?;
+        Ok(())
+    }
+
+    // 相手に接続し、keyで署名して自分のIDを証明する
+    async fn dial(
+        &self,
+        key: &PrivateKey,
+        hostname: &str,
+    ) -> Result<BufStream<BoxedConnection>, Error> {
+        trace!("RYOKUCHATSession::dial() is called");
+        defer!(trace!("returning from RYOKUCHATSession::dial()"));
+
+        let stream = self.inner.transport.dial(hostname).await?;
+        let mut stream = BufStream::new(stream);
+        info!("created new connection");
+
+        // 57バイトの公開鍵(ID)
+        let pubkey = PublicKey::try_from(key).err_into(Error::Handshake)?;
+        stream
+            .write_all(&pubkey.as_byte())
+            .await
+            .err_into(Error::Transport)?;
+        stream.flush().await.err_into(Error::Transport)?;
+
+        // 16バイトの検証用メッセージ
+        let mut auth = [0; 16];
+        stream
+            .read_exact(&mut auth)
+            .await
+            .err_into(Error::Transport)?;
+
+        // 114バイトの署名
+        let sign = key
+            .sign(&greeting_auth(&auth)?, None)
+            .err_into(Error::Handshake)?;
+        stream.write_all(&sign).await.err_into(Error::Transport)?;
+        stream.flush().await.err_into(Error::Transport)?;
+
+        Ok(stream)
     }
 
     // 新しく接続を開始する
-    async fn new_connection(&self, id: &PublicKey) -> Option<()> {
+    async fn new_connection(&self, id: &PublicKey) -> Result<(), Error> {
         trace!("RYOKUCHATSession::new_connection() is called");
         defer!(trace!("returning from RYOKUCHATSession::new_connection()"));
 
-        let user_data_temp = self.user_data_temp.read().await;
+        let user_data_temp = self.inner.user_data_temp.read().await;
         match user_data_temp.get(&id.as_byte()) {
             Some(_) => {
                 info!("already connected");
@@ -532,72 +2099,54 @@ impl RYOKUCHATSession {
                 info!("connecting to the other party...");
                 drop(user_data_temp);
 
-                let userdata = self.get_user_from_id(id).await?;
-                let stream = tokio_socks::tcp::Socks5Stream::connect(
-                    format!("{}:{}", &self.localhost, self.socks_port).as_str(),
-                    format!("{}:4545", userdata.hostname),
-                )
-                .await
-                .err_exec(|e| error!("{}", e))
-                .ok()?;
-                let mut stream = BufStream::new(stream);
-                info!("created new connection");
-
-                // 57バイトの公開鍵(ID)
-                let pubkey = PublicKey::try_from(&self.myprivkey).ok()?;
-                stream.write_all(&pubkey.as_byte()).await.ok()?;
-                stream.flush().await.ok()?;
-
-                // 16バイトの検証用メッセージ
-                let mut auth = [0; 16];
-                stream.read_exact(&mut auth).await.ok()?;
-
-                // 114バイトの署名
-                let sign = self.myprivkey.sign(&greeting_auth(&auth)?, None).ok()?;
-                stream.write_all(&sign).await.ok()?;
-                stream.flush().await.ok()?;
-
+                let userdata = self
+                    .get_user_from_id(id)
+                    .await
+                    .inspect_err(|e| self.notify_storage_error(e))?;
+                let stream = self.dial(&self.myprivkey(), &userdata.hostname).await?;
                 process_message(self, userdata.id, stream).await;
             }
         }
-        Some(())
+        Ok(())
     }
 
     // ユーザーの最終更新を現在の時刻に変更する
-    async fn new_lastupdate(&self, id: &PublicKey) -> Option<()> {
+    async fn new_lastupdate(&self, id: &PublicKey) -> Result<(), Error> {
         trace!("RYOKUCHATSession::new_lastupdate() is called");
         defer!(trace!("returning from RYOKUCHATSession::new_lastupdate()"));
 
         let timestamp = chrono::Local::now().timestamp();
         debug!("timestamp is {}", timestamp);
 
-        let mut users = self.user_database.lock().await;
+        let mut users = self.database().await?;
         sqlx::query("UPDATE users SET lastupdate=? WHERE id=?;")
             .bind(timestamp)
             .bind(id.as_byte().as_slice())
             .execute(&mut *users)
             .await
-            .err_exec(|e| error!("{}", e))
-            .ok()?;
+            .err_exec(|e| error!("{}", e))?;
         drop(users);
 
-        Some(())
+        Ok(())
     }
 }
 
 /// 連絡先リストに含まれるユーザーのデータです
 /// idにはそのユーザーのIDが内部表現で入っています
+/// client_authには相手のHidden Serviceのクライアント認証の公開鍵が入っており、古い形式のアドレスの場合はNoneになります  
 pub struct UserData {
     pub id: PublicKey,
     pub hostname: String,
     // stub: ユーザーネームを取得できるようにする
     pub username: Option<String>,
+    pub client_auth: Option<String>,
 }
 
 impl UserData {
     /// 動作の説明:  
     /// アドレスを取得します  
-    /// アドレスのフォーマットは(ユーザーID)@(Tor Hidden Serviceのホスト名)です  
+    /// アドレスのフォーマットは(ユーザーID)@(Tor Hidden Serviceのホスト名)#(クライアント認証の公開鍵)です  
+    /// クライアント認証の公開鍵が無い場合は#以降が省略されます  
     pub fn get_address(&self) -> String {
         trace!("UserData::get_address() is called");
         defer!(trace!("returning from UserData::get_address()"));
@@ -605,15 +2154,61 @@ impl UserData {
         let mut address = base64::encode_config(self.id.as_byte(), base64::URL_SAFE_NO_PAD);
         address.push('@');
         address.push_str(&self.hostname);
+        if let Some(client_auth) = &self.client_auth {
+            address.push('#');
+            address.push_str(client_auth);
+        }
 
         debug!("address is {}", &address);
         address
     }
 }
 
-/// メッセージを受信するときに使います
+/// メッセージやセッションの状態の変化を受信するときに使います  
+/// 今後バリアントが増える可能性があります  
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum Message {
     /// 新しい通常のメッセージが来た場合の情報を格納します  
     /// 1つ目にユーザーID、2つ目にメッセージが入ります  
     DirectMsg(PublicKey, String),
+    /// 受け取りが遅れたために捨てられた通知の数が入ります  
+    Lagged(u64),
+    /// 連絡先との接続が確立した場合に、そのユーザーIDが入ります  
+    Connected(PublicKey),
+    /// 連絡先との接続が切れた場合に、そのユーザーIDが入ります  
+    Disconnected(PublicKey),
+    /// メッセージの送信に成功した場合の情報を格納します  
+    /// 1つ目に送信先のユーザーID、2つ目にメッセージが入ります  
+    DeliverySucceeded(PublicKey, String),
+    /// メッセージの送信に失敗した場合の情報を格納します  
+    /// 1つ目に送信先のユーザーID、2つ目にメッセージ、3つ目に失敗した理由が入ります  
+    DeliveryFailed(PublicKey, String, String),
+    /// 連絡先リストに無いIDからの接続や、認証に失敗した接続を拒否した場合に、そのIDが入ります  
+    HandshakeRejected(PublicKey),
+    /// Torの起動の進み具合です  
+    /// 1つ目に進捗(%)、2つ目に今の段階の説明が入ります  
+    TorBootstrap(u8, String),
+    /// Torの起動が進まなくなった場合に、その時点の進捗(%)と理由が入ります  
+    /// ブリッジを使っている場合は、別のブリッジやPluggable Transportを試すことを勧めてください  
+    /// 起動は続けられ、進んだ場合はTorBootstrapが届きます  
+    BootstrapStuck(u8, String),
+    /// TorConfig::proxyやtorrcで指定されたプロキシを経由して、Torがリレーに接続できなかった場合に、Torが報告した理由が入ります  
+    /// プロキシのアドレスや認証情報が正しいかを確かめてもらってください  
+    ProxyError(String),
+    /// Tor Hidden Serviceなどの準備ができ、接続を受け付けられるようになった場合に、自分のアドレスが入ります  
+    OnionReady(String),
+    /// 連絡先がrotate_identityでIDを変え、連絡先リストを書き換えた場合に届きます  
+    /// 1つ目に古いID、2つ目に新しいIDが入ります  
+    ContactRotated(PublicKey, PublicKey),
+    /// Torを経由して自分のHidden Serviceに接続できることを確かめられた場合に届きます  
+    Online,
+    /// 自分のHidden Serviceに接続できなくなった場合に、その理由が入ります  
+    /// libteaは自動でHidden Serviceを作り直し、再び接続できるようになるとOnlineが届きます  
+    Offline(String),
+    /// 連絡先がrotate_onionでアドレスを変え、連絡先リストを書き換えた場合の情報を格納します  
+    /// 1つ目にユーザーID、2つ目に新しいアドレスが入ります  
+    ContactMoved(PublicKey, String),
+    /// バックグラウンドでのSQLiteの操作や、鍵のファイルの読み出しに失敗した場合に、その内容が入ります  
+    StorageError(String),
 }
@@ -0,0 +1,524 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// 1つのディレクトリの中で、名前を付けた複数のID(プロフィール)を管理する
+// プロフィールは(データを設置する場所)/profiles/(名前)に置かれ、それぞれが秘密鍵やHidden Service、データベースを持つ
+// Torは全てのプロフィールで1つを共有し、プロフィールごとにADD_ONIONでHidden Serviceを作る
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    fs,
+    sync::{broadcast, mpsc, Mutex},
+    time::Instant,
+};
+use tokio_stream::Stream;
+
+#[cfg(feature = "embedded-tor")]
+use crate::inside::structs::EmbeddedTor;
+use crate::{
+    inside::{
+        functions::replace_file,
+        structs::{ErrMsg, HandleWrapper, Passphrase},
+    },
+    tor_control::ControlConnection,
+    ControlAuth, Error, Message, RYOKUCHATSession, SessionBuilder, SessionInner,
+};
+
+// プロフィールの名前の最大の長さ
+const MAX_NAME_LENGTH: usize = 64;
+// 今使っているプロフィールの名前を書いておくファイル
+const CURRENT_FILE: &str = "current_profile";
+// 共有しているTorにNEWNYMを送る最短の間隔
+const NEWNYM_INTERVAL: Duration = Duration::from_secs(600);
+
+/// 名前を付けた複数のIDを、1つのディレクトリと1つのTorで管理します  
+/// 各プロフィールは別の秘密鍵、Hidden Service、データベースを持ち、同時に使うこともできます  
+/// 注意点:  
+/// 組み込みのTorはプロフィールを初めてopenしたときに起動し、shutdownするかdropするまで動き続けます  
+/// Torの回線は全てのプロフィールで共有されるため、NEWNYMは各プロフィールではなくProfileManagerが10分に1回まで送ります  
+/// 複数のプロフィールが同じ相手を連絡先にしている場合、相手に接続するためのクライアント認証の鍵は最後のプロフィールが消すまで残ります  
+pub struct ProfileManager {
+    // 各プロフィールのSessionBuilderの元になる設定
+    template: SessionBuilder,
+    // 共有しているTorのControlPortのアドレスと認証方法
+    tor: Mutex<Option<(String, ControlAuth)>>,
+    // 起動した組み込みのTor
+    #[cfg(feature = "embedded-tor")]
+    embedded: Mutex<Option<EmbeddedTor>>,
+    // openしているプロフィール
+    // セッションを作っている間はロックしないので、他のプロフィールの操作を待たせない
+    sessions: Mutex<HashMap<String, Weak<SessionInner>>>,
+    // プロフィールごとのロック
+    // openでセッションを作っている間は取られたままになり、同じプロフィールのopenやdeleteを待たせる
+    busy: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    // 各プロフィールからNEWNYMの依頼を受け取り、共有しているTorに送るスレッド
+    newnym: Mutex<Option<(mpsc::Sender<()>, HandleWrapper)>>,
+}
+
+impl ProfileManager {
+    /// 動作の説明:  
+    /// 新しくProfileManagerを作ります  
+    /// 引数について:  
+    /// SessionBuilderを指定します  
+    /// データを設置する場所がプロフィールを置く場所になり、Torやポートなどの設定が全てのプロフィールで使われます  
+    /// 注意点:  
+    /// 各プロフィールのportには、空いているポートが自動で選ばれます  
+    /// SessionBuilder::transportは1つのプロフィールでしか使えないため、指定した場合はopenがError::Configを返します  
+    pub fn new(builder: SessionBuilder) -> ProfileManager {
+        ProfileManager {
+            tor: Mutex::const_new(builder.system_tor.clone()),
+            template: builder,
+            #[cfg(feature = "embedded-tor")]
+            embedded: Mutex::const_new(None),
+            sessions: Mutex::const_new(HashMap::new()),
+            busy: Mutex::const_new(HashMap::new()),
+            newnym: Mutex::const_new(None),
+        }
+    }
+
+    /// 動作の説明:  
+    /// プロフィールの名前を並べて返します  
+    pub async fn list(&self) -> Result<Vec<String>, Error> {
+        trace!("ProfileManager::list() is called");
+        defer!(trace!("returning from ProfileManager::list()"));
+
+        let mut names = Vec::new();
+        let mut dir = match fs::read_dir(self.profiles_dir()).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => {
+                error!("{}", e);
+                return Err(Error::Io(e));
+            }
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str().filter(|n| is_valid_name(n)) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// 動作の説明:  
+    /// 新しくプロフィールを作ります  
+    /// 秘密鍵などは、初めてopenしたときに作られます  
+    /// 引数について:  
+    /// 英数字、-、_からなる64文字までの名前を指定します  
+    /// 返り値について:  
+    /// 名前が正しくない場合や、同じ名前のプロフィールが既にある場合はError::Configになります  
+    /// 注意点:  
+    /// 今使っているプロフィールが無い場合は、作ったプロフィールに切り替えます  
+    pub async fn create(&self, name: &str) -> Result<(), Error> {
+        trace!("ProfileManager::create() is called");
+        defer!(trace!("returning from ProfileManager::create()"));
+
+        let dir = self.profile_dir(name)?;
+        fs::create_dir_all(self.profiles_dir())
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        match fs::create_dir(&dir).await {
+            Ok(_) => info!("profile {} is created", name),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                error!("profile {} already exists", name);
+                return Err(Error::Config(format!("profile {} already exists", name)));
+            }
+            Err(e) => {
+                error!("{}", e);
+                return Err(Error::Io(e));
+            }
+        }
+
+        if self.current().await?.is_none() {
+            self.switch(name).await?;
+        }
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// プロフィールを削除します  
+    /// 秘密鍵や連絡先も全て消えるため、必要な場合は先にexport_backupでバックアップを作ってください  
+    /// 返り値について:  
+    /// プロフィールが無い場合や、openしていてshutdownされていない場合はError::Configになります  
+    pub async fn delete(&self, name: &str) -> Result<(), Error> {
+        trace!("ProfileManager::delete() is called");
+        defer!(trace!("returning from ProfileManager::delete()"));
+
+        let dir = self.existing_profile_dir(name).await?;
+        let busy = self.busy(name).await;
+        let _busy = busy.lock().await;
+        let mut sessions = self.sessions.lock().await;
+        if sessions.get(name).and_then(running).is_some() {
+            error!("profile {} is open", name);
+            return Err(Error::Config(format!("profile {} is open", name)));
+        }
+        sessions.remove(name);
+
+        fs::remove_dir_all(&dir)
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        info!("profile {} is deleted", name);
+        drop(sessions);
+
+        if self.current().await?.as_deref() == Some(name) {
+            fs::remove_file(self.template.data_dir.join(CURRENT_FILE))
+                .await
+                .err_exec(|e| error!("{}", e))?;
+        }
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// 今使っているプロフィールを切り替えます  
+    /// 切り替えたプロフィールは保存され、currentで取り出せます  
+    /// 返り値について:  
+    /// プロフィールが無い場合はError::Configになります  
+    /// 注意点:  
+    /// openしているプロフィールはそのまま動き続けます  
+    pub async fn switch(&self, name: &str) -> Result<(), Error> {
+        trace!("ProfileManager::switch() is called");
+        defer!(trace!("returning from ProfileManager::switch()"));
+
+        self.existing_profile_dir(name).await?;
+        fs::create_dir_all(&self.template.data_dir)
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        replace_file(&self.template.data_dir.join(CURRENT_FILE), name.as_bytes()).await?;
+        info!("switched to profile {}", name);
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// 今使っているプロフィールの名前を返します  
+    /// 返り値について:  
+    /// プロフィールを1つも作っていない場合や、使っていたプロフィールが削除された場合はNoneになります  
+    pub async fn current(&self) -> Result<Option<String>, Error> {
+        let name = match fs::read_to_string(self.template.data_dir.join(CURRENT_FILE)).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                error!("{}", e);
+                return Err(Error::Io(e));
+            }
+        };
+        match self.existing_profile_dir(&name).await {
+            Ok(_) => Ok(Some(name)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// 動作の説明:  
+    /// プロフィールのデータを設置する場所を返します  
+    /// SessionBuilderと同じファイル名が使われるため、import_backupで復元する先にも使えます  
+    /// 返り値について:  
+    /// 名前が正しくない場合はError::Configになります  
+    pub fn profile_dir(&self, name: &str) -> Result<PathBuf, Error> {
+        if !is_valid_name(name) {
+            error!("{:?} is not a valid profile name", name);
+            return Err(Error::Config(format!(
+                "{:?} is not a valid profile name",
+                name
+            )));
+        }
+        Ok(self.profiles_dir().join(name))
+    }
+
+    /// 動作の説明:  
+    /// プロフィールを使うセッションを作ります  
+    /// Torは全てのプロフィールで共有され、プロフィールごとに別のHidden Serviceが作られます  
+    /// 引数について:  
+    /// 1: プロフィールの名前を指定します  
+    /// 2: 秘密鍵のファイルのパスフレーズを指定します  
+    /// 返り値について:  
+    /// 既にopenしている場合は、そのセッションを返します  
+    /// プロフィールが無い場合はError::Configになります  
+    pub async fn open(
+        &self,
+        name: &str,
+        passphrase: Option<&str>,
+    ) -> Result<RYOKUCHATSession, Error> {
+        trace!("ProfileManager::open() is called");
+        defer!(trace!("returning from ProfileManager::open()"));
+
+        let dir = self.existing_profile_dir(name).await?;
+        if self.template.transport.is_some() {
+            error!("a transport can not be shared between profiles");
+            return Err(Error::Config(
+                "a transport can not be shared between profiles".to_string(),
+            ));
+        }
+        // 同じプロフィールを同時に2つ作らないように、作り終わるまでロックしておく
+        let busy = self.busy(name).await;
+        let _busy = busy.lock().await;
+        if let Some(session) = self.sessions.lock().await.get(name).and_then(running) {
+            debug!("profile {} is already open", name);
+            return Ok(session);
+        }
+
+        let (address, auth) = self.shared_tor().await?;
+        let newnym = self.newnym(&address, &auth).await;
+        let mut builder = self.template.clone();
+        builder.data_dir = dir;
+        builder.port = 0;
+        builder.system_tor = Some((address, auth));
+        builder.newnym = Some(newnym);
+        builder.passphrase = passphrase.map(|p| Passphrase(p.to_string()));
+        // 通知はプロフィールごとに分ける
+        builder.events = broadcast::channel(builder.event_capacity).0;
+
+        let session = builder.build().await?;
+        self.sessions
+            .lock()
+            .await
+            .insert(name.to_string(), session.downgrade());
+        info!("profile {} is open", name);
+        Ok(session)
+    }
+
+    /// 動作の説明:  
+    /// 共有しているTorの起動の進み具合などの通知を受け取ります  
+    /// 各プロフィールの通知は、それぞれのセッションのsubscribeで受け取ってください  
+    pub fn subscribe(&self) -> impl Stream<Item = Message> + Send + Unpin + 'static {
+        self.template.subscribe()
+    }
+
+    /// 動作の説明:  
+    /// openしている全てのプロフィールのセッションを終了してから、組み込みのTorを終了します  
+    /// 返り値について:  
+    /// 途中で失敗しても最後まで終了させ、最初のエラーを返します  
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        trace!("ProfileManager::shutdown() is called");
+        defer!(trace!("returning from ProfileManager::shutdown()"));
+
+        // openの途中のプロフィールは、作り終わるまで待ってから終了させる
+        let busy: Vec<_> = self.busy.lock().await.values().cloned().collect();
+        let mut guards = Vec::with_capacity(busy.len());
+        for lock in &busy {
+            guards.push(lock.lock().await);
+        }
+
+        let mut result = Ok(());
+        let sessions = std::mem::take(&mut *self.sessions.lock().await);
+        for (name, session) in sessions {
+            if let Some(session) = running(&session) {
+                debug!("closing profile {}", name);
+                result = result.and(session.shutdown().await);
+            }
+        }
+
+        // 次にopenしたときは、その時のTorに接続し直す
+        *self.newnym.lock().await = None;
+
+        #[cfg(feature = "embedded-tor")]
+        let result = result.and(self.stop_tor().await);
+        result
+    }
+
+    // 組み込みのTorを起動していれば終了させる
+    // RYOKUCHATSession::shutdownと同じく、30秒待っても終了しない場合はエラーにする
+    #[cfg(feature = "embedded-tor")]
+    async fn stop_tor(&self) -> Result<(), Error> {
+        // shared_torと同じく、torを先にロックする
+        let mut shared = self.tor.lock().await;
+        let embedded = self.embedded.lock().await.take();
+        let mut tor = match embedded {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        *shared = self.template.system_tor.clone();
+        drop(shared);
+
+        if let Err(e) = tor.control.signal("SHUTDOWN").await {
+            warn!("failed to send SIGNAL SHUTDOWN: {}", e);
+        }
+        let result = match tokio::time::timeout(Duration::from_secs(30), &mut tor.process).await {
+            Ok(Ok(_)) => {
+                info!("the shared Tor is stopped");
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                error!("{}", e);
+                Err(Error::Tor(e.to_string()))
+            }
+            Err(_) => {
+                // すぐに終了させ、それでも終わらない場合は待たずに戻る
+                error!("the shared Tor did not exit in time");
+                if let Err(e) = tor.control.signal("HALT").await {
+                    warn!("failed to send SIGNAL HALT: {}", e);
+                }
+                Err(Error::Tor("Tor did not exit in time".to_string()))
+            }
+        };
+        // 所有権を持っている接続を閉じてもTorは終了する
+        drop(tor.control);
+        result
+    }
+
+    // 各プロフィールがNEWNYMを頼む送り先を返す
+    // 初めて呼ばれたときに、共有しているTorに送るスレッドを起動する
+    async fn newnym(&self, address: &str, auth: &ControlAuth) -> mpsc::Sender<()> {
+        let mut newnym = self.newnym.lock().await;
+        if let Some((sender, _)) = &*newnym {
+            return sender.clone();
+        }
+        let (sender, receiver) = mpsc::channel(1);
+        let handle = HandleWrapper(tokio::spawn(send_newnym(
+            address.to_string(),
+            auth.clone(),
+            receiver,
+        )));
+        *newnym = Some((sender.clone(), handle));
+        sender
+    }
+
+    // プロフィールごとのロックを取り出す
+    async fn busy(&self, name: &str) -> Arc<Mutex<()>> {
+        self.busy
+            .lock()
+            .await
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    // プロフィールを置くディレクトリ
+    fn profiles_dir(&self) -> PathBuf {
+        self.template.data_dir.join("profiles")
+    }
+
+    // プロフィールがあることを確かめてから、その場所を返す
+    async fn existing_profile_dir(&self, name: &str) -> Result<PathBuf, Error> {
+        let dir = self.profile_dir(name)?;
+        match fs::metadata(&dir).await {
+            Ok(o) if o.is_dir() => Ok(dir),
+            _ => {
+                error!("profile {} is not found", name);
+                Err(Error::Config(format!("profile {} is not found", name)))
+            }
+        }
+    }
+
+    // 共有しているTorのControlPortのアドレスと認証方法を返す
+    // system_torが指定されていない場合は、初めて呼ばれたときに組み込みのTorを起動する
+    async fn shared_tor(&self) -> Result<(String, ControlAuth), Error> {
+        let mut tor = self.tor.lock().await;
+        if let Some(s) = &*tor {
+            return Ok(s.clone());
+        }
+
+        let shared = self.start_tor().await?;
+        *tor = Some(shared.clone());
+        Ok(shared)
+    }
+
+    #[cfg(not(feature = "embedded-tor"))]
+    async fn start_tor(&self) -> Result<(String, ControlAuth), Error> {
+        error!("embedded Tor is disabled");
+        Err(Error::Config(
+            "embedded Tor is disabled, use SessionBuilder::system_tor".to_string(),
+        ))
+    }
+
+    // 全てのプロフィールで共有する組み込みのTorを起動する
+    #[cfg(feature = "embedded-tor")]
+    async fn start_tor(&self) -> Result<(String, ControlAuth), Error> {
+        let tor_dir = self.template.data_dir.join(&self.template.tor_dir);
+        let tor_config = tor_dir.join("torrc");
+        fs::create_dir_all(&tor_dir)
+            .await
+            .err_exec(|e| error!("{}", e))?;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&tor_config)
+            .err_exec(|e| error!("{}", e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tor_dir, std::fs::Permissions::from_mode(0o700))
+                .await
+                .err_exec(|e| error!("{}", e))?;
+        }
+
+        info!("starting the shared Tor");
+        let started =
+            RYOKUCHATSession::start_embedded_tor(&self.template, &tor_dir, &tor_config, &[])
+                .await?;
+        let shared = (
+            started.control_address.clone(),
+            ControlAuth::Password(started.control_passwd.clone()),
+        );
+        *self.embedded.lock().await = Some(started);
+        Ok(shared)
+    }
+}
+
+// 各プロフィールから頼まれたときに、共有しているTorにNEWNYMを送る
+// 全てのプロフィールの回線が作り直されるため、何度頼まれてもNEWNYM_INTERVALに1回までにする
+async fn send_newnym(address: String, auth: ControlAuth, mut requests: mpsc::Receiver<()>) {
+    let mut last: Option<Instant> = None;
+    while requests.recv().await.is_some() {
+        if last.is_some_and(|t| t.elapsed() < NEWNYM_INTERVAL) {
+            debug!("NEWNYM was sent recently");
+            continue;
+        }
+        let result = async {
+            let mut control = ControlConnection::connect(&address).await?;
+            control.authenticate(&auth).await?;
+            control.signal_newnym().await
+        }
+        .await;
+        match result {
+            Ok(_) => {
+                info!("asked the shared Tor to build new circuits");
+                last = Some(Instant::now());
+            }
+            Err(e) => warn!("failed to send NEWNYM: {}", e),
+        }
+    }
+}
+
+// プロフィールの名前として使えるか
+// ディレクトリの名前になるため、英数字と-、_だけを使える
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// まだshutdownされていないセッションを取り出す
+fn running(weak: &Weak<SessionInner>) -> Option<RYOKUCHATSession> {
+    RYOKUCHATSession::upgrade(weak)
+        .ok()
+        .filter(|s| !s.inner.closed.load(std::sync::atomic::Ordering::SeqCst))
+}
@@ -0,0 +1,595 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Torを起動せずに、同じプロセスの中で複数のセッションを通信させるためのモジュールです  
+//! test-supportフィーチャーを有効にした場合のみ使えます  
+//! データは一時ディレクトリに置かれ、TestSessionsがdropされると削除されます  
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use ed448_rust::{PrivateKey, PublicKey};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    inside::{
+        functions::rotation_statement,
+        keyfile::{read_key_file, KeyType},
+        structs::{ErrInto, HandleWrapper, MessageForNetwork},
+    },
+    tor_control::quote,
+    transport::{MemoryNetwork, TcpTransport},
+    Error, Message, RYOKUCHATSession, SessionBuilder,
+};
+
+/// テスト用に作られた複数のセッションです  
+/// sessionsには作られた順番にセッションが入っています  
+pub struct TestSessions {
+    pub sessions: Vec<RYOKUCHATSession>,
+    network: Option<MemoryNetwork>,
+    // セッションが使い終わるまで一時ディレクトリを消さないようにする
+    _data_dir: TempDir,
+}
+
+impl TestSessions {
+    /// 動作の説明:  
+    /// MemoryNetworkでつながったセッションをcount個作ります  
+    /// ホスト名はpeer0.test、peer1.test...のようになります  
+    pub async fn memory(count: usize) -> Result<TestSessions, Error> {
+        trace!("TestSessions::memory() is called");
+        defer!(trace!("returning from TestSessions::memory()"));
+
+        let data_dir = TempDir::new()?;
+        let network = MemoryNetwork::new();
+        let mut sessions = Vec::with_capacity(count);
+        for i in 0..count {
+            let transport = network.transport(&format!("peer{}.test", i))?;
+            let session = SessionBuilder::new(data_dir.path().join(format!("peer{}", i)))
+                .transport(transport)
+                .build()
+                .await?;
+            sessions.push(session);
+        }
+
+        Ok(TestSessions {
+            sessions,
+            network: Some(network),
+            _data_dir: data_dir,
+        })
+    }
+
+    /// 動作の説明:  
+    /// 127.0.0.1のTCPでつながったセッションをcount個作ります  
+    /// ホスト名は127.0.0.1:(自動で選ばれたポート)になります  
+    pub async fn tcp(count: usize) -> Result<TestSessions, Error> {
+        trace!("TestSessions::tcp() is called");
+        defer!(trace!("returning from TestSessions::tcp()"));
+
+        let data_dir = TempDir::new()?;
+        let mut sessions = Vec::with_capacity(count);
+        for i in 0..count {
+            let transport =
+                TcpTransport::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+            let session = SessionBuilder::new(data_dir.path().join(format!("peer{}", i)))
+                .transport(transport)
+                .build()
+                .await?;
+            sessions.push(session);
+        }
+
+        Ok(TestSessions {
+            sessions,
+            network: None,
+            _data_dir: data_dir,
+        })
+    }
+
+    /// 動作の説明:  
+    /// 全てのセッションの連絡先リストに、自分以外の全てのセッションを追加します  
+    pub async fn connect_all(&self) -> Result<(), Error> {
+        trace!("TestSessions::connect_all() is called");
+        defer!(trace!("returning from TestSessions::connect_all()"));
+
+        for (i, session) in self.sessions.iter().enumerate() {
+            for (j, other) in self.sessions.iter().enumerate() {
+                if i != j {
+                    session.add_user(&other.myaddress()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// memoryで作った場合に、セッションがつながっているMemoryNetworkを返します  
+    /// 新しくMemoryTransportを作ることで、テストの途中で別のセッションを追加できます  
+    /// tcpで作った場合はNoneが返ります  
+    pub fn network(&self) -> Option<&MemoryNetwork> {
+        self.network.as_ref()
+    }
+
+    /// 動作の説明:  
+    /// 全てのセッションを順番にshutdownします  
+    pub async fn shutdown(self) -> Result<(), Error> {
+        trace!("TestSessions::shutdown() is called");
+        defer!(trace!("returning from TestSessions::shutdown()"));
+
+        let mut result = Ok(());
+        for session in &self.sessions {
+            result = result.and(session.shutdown().await);
+        }
+        result
+    }
+}
+
+/// 動作の説明:  
+/// Hidden Serviceの鍵のファイルから、ADD_ONIONに渡す形式の鍵を取り出します  
+/// 引数について:  
+/// セッションにパスフレーズを指定した場合は、同じパスフレーズを指定します  
+/// 返り値について:  
+/// ファイルが無い場合や壊れている場合はError::KeyFileになります  
+/// パスフレーズが間違っている場合はError::Lockedになります  
+pub async fn read_onion_key(path: &Path, passphrase: Option<&str>) -> Result<String, Error> {
+    let key = read_key_file(path, KeyType::OnionService, passphrase)
+        .await?
+        .err_into(|_| Error::KeyFile(format!("{:?} is not found", path)))?;
+    String::from_utf8(key).err_into(Error::KeyFile)
+}
+
+/// 動作の説明:  
+/// eventsからfがSomeを返す通知が来るまで待ちます  
+/// 返り値について:  
+/// fが返した値が返ります  
+/// 注意点:  
+/// 10秒以内に通知が来ない場合や、eventsが閉じた場合はpanicします  
+pub async fn wait_for<S: Stream<Item = Message> + Unpin, T>(
+    events: &mut S,
+    mut f: impl FnMut(Message) -> Option<T>,
+) -> T {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(event) = events.next().await {
+            if let Some(o) = f(event) {
+                return o;
+            }
+        }
+        panic!("the event stream is closed");
+    })
+    .await
+    .expect("timed out waiting for an event")
+}
+
+/// 動作の説明:  
+/// 任意のホスト名に移ったという知らせをsessionからtoに送ります  
+/// 受け取った側が不正なホスト名を拒否することを確かめるために使います  
+pub async fn send_hostname_migration(
+    session: &RYOKUCHATSession,
+    to: &PublicKey,
+    hostname: &str,
+) -> Result<(), Error> {
+    let data = MessageForNetwork::MigrateHostname(hostname.to_string(), None);
+    let data = bincode::serialize(&data).err_into(Error::Protocol)?;
+    session.send(to, &data).await
+}
+
+/// 動作の説明:  
+/// 新しい鍵の署名を持たない、偽のIDの移行の宣言をsessionからtoに送ります  
+/// 受け取った側が宣言を拒否することを確かめるために使います  
+/// 返り値について:  
+/// 宣言で新しいIDとして主張した公開鍵が返ります  
+pub async fn send_forged_rotation(
+    session: &RYOKUCHATSession,
+    to: &PublicKey,
+) -> Result<PublicKey, Error> {
+    let old_id = PublicKey::from(&*session.myprivkey());
+    let new_id = PublicKey::from(&PrivateKey::new(&mut rand::rngs::OsRng));
+    let statement = rotation_statement(&old_id, &new_id);
+    let old_sign = session
+        .myprivkey()
+        .sign(&statement, None)
+        .err_into(Error::Protocol)?;
+    // 新しい鍵の代わりに、別の鍵で署名する
+    let new_sign = PrivateKey::new(&mut rand::rngs::OsRng)
+        .sign(&statement, None)
+        .err_into(Error::Protocol)?;
+
+    let data = MessageForNetwork::RotateIdentity(
+        new_id.as_byte().to_vec(),
+        old_sign.to_vec(),
+        new_sign.to_vec(),
+    );
+    let data = bincode::serialize(&data).err_into(Error::Protocol)?;
+    session.send(to, &data).await?;
+    Ok(new_id)
+}
+
+/// 既に動いているTorのControlPortの代わりに使うスタンドインです  
+/// SessionBuilder::system_torに渡すことで、Torを使わずにADD_ONIONまでの流れを試すことができます  
+/// 受け取ったコマンドは全て記録され、commandsで取り出せます  
+/// Socksプロキシも用意され、ADD_ONIONで作られたHidden Serviceには(ServiceID).onionで接続できます  
+/// クライアント認証は確かめないため、認証の鍵が無くても接続できます  
+pub struct FakeControlPort {
+    address: SocketAddr,
+    state: Arc<StdMutex<FakeState>>,
+    events: broadcast::Sender<String>,
+    _handles: Vec<HandleWrapper>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    password: Option<String>,
+    socks_address: String,
+    // trueの場合は起動の進捗を50%のまま止める
+    stall_bootstrap: bool,
+    // GETCONFで返す設定
+    conf: HashMap<String, String>,
+    commands: Vec<String>,
+    // ADD_ONIONに渡された鍵とServiceIDの対応
+    onions: HashMap<String, String>,
+    // 接続できるHidden ServiceのServiceIDと転送先
+    targets: HashMap<String, String>,
+    // Socksプロキシが受け付けた接続の数
+    socks_connections: usize,
+}
+
+// スタンドインへの1つの接続の状態
+#[derive(Default)]
+struct FakeConnection {
+    authenticated: bool,
+    // SETEVENTSで指定されたイベントの種類
+    subscribed: Arc<StdMutex<Vec<String>>>,
+    // この接続で作られたHidden ServiceのServiceID
+    onions: Vec<String>,
+}
+
+impl FakeControlPort {
+    /// 動作の説明:  
+    /// 127.0.0.1の空いているポートでスタンドインを起動します  
+    /// 引数について:  
+    /// パスワード認証にする場合はパスワードを、認証なしにする場合はNoneを指定します  
+    pub async fn start(password: Option<&str>) -> Result<FakeControlPort, Error> {
+        trace!("FakeControlPort::start() is called");
+        defer!(trace!("returning from FakeControlPort::start()"));
+
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let address = listener.local_addr()?;
+        let socks = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let state = Arc::new(StdMutex::new(FakeState {
+            password: password.map(|p| p.to_string()),
+            socks_address: socks.local_addr()?.to_string(),
+            ..Default::default()
+        }));
+        let events = broadcast::channel(16).0;
+
+        let state2 = state.clone();
+        let events2 = events.clone();
+        let control_handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(fake_control_connection(
+                    stream,
+                    state2.clone(),
+                    events2.subscribe(),
+                ));
+            }
+        });
+        let state2 = state.clone();
+        let socks_handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = socks.accept().await {
+                tokio::spawn(fake_socks_connection(stream, state2.clone()));
+            }
+        });
+
+        Ok(FakeControlPort {
+            address,
+            state,
+            events,
+            _handles: vec![HandleWrapper(control_handle), HandleWrapper(socks_handle)],
+        })
+    }
+
+    /// 動作の説明:  
+    /// SessionBuilder::system_torに渡すアドレスを返します  
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// 動作の説明:  
+    /// これまでに受け取ったコマンドを、受け取った順番で返します  
+    pub fn commands(&self) -> Vec<String> {
+        lock(&self.state).commands.clone()
+    }
+
+    /// 動作の説明:  
+    /// SETEVENTSでその種類のイベントを受け取るようにしている全ての接続に、非同期イベントを送ります  
+    /// 引数について:  
+    /// "650 "を除いた"HS_DESC UPLOADED ..."のような行を指定します  
+    pub fn emit_event(&self, event: &str) {
+        let _ = self.events.send(event.to_string());
+    }
+
+    /// 動作の説明:  
+    /// GETCONFで返す設定を変更します  
+    /// 設定していない項目は、値が無いものとして返ります  
+    pub fn set_conf(&self, key: &str, value: &str) {
+        lock(&self.state)
+            .conf
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// 動作の説明:  
+    /// これ以降の接続では、起動の進捗を50%のまま止めます  
+    /// ブリッジに接続できない場合などの代わりに使います  
+    /// emit_eventでSTATUS_CLIENTイベントを送ると、その内容で進みます  
+    pub fn stall_bootstrap(&self) {
+        lock(&self.state).stall_bootstrap = true;
+    }
+
+    /// 動作の説明:  
+    /// 作られた全てのHidden Serviceに接続できないようにします  
+    /// Hidden Serviceの記述子が公開できなくなった場合などの代わりに使います  
+    /// ADD_ONIONで作り直されると、再び接続できるようになります  
+    pub fn drop_onions(&self) {
+        lock(&self.state).targets.clear();
+    }
+
+    /// 動作の説明:  
+    /// これまでにSocksプロキシが受け付けた接続の数を返します  
+    pub fn socks_connections(&self) -> usize {
+        lock(&self.state).socks_connections
+    }
+}
+
+fn lock<T>(mutex: &StdMutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(o) => o,
+        Err(e) => e.into_inner(),
+    }
+}
+
+// スタンドインへの1つの接続を処理する
+// 応答とイベントは同じチャンネルを通して、届いた順番に書き込む
+async fn fake_control_connection(
+    stream: TcpStream,
+    state: Arc<StdMutex<FakeState>>,
+    mut events: broadcast::Receiver<String>,
+) {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut read = BufReader::new(read);
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let mut connection = FakeConnection::default();
+
+    let _writer = HandleWrapper(tokio::spawn(async move {
+        while let Some(s) = receiver.recv().await {
+            if write.write_all(s.as_bytes()).await.is_err() || write.flush().await.is_err() {
+                return;
+            }
+        }
+    }));
+    let subscribed = connection.subscribed.clone();
+    let sender2 = sender.clone();
+    let _forwarder = HandleWrapper(tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(o) => o,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let name = event.split(' ').next().unwrap_or_default().to_string();
+            if lock(&subscribed).contains(&name) {
+                let _ = sender2.send(format!("650 {}\r\n", event));
+            }
+        }
+    }));
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match read.read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let command = line.trim_end().to_string();
+        let reply = {
+            let mut state = lock(&state);
+            state.commands.push(command.clone());
+            fake_reply(&mut state, &mut connection, &command)
+        };
+        if sender.send(reply).is_err() {
+            break;
+        }
+    }
+
+    // 接続が閉じられると、その接続で作ったHidden Serviceは消える
+    let mut state = lock(&state);
+    for service_id in connection.onions {
+        state.targets.remove(&service_id);
+    }
+}
+
+// コマンドに対する応答を作る
+fn fake_reply(state: &mut FakeState, connection: &mut FakeConnection, command: &str) -> String {
+    let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        "PROTOCOLINFO" => {
+            let methods = match state.password {
+                Some(_) => "HASHEDPASSWORD",
+                None => "NULL",
+            };
+            format!(
+                "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={}\r\n250-VERSION Tor=\"0.4.8.0\"\r\n250 OK\r\n",
+                methods
+            )
+        }
+        "AUTHENTICATE" => {
+            let ok = match &state.password {
+                Some(p) => args == quote(p),
+                None => true,
+            };
+            connection.authenticated = ok;
+            match ok {
+                true => "250 OK\r\n".to_string(),
+                false => "515 Authentication failed: Password did not match\r\n".to_string(),
+            }
+        }
+        _ if !connection.authenticated => "514 Authentication required.\r\n".to_string(),
+        "GETINFO" => match args {
+            "net/listeners/socks" => format!(
+                "250-net/listeners/socks=\"{}\"\r\n250 OK\r\n",
+                state.socks_address
+            ),
+            // 起動途中の状態を返し、その後に完了のイベントを送る
+            "status/bootstrap-phase" => {
+                let mut reply = concat!(
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"\r\n",
+                    "250 OK\r\n",
+                )
+                .to_string();
+                if !state.stall_bootstrap {
+                    reply.push_str(
+                        "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n",
+                    );
+                }
+                reply
+            }
+            _ => format!("552 Unrecognized key \"{}\"\r\n", args),
+        },
+        "SETEVENTS" => {
+            *lock(&connection.subscribed) = args
+                .split(' ')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect();
+            "250 OK\r\n".to_string()
+        }
+        "ADD_ONION" => {
+            let key = args.split(' ').next().unwrap_or_default();
+            let target = args
+                .split(' ')
+                .find_map(|a| a.strip_prefix("Port="))
+                .and_then(|p| p.split_once(','))
+                .map(|(_, t)| t.to_string())
+                .unwrap_or_default();
+            let len = state.onions.len();
+            let (service_id, private_key) = if key.starts_with("NEW:") {
+                let new_key = format!("ED25519-V3:fakekey{}", len);
+                state.onions.insert(new_key.clone(), fake_service_id(len));
+                (fake_service_id(len), Some(new_key))
+            } else {
+                let service_id = state
+                    .onions
+                    .entry(key.to_string())
+                    .or_insert_with(|| fake_service_id(len))
+                    .clone();
+                (service_id, None)
+            };
+            state.targets.insert(service_id.clone(), target);
+            connection.onions.push(service_id.clone());
+            match private_key {
+                Some(private_key) => format!(
+                    "250-ServiceID={}\r\n250-PrivateKey={}\r\n250 OK\r\n",
+                    service_id, private_key
+                ),
+                None => format!("250-ServiceID={}\r\n250 OK\r\n", service_id),
+            }
+        }
+        "DEL_ONION" => {
+            state.targets.remove(args);
+            connection.onions.retain(|s| s != args);
+            "250 OK\r\n".to_string()
+        }
+        "GETCONF" => match state.conf.get(args) {
+            Some(value) => format!("250 {}={}\r\n", args, value),
+            None => format!("250 {}\r\n", args),
+        },
+        "SIGNAL" | "TAKEOWNERSHIP" | "ONION_CLIENT_AUTH_ADD" | "ONION_CLIENT_AUTH_REMOVE" => {
+            "250 OK\r\n".to_string()
+        }
+        _ => format!("510 Unrecognized command \"{}\"\r\n", name),
+    }
+}
+
+// 56文字のv3 onionアドレスのようなServiceIDを作る
+fn fake_service_id(n: usize) -> String {
+    // 公開鍵の代わりに番号を入れ、最後にバージョンの3を付ける
+    let mut id = [0; 35];
+    id[..8].copy_from_slice(&(n as u64).to_be_bytes());
+    id[34] = 3;
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &id).to_ascii_lowercase()
+}
+
+// スタンドインのSocksプロキシへの1つの接続を処理する
+// (ServiceID).onionへの接続を、ADD_ONIONで指定された転送先につなぐ
+async fn fake_socks_connection(mut stream: TcpStream, state: Arc<StdMutex<FakeState>>) {
+    lock(&state).socks_connections += 1;
+    let _ = async {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        let mut methods = vec![0; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if methods.contains(&2) {
+            // ユーザー名とパスワードは読み捨てる
+            stream.write_all(&[5, 2]).await?;
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await?;
+            stream.read_exact(&mut vec![0; len[1] as usize]).await?;
+            stream.read_exact(&mut len[..1]).await?;
+            stream.read_exact(&mut vec![0; len[0] as usize]).await?;
+            stream.write_all(&[1, 0]).await?;
+        } else {
+            stream.write_all(&[5, 0]).await?;
+        }
+
+        let mut request = [0; 5];
+        stream.read_exact(&mut request).await?;
+        let mut host = vec![0; request[4] as usize];
+        stream.read_exact(&mut host).await?;
+        stream.read_exact(&mut [0; 2]).await?;
+        let host = String::from_utf8_lossy(&host).to_string();
+        let target = host
+            .strip_suffix(".onion")
+            .and_then(|s| lock(&state).targets.get(s).cloned());
+        let mut target = match target {
+            Some(t) => TcpStream::connect(t).await.ok(),
+            None => None,
+        };
+        match &mut target {
+            Some(target) => {
+                stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut stream, target).await?;
+            }
+            // Hidden Serviceに接続できない場合はHost unreachableを返す
+            None => stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).await?,
+        }
+        Ok::<(), std::io::Error>(())
+    }
+    .await;
+}
@@ -0,0 +1,780 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::Error;
+
+// libteaが自分で設定するため、customで指定できないオプション
+const RESERVED_OPTIONS: &[&str] = &[
+    "DataDirectory",
+    "SocksPort",
+    "ControlPort",
+    "ControlSocket",
+    "HashedControlPassword",
+    "CookieAuthentication",
+    "__OwningControllerProcess",
+    "HiddenServiceDir",
+    "HiddenServicePort",
+    "RunAsDaemon",
+];
+
+/// 組み込みのTorが使うパディングの量です  
+/// パディングは通信の量やタイミングから何をしているかを推測されにくくしますが、その分通信量が増えます  
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// 接続と回線の両方で最大限のパディングを使います
+    #[default]
+    Full,
+    /// パディングを減らします  
+    /// 従量制のモバイル回線などで通信量を抑えたい場合に使います  
+    Reduced,
+    /// パディングを使いません
+    Disabled,
+}
+
+/// Torのブリッジです  
+/// 検閲されているネットワークで、公開されていないリレーを経由してTorにつなぐために使います  
+/// bridges.torproject.orgなどで配られている行をparseで読み取って作ります  
+/// 例: obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=... iat-mode=0  
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bridge {
+    transport: Option<String>,
+    address: SocketAddr,
+    fingerprint: Option<String>,
+    args: Vec<(String, String)>,
+}
+
+impl Bridge {
+    /// 動作の説明:  
+    /// obfs4やsnowflakeなど、使うPluggable Transportの名前を返します  
+    /// 普通のブリッジの場合はNoneになります  
+    pub fn transport(&self) -> Option<&str> {
+        self.transport.as_deref()
+    }
+
+    /// 動作の説明:  
+    /// ブリッジのアドレスを返します  
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// 動作の説明:  
+    /// ブリッジのフィンガープリントを大文字の16進数で返します  
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+}
+
+impl FromStr for Bridge {
+    type Err = Error;
+
+    /// 動作の説明:  
+    /// ブリッジの行を読み取ります  
+    /// torrcのように先頭にBridgeが付いていても読み取れます  
+    /// 返り値について:  
+    /// 形式が正しくない場合はError::Configが返ります  
+    fn from_str(line: &str) -> Result<Bridge, Error> {
+        trace!("Bridge::from_str() is called");
+        defer!(trace!("returning from Bridge::from_str()"));
+
+        let invalid = |reason: &str| {
+            error!("invalid bridge line {:?}: {}", line, reason);
+            Error::Config(format!("invalid bridge line: {}", reason))
+        };
+
+        let mut words = line.split_whitespace().peekable();
+        if words.peek() == Some(&"Bridge") {
+            words.next();
+        }
+
+        // 先頭がアドレスでなければPluggable Transportの名前
+        let first = words.next().ok_or_else(|| invalid("the line is empty"))?;
+        let (transport, address) = match first.parse::<SocketAddr>() {
+            Ok(o) => (None, o),
+            Err(_) => {
+                if !is_transport_name(first) {
+                    return Err(invalid("the transport name is invalid"));
+                }
+                let address = words
+                    .next()
+                    .ok_or_else(|| invalid("the address is missing"))?
+                    .parse::<SocketAddr>()
+                    .map_err(|_| invalid("the address must be (IP address):(port)"))?;
+                (Some(first.to_string()), address)
+            }
+        };
+
+        let mut fingerprint = None;
+        let mut args = Vec::new();
+        for (i, word) in words.enumerate() {
+            match word.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    args.push((key.to_string(), value.to_string()))
+                }
+                None if i == 0 && is_fingerprint(word) => {
+                    fingerprint = Some(word.trim_start_matches('$').to_ascii_uppercase())
+                }
+                _ => return Err(invalid(&format!("unexpected {:?}", word))),
+            }
+        }
+        if !args.is_empty() && transport.is_none() {
+            return Err(invalid("arguments need a pluggable transport"));
+        }
+
+        Ok(Bridge {
+            transport,
+            address,
+            fingerprint,
+            args,
+        })
+    }
+}
+
+impl fmt::Display for Bridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(transport) = &self.transport {
+            write!(f, "{} ", transport)?;
+        }
+        write!(f, "{}", self.address)?;
+        if let Some(fingerprint) = &self.fingerprint {
+            write!(f, " {}", fingerprint)?;
+        }
+        for (key, value) in &self.args {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Torがリレーやブリッジに接続するときに経由するプロキシです  
+/// アドレスは(ホスト名かIPアドレス):(ポート)の形式で指定します  
+/// ログに出ないように、Debugでは認証情報を表示しません  
+#[derive(Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// HTTPのCONNECTメソッドを使うプロキシです(HTTPSProxy)  
+    /// 認証が必要な場合は、ユーザー名とパスワードを指定します  
+    Https {
+        address: String,
+        auth: Option<(String, String)>,
+    },
+    /// SOCKS4のプロキシです(Socks4Proxy)
+    Socks4 { address: String },
+    /// SOCKS5のプロキシです(Socks5Proxy)  
+    /// 認証が必要な場合は、ユーザー名とパスワードを指定します  
+    Socks5 {
+        address: String,
+        auth: Option<(String, String)>,
+    },
+}
+
+impl std::fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let auth = |auth: &Option<(String, String)>| auth.as_ref().map(|_| "..");
+        match self {
+            Proxy::Https { address, auth: a } => f
+                .debug_struct("Https")
+                .field("address", address)
+                .field("auth", &auth(a))
+                .finish(),
+            Proxy::Socks4 { address } => {
+                f.debug_struct("Socks4").field("address", address).finish()
+            }
+            Proxy::Socks5 { address, auth: a } => f
+                .debug_struct("Socks5")
+                .field("address", address)
+                .field("auth", &auth(a))
+                .finish(),
+        }
+    }
+}
+
+// ClientTransportPluginで起動するPluggable Transportのプログラム
+#[derive(Clone, Debug)]
+struct TransportPlugin {
+    transports: Vec<String>,
+    path: PathBuf,
+    args: Vec<String>,
+}
+
+/// 組み込みのTorに渡す設定です  
+/// SessionBuilder::tor_configで指定します  
+/// 初期値はExcludeNodes SlowServer、StrictNodes 1、Padding::Fullです  
+/// 注意点:  
+/// 既に動いているTorを使う場合は使われないため、そのTorのtorrcで設定してください  
+/// to_torrcでtorrcに書く形式に変換できます  
+#[derive(Clone, Debug)]
+pub struct TorConfig {
+    exclude_nodes: Vec<String>,
+    entry_nodes: Vec<String>,
+    exit_nodes: Vec<String>,
+    strict_nodes: bool,
+    padding: Padding,
+    bridges: Vec<Bridge>,
+    transport_plugins: Vec<TransportPlugin>,
+    proxy: Option<Proxy>,
+    reachable_addresses: Vec<String>,
+    custom: Vec<(String, String)>,
+}
+
+impl Default for TorConfig {
+    fn default() -> TorConfig {
+        TorConfig {
+            exclude_nodes: vec!["SlowServer".to_string()],
+            entry_nodes: Vec::new(),
+            exit_nodes: Vec::new(),
+            strict_nodes: true,
+            padding: Padding::default(),
+            bridges: Vec::new(),
+            transport_plugins: Vec::new(),
+            proxy: None,
+            reachable_addresses: Vec::new(),
+            custom: Vec::new(),
+        }
+    }
+}
+
+impl TorConfig {
+    /// 動作の説明:  
+    /// 初期値のTorConfigを作ります  
+    pub fn new() -> TorConfig {
+        TorConfig::default()
+    }
+
+    /// 動作の説明:  
+    /// 使わないリレーを指定します(ExcludeNodes)  
+    /// 引数について:  
+    /// フィンガープリント($から始まる40文字の16進数)、ニックネーム、{国コード}、IPアドレスかその範囲を指定できます  
+    /// 空にした場合は除外しません  
+    pub fn exclude_nodes<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        nodes: I,
+    ) -> TorConfig {
+        self.exclude_nodes = nodes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 動作の説明:  
+    /// 最初に経由するリレーを指定します(EntryNodes)  
+    /// 引数について:  
+    /// exclude_nodesと同じ形式で指定します  
+    pub fn entry_nodes<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        nodes: I,
+    ) -> TorConfig {
+        self.entry_nodes = nodes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 動作の説明:  
+    /// 出口にするリレーを指定します(ExitNodes)  
+    /// 引数について:  
+    /// exclude_nodesと同じ形式で指定します  
+    /// 注意点:  
+    /// Hidden Service同士の通信には出口のリレーが使われないため、libteaの通信には影響しません  
+    pub fn exit_nodes<I: IntoIterator<Item = S>, S: Into<String>>(mut self, nodes: I) -> TorConfig {
+        self.exit_nodes = nodes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 動作の説明:  
+    /// exclude_nodesなどの指定を必ず守らせるかどうかを指定します(StrictNodes)  
+    /// falseの場合、接続できないときにTorが指定を無視することがあります  
+    pub fn strict_nodes(mut self, strict: bool) -> TorConfig {
+        self.strict_nodes = strict;
+        self
+    }
+
+    /// 動作の説明:  
+    /// パディングの量を指定します  
+    pub fn padding(mut self, padding: Padding) -> TorConfig {
+        self.padding = padding;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 使うブリッジを指定します(UseBridges、Bridge)  
+    /// 空でない場合、Torは指定したブリッジだけを経由してつながります  
+    /// 引数について:  
+    /// Bridgeは行をparseして作ってください  
+    /// 注意点:  
+    /// obfs4などを使うブリッジには、transport_pluginでそのPluggable Transportのプログラムも指定してください  
+    /// entry_nodesと同時には使えません  
+    pub fn bridges<I: IntoIterator<Item = Bridge>>(mut self, bridges: I) -> TorConfig {
+        self.bridges = bridges.into_iter().collect();
+        self
+    }
+
+    /// 動作の説明:  
+    /// Pluggable Transportのプログラムを指定します(ClientTransportPlugin)  
+    /// 複数回呼ぶことで、複数のプログラムを指定できます  
+    /// 引数について:  
+    /// 1: このプログラムが提供するPluggable Transportの名前を指定します(例: obfs4、snowflake)  
+    /// 2: プログラムの場所を絶対パスで指定します  
+    /// 3: プログラムに渡す引数を指定します  
+    /// 注意点:  
+    /// プログラムの場所と引数には空白を含められません  
+    pub fn transport_plugin<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        transports: I,
+        path: impl Into<PathBuf>,
+        args: &[&str],
+    ) -> TorConfig {
+        self.transport_plugins.push(TransportPlugin {
+            transports: transports.into_iter().map(Into::into).collect(),
+            path: path.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        });
+        self
+    }
+
+    /// 動作の説明:  
+    /// Torがリレーやブリッジに接続するときに経由するプロキシを指定します  
+    /// HTTPやSOCKSのプロキシでしか外に出られないネットワークで使います  
+    /// 注意点:  
+    /// プロキシに接続を拒否された場合は、起動中にMessage::ProxyErrorが届きます  
+    pub fn proxy(mut self, proxy: Proxy) -> TorConfig {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// 動作の説明:  
+    /// ファイアウォールが接続を許しているアドレスとポートを指定します(ReachableAddresses)  
+    /// Torはこれに合うリレーやブリッジにだけ接続します  
+    /// 引数について:  
+    /// "*:443"や"192.0.2.0/24:80-8080"のように、(アドレス)[/(マスク)]:(ポート)の形式で指定します  
+    /// 先頭にrejectを付けると、そのアドレスに接続しないようにできます  
+    pub fn reachable_addresses<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        addresses: I,
+    ) -> TorConfig {
+        self.reachable_addresses = addresses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 動作の説明:  
+    /// TorConfigに無いオプションをそのままTorに渡します  
+    /// 引数について:  
+    /// 1: オプションの名前を指定します  
+    /// 2: オプションの値を指定します  
+    /// 注意点:  
+    /// DataDirectoryやSocksPortなど、libteaが設定するオプションは指定できません  
+    /// 値の内容は確かめないため、間違っているとTorが起動しません  
+    pub fn custom(mut self, key: impl Into<String>, value: impl Into<String>) -> TorConfig {
+        self.custom.push((key.into(), value.into()));
+        self
+    }
+
+    /// 動作の説明:  
+    /// 設定が正しいかを確かめます  
+    /// 返り値について:  
+    /// 正しくない場合はError::Configが返ります  
+    pub fn validate(&self) -> Result<(), Error> {
+        trace!("TorConfig::validate() is called");
+        defer!(trace!("returning from TorConfig::validate()"));
+
+        for (option, nodes) in [
+            ("ExcludeNodes", &self.exclude_nodes),
+            ("EntryNodes", &self.entry_nodes),
+            ("ExitNodes", &self.exit_nodes),
+        ] {
+            if let Some(node) = nodes.iter().find(|n| !is_node(n)) {
+                error!("{:?} is not a valid node for {}", node, option);
+                return Err(Error::Config(format!(
+                    "{:?} is not a valid node for {}",
+                    node, option
+                )));
+            }
+        }
+
+        if let Some(proxy) = &self.proxy {
+            let (address, auth) = match proxy {
+                Proxy::Https { address, auth } | Proxy::Socks5 { address, auth } => {
+                    (address, auth.as_ref())
+                }
+                Proxy::Socks4 { address } => (address, None),
+            };
+            if !is_host_port(address) {
+                error!("{:?} is not a valid proxy address", address);
+                return Err(Error::Config(format!(
+                    "{:?} is not a valid proxy address",
+                    address
+                )));
+            }
+            // HTTPSProxyAuthenticatorでは:で区切り、SOCKS5では255バイトまでになる
+            if let Some((username, password)) = auth {
+                let valid =
+                    |s: &str| (1..=255).contains(&s.len()) && !s.contains(['\r', '\n', '\0']);
+                if !valid(username)
+                    || !valid(password)
+                    || (matches!(proxy, Proxy::Https { .. }) && username.contains(':'))
+                {
+                    error!("the username or password of the proxy is invalid");
+                    return Err(Error::Config(
+                        "the username or password of the proxy is invalid".to_string(),
+                    ));
+                }
+            }
+        }
+        if let Some(address) = self
+            .reachable_addresses
+            .iter()
+            .find(|a| !is_reachable_address(a))
+        {
+            error!("{:?} is not a valid reachable address", address);
+            return Err(Error::Config(format!(
+                "{:?} is not a valid reachable address",
+                address
+            )));
+        }
+
+        // ブリッジを使う場合、最初に経由するリレーはブリッジになる
+        if !self.bridges.is_empty() && !self.entry_nodes.is_empty() {
+            error!("bridges and EntryNodes cannot be used together");
+            return Err(Error::Config(
+                "bridges and EntryNodes cannot be used together".to_string(),
+            ));
+        }
+        for plugin in &self.transport_plugins {
+            if plugin.transports.is_empty()
+                || !plugin.transports.iter().all(|t| is_transport_name(t))
+            {
+                error!("invalid transport names {:?}", &plugin.transports);
+                return Err(Error::Config(format!(
+                    "invalid transport names {:?}",
+                    &plugin.transports
+                )));
+            }
+            let path = plugin.path.to_str().unwrap_or_default();
+            if !plugin.path.is_absolute() || path.is_empty() || path.contains(char::is_whitespace) {
+                error!("{:?} is not a valid plugin path", &plugin.path);
+                return Err(Error::Config(format!(
+                    "{:?} must be an absolute path without spaces",
+                    &plugin.path
+                )));
+            }
+            if plugin
+                .args
+                .iter()
+                .any(|a| a.is_empty() || a.contains(char::is_whitespace))
+            {
+                error!("arguments of {:?} contain spaces", &plugin.path);
+                return Err(Error::Config(format!(
+                    "arguments of {:?} must not contain spaces",
+                    &plugin.path
+                )));
+            }
+        }
+        // ブリッジが使うPluggable Transportを提供するプログラムが必要
+        for transport in self.bridges.iter().filter_map(|b| b.transport()) {
+            if !self
+                .transport_plugins
+                .iter()
+                .any(|p| p.transports.iter().any(|t| t == transport))
+            {
+                error!("no plugin provides {}", transport);
+                return Err(Error::Config(format!(
+                    "no transport plugin provides {}",
+                    transport
+                )));
+            }
+        }
+
+        for (key, value) in &self.custom {
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                error!("{:?} is not a valid Tor option", key);
+                return Err(Error::Config(format!(
+                    "{:?} is not a valid Tor option",
+                    key
+                )));
+            }
+            if RESERVED_OPTIONS.iter().any(|r| r.eq_ignore_ascii_case(key)) {
+                error!("{} is set by libtea", key);
+                return Err(Error::Config(format!("{} is set by libtea", key)));
+            }
+            if value.contains(['\r', '\n', '\0']) {
+                error!("the value of {} contains a line break", key);
+                return Err(Error::Config(format!(
+                    "the value of {} contains a line break",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// torrcに書く形式に変換します  
+    /// 返り値について:  
+    /// 1行に1つのオプションが入った文字列が返ります  
+    /// 設定が正しくない場合はError::Configが返ります  
+    pub fn to_torrc(&self) -> Result<String, Error> {
+        trace!("TorConfig::to_torrc() is called");
+        defer!(trace!("returning from TorConfig::to_torrc()"));
+
+        Ok(self
+            .options()?
+            .into_iter()
+            .map(|(key, value)| format!("{} {}\n", key, value))
+            .collect())
+    }
+
+    // プロキシを経由してリレーに接続するかどうか
+    #[cfg(feature = "embedded-tor")]
+    pub(crate) fn uses_proxy(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    // 確かめてから、Torに渡すオプションと値の組に変換する
+    pub(crate) fn options(&self) -> Result<Vec<(String, String)>, Error> {
+        self.validate()?;
+
+        let mut options = Vec::new();
+        for (option, nodes) in [
+            ("ExcludeNodes", &self.exclude_nodes),
+            ("EntryNodes", &self.entry_nodes),
+            ("ExitNodes", &self.exit_nodes),
+        ] {
+            if !nodes.is_empty() {
+                options.push((option.to_string(), nodes.join(",")));
+            }
+        }
+        options.push((
+            "StrictNodes".to_string(),
+            (self.strict_nodes as u8).to_string(),
+        ));
+
+        let padding: &[(&str, &str)] = match self.padding {
+            Padding::Full => &[
+                ("ConnectionPadding", "1"),
+                ("ReducedConnectionPadding", "0"),
+                ("CircuitPadding", "1"),
+                ("ReducedCircuitPadding", "0"),
+            ],
+            Padding::Reduced => &[
+                ("ConnectionPadding", "auto"),
+                ("ReducedConnectionPadding", "1"),
+                ("CircuitPadding", "1"),
+                ("ReducedCircuitPadding", "1"),
+            ],
+            Padding::Disabled => &[("ConnectionPadding", "0"), ("CircuitPadding", "0")],
+        };
+        options.extend(
+            padding
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+
+        match &self.proxy {
+            Some(Proxy::Https { address, auth }) => {
+                options.push(("HTTPSProxy".to_string(), address.clone()));
+                if let Some((username, password)) = auth {
+                    options.push((
+                        "HTTPSProxyAuthenticator".to_string(),
+                        format!("{}:{}", username, password),
+                    ));
+                }
+            }
+            Some(Proxy::Socks4 { address }) => {
+                options.push(("Socks4Proxy".to_string(), address.clone()));
+            }
+            Some(Proxy::Socks5 { address, auth }) => {
+                options.push(("Socks5Proxy".to_string(), address.clone()));
+                if let Some((username, password)) = auth {
+                    options.push(("Socks5ProxyUsername".to_string(), username.clone()));
+                    options.push(("Socks5ProxyPassword".to_string(), password.clone()));
+                }
+            }
+            None => {}
+        }
+        if !self.reachable_addresses.is_empty() {
+            options.push((
+                "ReachableAddresses".to_string(),
+                self.reachable_addresses.join(","),
+            ));
+        }
+
+        if !self.bridges.is_empty() {
+            options.push(("UseBridges".to_string(), "1".to_string()));
+        }
+        for bridge in &self.bridges {
+            options.push(("Bridge".to_string(), bridge.to_string()));
+        }
+        for plugin in &self.transport_plugins {
+            let mut command = format!(
+                "{} exec {}",
+                plugin.transports.join(","),
+                plugin.path.to_str().unwrap_or_default()
+            );
+            for arg in &plugin.args {
+                command.push(' ');
+                command.push_str(arg);
+            }
+            options.push(("ClientTransportPlugin".to_string(), command));
+        }
+
+        options.extend(self.custom.iter().cloned());
+        // プロキシの認証情報はログに出さない
+        let logged: Vec<_> = options
+            .iter()
+            .map(|(key, value)| match key.as_str() {
+                "HTTPSProxyAuthenticator" | "Socks5ProxyPassword" => (key.as_str(), ".."),
+                _ => (key.as_str(), value.as_str()),
+            })
+            .collect();
+        debug!("options of Tor are {:?}", &logged);
+        Ok(options)
+    }
+}
+
+// ExcludeNodesなどに指定できる形式か確かめる
+fn is_node(node: &str) -> bool {
+    // {国コード}
+    if let Some(country) = node.strip_prefix('{').and_then(|n| n.strip_suffix('}')) {
+        return country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic() || c == '?');
+    }
+    // フィンガープリント、後ろに~か=でニックネームを付けることもできる
+    let (fingerprint, nickname) = match node.find(['~', '=']) {
+        Some(i) => (&node[..i], Some(&node[i + 1..])),
+        None => (node, None),
+    };
+    let hex = fingerprint.strip_prefix('$').unwrap_or(fingerprint);
+    if hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return nickname.is_none_or(is_nickname);
+    }
+    if hex != fingerprint || nickname.is_some() {
+        return false;
+    }
+    // IPアドレスかその範囲
+    let (address, prefix) = match node.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (node, None),
+    };
+    if let Ok(address) = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        return prefix.is_none_or(|p| p.parse::<u8>().is_ok_and(|p| p <= max));
+    }
+    is_nickname(node)
+}
+
+// $を付けても付けなくてもよい、40文字の16進数
+fn is_fingerprint(fingerprint: &str) -> bool {
+    let fingerprint = fingerprint.strip_prefix('$').unwrap_or(fingerprint);
+    fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// (ホスト名かIPアドレス):(ポート)の形式か確かめる
+// IPv6アドレスは[]で囲む
+fn is_host_port(address: &str) -> bool {
+    let (host, port) = match address.rsplit_once(':') {
+        Some(o) => o,
+        None => return false,
+    };
+    let host_ok = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(v6) => v6.parse::<std::net::Ipv6Addr>().is_ok(),
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        }
+    };
+    host_ok && port.parse::<u16>().is_ok_and(|p| p != 0)
+}
+
+// ReachableAddressesに指定できる形式か確かめる
+// 例: *:443、accept 192.0.2.0/24:80-8080、reject [2001:db8::]/32:*
+fn is_reachable_address(entry: &str) -> bool {
+    let entry = entry
+        .strip_prefix("accept ")
+        .or_else(|| entry.strip_prefix("reject "))
+        .unwrap_or(entry)
+        .trim();
+
+    // IPv6アドレスは:を含むので、[]の後ろでポートと分ける
+    let (address, port) = match entry.strip_prefix('[') {
+        Some(v6) => {
+            let (v6, rest) = match v6.split_once(']') {
+                Some(o) => o,
+                None => return false,
+            };
+            let (prefix, port) = match rest.split_once(':') {
+                Some((prefix, port)) => (prefix, Some(port)),
+                None => (rest, None),
+            };
+            let prefix_ok = match prefix.strip_prefix('/') {
+                Some(p) => p.parse::<u8>().is_ok_and(|p| p <= 128),
+                None => prefix.is_empty(),
+            };
+            if !prefix_ok || v6.parse::<std::net::Ipv6Addr>().is_err() {
+                return false;
+            }
+            ("*", port)
+        }
+        None => match entry.split_once(':') {
+            Some((address, port)) => (address, Some(port)),
+            None => (entry, None),
+        },
+    };
+
+    let address_ok = match address {
+        "*" | "*4" | "*6" => true,
+        _ => {
+            let (address, prefix) = match address.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (address, None),
+            };
+            address.parse::<std::net::Ipv4Addr>().is_ok()
+                && prefix.is_none_or(|p| p.parse::<u8>().is_ok_and(|p| p <= 32))
+        }
+    };
+    let port_ok = |p: &str| p.parse::<u16>().is_ok_and(|p| p != 0);
+    let ports_ok = match port {
+        None | Some("*") => true,
+        Some(port) => match port.split_once('-') {
+            Some((low, high)) => {
+                port_ok(low) && port_ok(high) && low.parse::<u16>().ok() <= high.parse().ok()
+            }
+            None => port_ok(port),
+        },
+    };
+    address_ok && ports_ok
+}
+
+// Pluggable Transportの名前は英字から始まる英数字と_
+fn is_transport_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// リレーのニックネームは1文字から19文字の英数字
+fn is_nickname(nickname: &str) -> bool {
+    (1..=19).contains(&nickname.len()) && nickname.chars().all(|c| c.is_ascii_alphanumeric())
+}
@@ -0,0 +1,553 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! TorのControlPortと話すための非同期クライアントです  
+//! 応答は複数行のものやデータ付きのものも含めて読み取られ、2xx以外の応答はError::Torになります  
+//! コマンドの応答を待っている間に届いた非同期イベント(650)は溜めておかれ、next_eventで取り出せます  
+//! 接続はAsyncReadとAsyncWriteを実装していれば何でもよいため、テストではtokio::io::duplexなども使えます  
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+use crate::{inside::structs::ErrInto, ControlAuth, Error};
+
+/// ControlPortからの1つの応答です  
+/// linesには各行の4文字目以降が入り、データ付きの行はデータが改行でつながれて入ります  
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+/// ControlPortから届いた非同期イベントです  
+/// 例えば"650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100"の場合、nameは"STATUS_CLIENT"、bodyは"NOTICE BOOTSTRAP PROGRESS=100"になります  
+/// 複数行のイベントの場合、2行目以降はdataに入ります  
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub name: String,
+    pub body: String,
+    pub data: Vec<String>,
+}
+
+impl Event {
+    fn from_lines(mut lines: Vec<String>) -> Event {
+        let first = if lines.is_empty() {
+            String::new()
+        } else {
+            lines.remove(0)
+        };
+        let (name, body) = first.split_once(' ').unwrap_or((&first, ""));
+        Event {
+            name: name.to_string(),
+            body: body.to_string(),
+            data: lines,
+        }
+    }
+}
+
+/// ADD_ONIONで作ったHidden Serviceです  
+/// private_keyには、鍵を新しく作ってもらった場合にその鍵が"ED25519-V3:(鍵)"の形式で入ります  
+#[derive(Clone, Debug)]
+pub struct AddedOnion {
+    pub service_id: String,
+    pub private_key: Option<String>,
+}
+
+/// TorのControlPortとの接続です  
+/// 接続が閉じられると、その接続で作ったHidden Serviceなどは消されます  
+pub struct ControlConnection<T> {
+    stream: BufStream<T>,
+    events: VecDeque<Event>,
+}
+
+impl ControlConnection<TcpStream> {
+    /// 動作の説明:  
+    /// (アドレス):(ポート)の形式で指定したControlPortにTCPで接続します  
+    /// 注意点:  
+    /// Torの起動を待つ間は何度も失敗するため、失敗してもログには出しません  
+    pub async fn connect(address: &str) -> Result<ControlConnection<TcpStream>, Error> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| Error::Tor(e.to_string()))?;
+        Ok(ControlConnection::new(stream))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ControlConnection<T> {
+    /// 動作の説明:  
+    /// 既に接続したストリームからControlConnectionを作ります  
+    pub fn new(stream: T) -> ControlConnection<T> {
+        ControlConnection {
+            stream: BufStream::new(stream),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// 動作の説明:  
+    /// コマンドを1行送り、応答を受け取ります  
+    /// 型付きのメソッドが無いコマンドを送る場合に使います  
+    /// 返り値について:  
+    /// 2xx以外の応答はError::Torになります  
+    /// 改行を含むコマンドは送らずにError::Torになります  
+    pub async fn command(&mut self, command: &str) -> Result<Reply, Error> {
+        trace!("ControlConnection::command() is called");
+        defer!(trace!("returning from ControlConnection::command()"));
+
+        // 改行が入っていると、続けて別のコマンドを送れてしまう
+        if command.contains(['\r', '\n']) {
+            let name = command.split(' ').next().unwrap_or_default();
+            error!("{} contains a line break", name);
+            return Err(Error::Tor(format!("{} contains a line break", name)));
+        }
+
+        self.stream
+            .write_all(command.as_bytes())
+            .await
+            .err_into(Error::Tor)?;
+        self.stream.write_all(b"\r\n").await.err_into(Error::Tor)?;
+        self.stream.flush().await.err_into(Error::Tor)?;
+
+        loop {
+            let reply = self.read_reply().await?;
+            if reply.code == 650 {
+                self.events.push_back(Event::from_lines(reply.lines));
+                continue;
+            }
+            if !(200..300).contains(&reply.code) {
+                // パスワードなどがログに残らないように、コマンドの最初の単語だけを出す
+                let name = command.split(' ').next().unwrap_or_default();
+                error!("{} failed: {} {:?}", name, reply.code, reply.lines);
+                return Err(Error::Tor(format!(
+                    "{} failed: {} {}",
+                    name,
+                    reply.code,
+                    reply.lines.join(" ")
+                )));
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// 動作の説明:  
+    /// ControlAuthで指定した方法で認証します  
+    /// ControlAuth::Cookieでファイルの場所が指定されていない場合は、PROTOCOLINFOで調べます  
+    pub async fn authenticate(&mut self, auth: &ControlAuth) -> Result<(), Error> {
+        trace!("ControlConnection::authenticate() is called");
+        defer!(trace!("returning from ControlConnection::authenticate()"));
+
+        match auth {
+            ControlAuth::Null => self.authenticate_null().await,
+            ControlAuth::Password(password) => self.authenticate_password(password).await,
+            ControlAuth::Cookie(path) => {
+                let path = match path {
+                    Some(s) => s.clone(),
+                    None => self.cookie_file().await?,
+                };
+                debug!("cookie file is {:?}", &path);
+                let cookie = tokio::fs::read(&path)
+                    .await
+                    .err_into(|e| Error::Tor(format!("could not read {:?}: {}", &path, e)))?;
+                self.authenticate_cookie(&cookie).await
+            }
+        }
+    }
+
+    /// 動作の説明:  
+    /// 認証が設定されていないControlPortに認証します  
+    pub async fn authenticate_null(&mut self) -> Result<(), Error> {
+        self.command("AUTHENTICATE").await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// Cookieファイルの中身で認証します  
+    pub async fn authenticate_cookie(&mut self, cookie: &[u8]) -> Result<(), Error> {
+        let mut hex = String::with_capacity(cookie.len() * 2);
+        for b in cookie {
+            hex.push_str(&format!("{:02X}", b));
+        }
+        self.command(&format!("AUTHENTICATE {}", hex)).await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// PROTOCOLINFOでCookieファイルの場所を調べます  
+    /// 認証する前に呼ぶことができます  
+    pub async fn cookie_file(&mut self) -> Result<PathBuf, Error> {
+        let reply = self.command("PROTOCOLINFO 1").await?;
+        reply
+            .lines
+            .iter()
+            .filter_map(|l| l.strip_prefix("AUTH "))
+            .find_map(|l| parse_keywords(l).remove("COOKIEFILE"))
+            .map(PathBuf::from)
+            .err_into(|_| Error::Tor("Tor does not support cookie authentication".to_string()))
+    }
+
+    /// 動作の説明:  
+    /// HashedControlPasswordで設定したパスワードで認証します  
+    pub async fn authenticate_password(&mut self, password: &str) -> Result<(), Error> {
+        self.command(&format!("AUTHENTICATE {}", quote(password)))
+            .await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// この接続が切れたらTorが終了するようにします  
+    #[cfg(feature = "embedded-tor")]
+    pub async fn take_ownership(&mut self) -> Result<(), Error> {
+        self.command("TAKEOWNERSHIP").await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// 受け取る非同期イベントの種類を指定します  
+    /// 空にした場合はイベントを受け取らなくなります  
+    pub async fn set_events(&mut self, events: &[&str]) -> Result<(), Error> {
+        let mut command = "SETEVENTS".to_string();
+        for event in events {
+            command.push(' ');
+            command.push_str(argument(event)?);
+        }
+        self.command(&command).await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// GETINFOで1つの値を取得します  
+    /// 返り値について:  
+    /// 値が無かった場合はError::Torになります  
+    pub async fn get_info(&mut self, key: &str) -> Result<String, Error> {
+        let reply = self.command(&format!("GETINFO {}", argument(key)?)).await?;
+        let prefix = format!("{}=", key);
+        reply
+            .lines
+            .iter()
+            .find_map(|l| l.strip_prefix(&prefix))
+            .map(|v| v.to_string())
+            .err_into(|_| Error::Tor(format!("GETINFO {} returned no value", key)))
+    }
+
+    /// 動作の説明:  
+    /// GETCONFで設定の値を取得します  
+    /// 返り値について:  
+    /// 同じ設定が複数ある場合は全ての値が返ります  
+    /// 設定されていない場合は空のVecが返ります  
+    pub async fn get_conf(&mut self, key: &str) -> Result<Vec<String>, Error> {
+        let reply = self.command(&format!("GETCONF {}", argument(key)?)).await?;
+        let prefix = format!("{}=", key);
+        Ok(reply
+            .lines
+            .iter()
+            .filter_map(|l| l.strip_prefix(&prefix))
+            .map(|v| v.trim_matches('"').to_string())
+            .collect())
+    }
+
+    /// 動作の説明:  
+    /// SETCONFで設定を変更します  
+    /// 引数について:  
+    /// 設定の名前と値の組を指定します  
+    /// 値に空文字列を指定した場合は初期値に戻ります  
+    pub async fn set_conf(&mut self, values: &[(&str, &str)]) -> Result<(), Error> {
+        let mut command = "SETCONF".to_string();
+        for (key, value) in values {
+            command.push(' ');
+            command.push_str(argument(key)?);
+            if !value.is_empty() {
+                command.push('=');
+                command.push_str(&quote(value));
+            }
+        }
+        self.command(&command).await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// ADD_ONIONでHidden Serviceを作ります  
+    /// 作ったHidden Serviceは、この接続が閉じられると消えます  
+    /// 引数について:  
+    /// 1: "NEW:ED25519-V3"か"ED25519-V3:(鍵)"を指定します  
+    /// 2: Hidden Service側で公開するポートを指定します  
+    /// 3: 転送先を(アドレス):(ポート)の形式で指定します  
+    /// 4: クライアント認証の公開鍵をBase32で指定します  
+    /// 空でない場合、その鍵を持つクライアントだけが接続できるようになります  
+    pub async fn add_onion(
+        &mut self,
+        key: &str,
+        virtual_port: u16,
+        target: &str,
+        client_auth: &[&str],
+    ) -> Result<AddedOnion, Error> {
+        let mut command = format!("ADD_ONION {}", argument(key)?);
+        if !client_auth.is_empty() {
+            command.push_str(" Flags=V3Auth");
+        }
+        command.push_str(&format!(" Port={},{}", virtual_port, argument(target)?));
+        for client in client_auth {
+            command.push_str(" ClientAuthV3=");
+            command.push_str(argument(client)?);
+        }
+        let reply = self.command(&command).await?;
+        let mut service_id = None;
+        let mut private_key = None;
+        for line in reply.lines {
+            if let Some(s) = line.strip_prefix("ServiceID=") {
+                service_id = Some(s.to_string());
+            } else if let Some(s) = line.strip_prefix("PrivateKey=") {
+                private_key = Some(s.to_string());
+            }
+        }
+        let service_id =
+            service_id.err_into(|_| Error::Tor("ADD_ONION returned no ServiceID".to_string()))?;
+        Ok(AddedOnion {
+            service_id,
+            private_key,
+        })
+    }
+
+    /// 動作の説明:  
+    /// ADD_ONIONで作ったHidden Serviceを消します  
+    /// 引数について:  
+    /// .onionを除いたServiceIDを指定します  
+    pub async fn del_onion(&mut self, service_id: &str) -> Result<(), Error> {
+        self.command(&format!("DEL_ONION {}", argument(service_id)?))
+            .await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// クライアント認証が必要なHidden Serviceに接続するための秘密鍵を登録します  
+    /// 引数について:  
+    /// 1: .onionを除いたServiceIDを指定します  
+    /// 2: x25519の秘密鍵をBase64にしたものを指定します  
+    pub async fn add_client_auth(&mut self, service_id: &str, secret: &str) -> Result<(), Error> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+            argument(service_id)?,
+            argument(secret)?
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// add_client_authで登録した秘密鍵を消します  
+    pub async fn remove_client_auth(&mut self, service_id: &str) -> Result<(), Error> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_REMOVE {}",
+            argument(service_id)?
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// Torにシグナルを送ります  
+    /// 引数について:  
+    /// "SHUTDOWN"や"RELOAD"などのシグナルの名前を指定します  
+    pub async fn signal(&mut self, signal: &str) -> Result<(), Error> {
+        self.command(&format!("SIGNAL {}", argument(signal)?))
+            .await?;
+        Ok(())
+    }
+
+    /// 動作の説明:  
+    /// これから作る接続に新しい回線を使うようにします  
+    pub async fn signal_newnym(&mut self) -> Result<(), Error> {
+        self.signal("NEWNYM").await
+    }
+
+    /// 動作の説明:  
+    /// 非同期イベントを1つ受け取ります  
+    /// 溜まっているイベントがある場合はそれを返し、無い場合は届くまで待ちます  
+    /// 注意点:  
+    /// set_eventsで受け取る種類を指定していない場合は、いつまでも戻りません  
+    pub async fn next_event(&mut self) -> Result<Event, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let reply = self.read_reply().await?;
+            if reply.code == 650 {
+                self.events.push_back(Event::from_lines(reply.lines));
+            } else {
+                warn!(
+                    "unexpected reply from Tor: {} {:?}",
+                    reply.code, reply.lines
+                );
+            }
+        }
+    }
+
+    // 1つの応答を最後の行まで読む
+    async fn read_reply(&mut self) -> Result<Reply, Error> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let (code, separator, body) = match (line.get(..3), line.get(3..4), line.get(4..)) {
+                (Some(a), Some(b), Some(c)) => (a, b, c),
+                _ => return Err(Error::Tor(format!("malformed reply: {:?}", line))),
+            };
+            let code = code
+                .parse::<u16>()
+                .err_into(|_| Error::Tor(format!("malformed reply: {:?}", line)))?;
+            let mut body = body.to_string();
+            match separator {
+                " " => {
+                    lines.push(body);
+                    return Ok(Reply { code, lines });
+                }
+                "-" => lines.push(body),
+                "+" => {
+                    // "."だけの行までがデータになる
+                    loop {
+                        let data = self.read_line().await?;
+                        if data == "." {
+                            break;
+                        }
+                        body.push('\n');
+                        body.push_str(data.strip_prefix('.').unwrap_or(&data));
+                    }
+                    lines.push(body);
+                }
+                _ => return Err(Error::Tor(format!("malformed reply: {:?}", line))),
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        let len = self
+            .stream
+            .read_line(&mut line)
+            .await
+            .err_into(Error::Tor)?;
+        if len == 0 {
+            return Err(Error::Tor("the control connection is closed".to_string()));
+        }
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+    }
+}
+
+/// 動作の説明:  
+/// 文字列をControlPortの引用符付き文字列にします  
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// コマンドの引数として使えるか確かめる
+// 空白や改行が入っていると、別の引数やコマンドを紛れ込ませられる
+// 秘密鍵が渡されることもあるので、中身はログに出さない
+fn argument(s: &str) -> Result<&str, Error> {
+    if s.is_empty() || s.contains(['\r', '\n', ' ']) {
+        error!("an argument for the control port is invalid");
+        return Err(Error::Tor(
+            "an argument for the control port is invalid".to_string(),
+        ));
+    }
+    Ok(s)
+}
+
+/// 動作の説明:  
+/// KEY=VALUEやKEY="VALUE"の並びを読み取ります  
+/// 値のないものは無視されます  
+pub fn parse_keywords(s: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return map;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || *c == ' ' {
+                break;
+            }
+            key.push(*c);
+            chars.next();
+        }
+        if chars.peek() != Some(&'=') {
+            continue;
+        }
+        chars.next();
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ' ' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+        map.insert(key, value);
+    }
+}
+
+/// 動作の説明:  
+/// GETINFO status/bootstrap-phaseやSTATUS_CLIENTイベントから、Torの起動の進捗(%)と段階の説明を取り出します  
+/// 例: NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY="Loading relay descriptors"  
+/// 返り値について:  
+/// BOOTSTRAPのステータスでない場合はNoneになります  
+pub fn parse_bootstrap(status: &str) -> Option<(u8, String)> {
+    let (_, keywords) = status.split_once("BOOTSTRAP ")?;
+    let keywords = parse_keywords(keywords);
+    let progress = keywords.get("PROGRESS")?.parse().ok()?;
+    let summary = keywords
+        .get("SUMMARY")
+        .or_else(|| keywords.get("TAG"))
+        .cloned()
+        .unwrap_or_default();
+    Some((progress, summary))
+}
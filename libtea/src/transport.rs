@@ -0,0 +1,342 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! 相手との通信路を抽象化するモジュールです  
+//! 標準ではTorのSocksプロキシとHidden Serviceを使うTorTransportが使われます  
+//! SessionBuilder::transportで別の実装を指定することで、Torを起動せずにセッションを作ることができます  
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock as StdRwLock,
+    },
+};
+
+use async_trait::async_trait;
+use rand::Rng;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+};
+
+use crate::{inside::structs::ErrInto, Error};
+
+/// Transportが返す接続が満たす必要のあるトレイトです  
+/// AsyncReadとAsyncWriteを実装していれば自動で実装されます  
+pub trait Connection: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Connection for T {}
+
+/// Transportが返す接続です
+pub type BoxedConnection = Box<dyn Connection>;
+
+/// 相手との通信路です  
+/// 接続した後の認証やメッセージのやり取りは、どの実装でもlibteaの中で同じように行われます  
+#[async_trait]
+pub trait Transport: std::fmt::Debug + Send + Sync + 'static {
+    /// 動作の説明:  
+    /// 他の人が自分に接続するためのホスト名を返します  
+    /// 自分のアドレスの@以降の部分になります  
+    fn hostname(&self) -> String;
+
+    /// 動作の説明:  
+    /// 相手に接続します  
+    /// 引数について:  
+    /// 相手のアドレスの@以降の部分が入ります  
+    async fn dial(&self, hostname: &str) -> Result<BoxedConnection, Error>;
+
+    /// 動作の説明:  
+    /// 相手からの接続を1つ受け付けます  
+    /// 返り値について:  
+    /// 今後一切接続を受け付けられない場合はError::Closedを返してください  
+    async fn accept(&self) -> Result<BoxedConnection, Error>;
+}
+
+/// TorのSocksプロキシに接続するときに、どの単位で回線を分けるかを指定します  
+/// Torは異なるSocksのユーザー名とパスワードを使った接続に、異なる回線を使います  
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CircuitIsolation {
+    /// 連絡先ごとに別の回線を使います  
+    /// 複数の連絡先との通信が同じ人のものだと結び付けられにくくなります  
+    #[default]
+    PerContact,
+    /// 接続ごとに別の回線を使います  
+    /// 同じ連絡先でも、接続し直すたびに回線が変わります  
+    PerConnection,
+    /// 全ての接続で同じ回線を使うことを許します  
+    /// 認証情報は送らず、以前のバージョンと同じ動作になります  
+    Shared,
+}
+
+/// TorのSocksプロキシを経由して接続し、Hidden Serviceで接続を受け付けるTransportです
+#[derive(Debug)]
+pub struct TorTransport {
+    listener: TcpListener,
+    socks_address: String,
+    virtual_port: u16,
+    // アドレスを変えたときに書き換えられる
+    hostname: StdRwLock<String>,
+    isolation: CircuitIsolation,
+    // Socksのユーザー名に使う、このTransportだけの値
+    isolation_token: String,
+    // CircuitIsolation::PerConnectionでパスワードに使う連番
+    connections: AtomicU64,
+}
+
+impl TorTransport {
+    /// 動作の説明:  
+    /// 新しくTorTransportを作ります  
+    /// 引数について:  
+    /// 1: Hidden Serviceの転送先になっているTcpListenerを指定します  
+    /// 2: TorのSocksプロキシのアドレスを(アドレス):(ポート)の形式で指定します  
+    /// 3: Hidden Service側で公開しているポートを指定します  
+    /// 4: 自分のHidden Serviceのホスト名を指定します  
+    /// 注意点:  
+    /// 回線は連絡先ごとに分けられます。変える場合はisolationを使ってください  
+    pub fn new(
+        listener: TcpListener,
+        socks_address: String,
+        virtual_port: u16,
+        hostname: String,
+    ) -> TorTransport {
+        TorTransport {
+            listener,
+            socks_address,
+            virtual_port,
+            hostname: StdRwLock::new(hostname),
+            isolation: CircuitIsolation::default(),
+            isolation_token: format!("ryokuchat-{:016x}", rand::rngs::OsRng.gen::<u64>()),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// 動作の説明:  
+    /// どの単位で回線を分けるかを指定します  
+    pub fn isolation(mut self, isolation: CircuitIsolation) -> TorTransport {
+        self.isolation = isolation;
+        self
+    }
+
+    /// 動作の説明:  
+    /// 自分のHidden Serviceのホスト名を変更します  
+    /// 注意点:  
+    /// Hidden Serviceそのものは変わらないため、先にADD_ONIONで新しいものを作っておいてください  
+    pub fn set_hostname(&self, hostname: String) {
+        debug!("hostname is changed to {}", &hostname);
+        match self.hostname.write() {
+            Ok(mut o) => *o = hostname,
+            Err(e) => *e.into_inner() = hostname,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TorTransport {
+    fn hostname(&self) -> String {
+        match self.hostname.read() {
+            Ok(o) => o.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    async fn dial(&self, hostname: &str) -> Result<BoxedConnection, Error> {
+        trace!("TorTransport::dial() is called");
+        defer!(trace!("returning from TorTransport::dial()"));
+
+        let proxy = self.socks_address.as_str();
+        let target = format!("{}:{}", hostname, self.virtual_port);
+        // ユーザー名とパスワードの組が異なる接続には、Torが別の回線を使う
+        let stream = match self.isolation {
+            CircuitIsolation::PerContact => {
+                tokio_socks::tcp::Socks5Stream::connect_with_password(
+                    proxy,
+                    target,
+                    &self.isolation_token,
+                    hostname,
+                )
+                .await
+            }
+            CircuitIsolation::PerConnection => {
+                let n = self.connections.fetch_add(1, Ordering::Relaxed);
+                tokio_socks::tcp::Socks5Stream::connect_with_password(
+                    proxy,
+                    target,
+                    &self.isolation_token,
+                    &n.to_string(),
+                )
+                .await
+            }
+            CircuitIsolation::Shared => {
+                tokio_socks::tcp::Socks5Stream::connect(proxy, target).await
+            }
+        }
+        .err_into(Error::Transport)?;
+        Ok(Box::new(stream))
+    }
+
+    async fn accept(&self) -> Result<BoxedConnection, Error> {
+        let (stream, _) = self.listener.accept().await.err_into(Error::Transport)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Torを使わずに、TCPで直接接続するTransportです  
+/// ホスト名は(アドレス):(ポート)の形式になります  
+/// 注意点:  
+/// 通信は暗号化されず、相手に自分のIPアドレスが分かるため、テストや信頼できるネットワークでのみ使ってください  
+#[derive(Debug)]
+pub struct TcpTransport {
+    listener: TcpListener,
+    hostname: String,
+}
+
+impl TcpTransport {
+    /// 動作の説明:  
+    /// 指定したアドレスをbindしてTcpTransportを作ります  
+    /// ポートに0を指定した場合は空いているポートが自動で選ばれます  
+    pub async fn bind(address: SocketAddr) -> Result<TcpTransport, Error> {
+        trace!("TcpTransport::bind() is called");
+        defer!(trace!("returning from TcpTransport::bind()"));
+
+        let listener = TcpListener::bind(address).await?;
+        let hostname = listener.local_addr()?.to_string();
+        debug!("hostname is {}", &hostname);
+        Ok(TcpTransport { listener, hostname })
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    fn hostname(&self) -> String {
+        self.hostname.clone()
+    }
+
+    async fn dial(&self, hostname: &str) -> Result<BoxedConnection, Error> {
+        let stream = TcpStream::connect(hostname)
+            .await
+            .err_into(Error::Transport)?;
+        Ok(Box::new(stream))
+    }
+
+    async fn accept(&self) -> Result<BoxedConnection, Error> {
+        let (stream, _) = self.listener.accept().await.err_into(Error::Transport)?;
+        Ok(Box::new(stream))
+    }
+}
+
+// MemoryNetworkでの1接続あたりのバッファのサイズ
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 同じプロセスの中だけで通信するための仮想的なネットワークです  
+/// cloneしたものは同じネットワークを指します  
+/// transportメソッドで、このネットワークにつながったMemoryTransportを作ることができます  
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork {
+    hosts: Arc<StdMutex<HashMap<String, mpsc::Sender<DuplexStream>>>>,
+}
+
+impl MemoryNetwork {
+    /// 動作の説明:  
+    /// 新しく空のネットワークを作ります  
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// 動作の説明:  
+    /// 指定したホスト名で接続を受け付けるMemoryTransportを作ります  
+    /// 返り値について:  
+    /// 既に同じホスト名が使われている場合はError::Configになります  
+    pub fn transport(&self, hostname: &str) -> Result<MemoryTransport, Error> {
+        trace!("MemoryNetwork::transport() is called");
+        defer!(trace!("returning from MemoryNetwork::transport()"));
+
+        let mut hosts = self
+            .hosts
+            .lock()
+            .err_into(|_| Error::Config("MemoryNetwork is poisoned".to_string()))?;
+        if hosts.get(hostname).is_some_and(|h| !h.is_closed()) {
+            error!("{} is already used", hostname);
+            return Err(Error::Config(format!("{} is already used", hostname)));
+        }
+        let (sender, receiver) = mpsc::channel(16);
+        hosts.insert(hostname.to_string(), sender);
+
+        Ok(MemoryTransport {
+            network: self.clone(),
+            receiver: Mutex::const_new(receiver),
+            hostname: hostname.to_string(),
+        })
+    }
+}
+
+/// MemoryNetworkの中で、tokio::io::duplexを使って通信するTransportです
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    receiver: Mutex<mpsc::Receiver<DuplexStream>>,
+    hostname: String,
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    fn hostname(&self) -> String {
+        self.hostname.clone()
+    }
+
+    async fn dial(&self, hostname: &str) -> Result<BoxedConnection, Error> {
+        let sender = self
+            .network
+            .hosts
+            .lock()
+            .err_into(|_| Error::Config("MemoryNetwork is poisoned".to_string()))?
+            .get(hostname)
+            .cloned()
+            .err_into(|_| Error::Transport(format!("{} is not found", hostname)))?;
+        let (local, remote) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        sender
+            .send(remote)
+            .await
+            .err_into(|_| Error::Transport(format!("{} is not accepting", hostname)))?;
+        Ok(Box::new(local))
+    }
+
+    async fn accept(&self) -> Result<BoxedConnection, Error> {
+        match self.receiver.lock().await.recv().await {
+            Some(s) => Ok(Box::new(s)),
+            None => Err(Error::Closed),
+        }
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        // 自分のホスト名をネットワークから外す
+        self.receiver.get_mut().close();
+        if let Ok(mut hosts) = self.network.hosts.lock() {
+            if hosts.get(&self.hostname).is_some_and(|h| h.is_closed()) {
+                hosts.remove(&self.hostname);
+            }
+        }
+    }
+}
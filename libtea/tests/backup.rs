@@ -0,0 +1,203 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+
+use libtea::{
+    test_support::TestSessions, transport::MemoryNetwork, Error, RYOKUCHATSession, SessionBuilder,
+};
+
+// MemoryNetworkにつながったセッションを作るSessionBuilder
+fn builder(data_dir: &Path, network: &MemoryNetwork) -> SessionBuilder {
+    SessionBuilder::new(data_dir).transport(network.transport("restored.test").unwrap())
+}
+
+// アドレスのうちIDの部分
+fn id_of(address: &str) -> String {
+    address.split_once('@').unwrap().0.to_string()
+}
+
+async fn addresses(session: &RYOKUCHATSession) -> Vec<String> {
+    let mut addresses: Vec<_> = session
+        .get_users()
+        .await
+        .unwrap()
+        .iter()
+        .map(|u| u.get_address())
+        .collect();
+    addresses.sort();
+    addresses
+}
+
+#[tokio::test]
+async fn backup_restores_identity_and_contacts() {
+    let sessions = TestSessions::memory(3).await.unwrap();
+    sessions.connect_all().await.unwrap();
+    let a = &sessions.sessions[0];
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.ykr");
+
+    a.export_backup(&backup, "backup passphrase").await.unwrap();
+    let data = std::fs::read(&backup).unwrap();
+    assert!(data.starts_with(b"RYOKUKEY"));
+    // 鍵の種類はバックアップで、必ず暗号化される
+    assert_eq!((data[9], data[10]), (4, 1));
+    // 暗号化されていないデータベースの一時ファイルを保存先に残さない
+    let files: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(files, ["backup.ykr"]);
+
+    let network = MemoryNetwork::new();
+    let builder = builder(&dir.path().join("restored"), &network);
+    RYOKUCHATSession::import_backup(&builder, &backup, "backup passphrase")
+        .await
+        .unwrap();
+    let restored = builder.build().await.unwrap();
+    assert_eq!(id_of(&restored.myaddress()), id_of(&a.myaddress()));
+    assert_eq!(addresses(&restored).await, addresses(a).await);
+    assert_eq!(addresses(&restored).await.len(), 2);
+
+    restored.shutdown().await.unwrap();
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn backup_needs_the_passphrase() {
+    let sessions = TestSessions::memory(1).await.unwrap();
+    let a = &sessions.sessions[0];
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.ykr");
+
+    assert!(matches!(
+        a.export_backup(&backup, "").await,
+        Err(Error::Config(_))
+    ));
+    a.export_backup(&backup, "right").await.unwrap();
+
+    let network = MemoryNetwork::new();
+    let builder = builder(&dir.path().join("restored"), &network);
+    assert!(matches!(
+        RYOKUCHATSession::import_backup(&builder, &backup, "wrong").await,
+        Err(Error::Locked)
+    ));
+    // 何も書き込まれていないので、新しいIDで作られる
+    let restored = builder.build().await.unwrap();
+    assert_ne!(id_of(&restored.myaddress()), id_of(&a.myaddress()));
+
+    restored.shutdown().await.unwrap();
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn backup_does_not_overwrite_an_identity() {
+    let sessions = TestSessions::memory(1).await.unwrap();
+    let a = &sessions.sessions[0];
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.ykr");
+    a.export_backup(&backup, "passphrase").await.unwrap();
+
+    // 復元する先には既に秘密鍵がある
+    let network = MemoryNetwork::new();
+    let data_dir = dir.path().join("existing");
+    let existing = builder(&data_dir, &network).build().await.unwrap();
+    let address = existing.myaddress();
+    existing.shutdown().await.unwrap();
+    drop(existing);
+
+    let result =
+        RYOKUCHATSession::import_backup(&builder(&data_dir, &network), &backup, "passphrase").await;
+    assert!(matches!(result, Err(Error::Config(_))));
+    let existing = builder(&data_dir, &network).build().await.unwrap();
+    assert_eq!(existing.myaddress(), address);
+
+    existing.shutdown().await.unwrap();
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn backup_does_not_overwrite_a_database() {
+    let sessions = TestSessions::memory(1).await.unwrap();
+    let a = &sessions.sessions[0];
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.ykr");
+    a.export_backup(&backup, "passphrase").await.unwrap();
+
+    // 秘密鍵だけを失い、データベースが残っている
+    let network = MemoryNetwork::new();
+    let data_dir = dir.path().join("existing");
+    let existing = builder(&data_dir, &network).build().await.unwrap();
+    existing.shutdown().await.unwrap();
+    drop(existing);
+    std::fs::remove_file(data_dir.join("DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr")).unwrap();
+
+    let result =
+        RYOKUCHATSession::import_backup(&builder(&data_dir, &network), &backup, "passphrase").await;
+    assert!(matches!(result, Err(Error::Config(_))));
+    assert!(!data_dir
+        .join("DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr")
+        .exists());
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn backup_keeps_pending_rotations() {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.ykr");
+
+    // 届かない連絡先がいる状態でIDを変える
+    let offline = MemoryNetwork::new();
+    let peer = builder(&dir.path().join("peer"), &offline)
+        .build()
+        .await
+        .unwrap();
+    let network = MemoryNetwork::new();
+    let a = builder(&dir.path().join("a"), &network)
+        .build()
+        .await
+        .unwrap();
+    a.add_user(&peer.myaddress()).await.unwrap();
+    assert_eq!(a.rotate_identity().await.unwrap().len(), 1);
+    a.export_backup(&backup, "passphrase").await.unwrap();
+    a.shutdown().await.unwrap();
+
+    let restored_dir = dir.path().join("restored");
+    let builder = builder(&restored_dir, &MemoryNetwork::new());
+    RYOKUCHATSession::import_backup(&builder, &backup, "passphrase")
+        .await
+        .unwrap();
+    assert!(restored_dir
+        .join("DO_NOT_SEND_TO_OTHER_PEOPLE_previouskeys.ykr")
+        .exists());
+
+    // 復元した先でも、古い鍵で送り直せる
+    let restored = builder.build().await.unwrap();
+    assert_eq!(id_of(&restored.myaddress()), id_of(&a.myaddress()));
+    assert_eq!(restored.resend_identity_rotation().await.unwrap().len(), 1);
+    assert!(restored_dir
+        .join("DO_NOT_SEND_TO_OTHER_PEOPLE_previouskeys.ykr")
+        .exists());
+
+    restored.shutdown().await.unwrap();
+    peer.shutdown().await.unwrap();
+}
@@ -0,0 +1,132 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use libtea::transport::{CircuitIsolation, TorTransport, Transport};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+type Credentials = Arc<Mutex<Vec<Option<(String, String)>>>>;
+
+// 受け取ったSocksの認証情報を記録するだけのSocks5プロキシ
+// 認証情報が無い接続はNoneとして記録する
+async fn fake_socks() -> (String, Credentials) {
+    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let credentials = Arc::new(Mutex::new(Vec::new()));
+    let credentials2 = credentials.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            socks_handshake(stream, &credentials2).await;
+        }
+    });
+    (address, credentials)
+}
+
+async fn socks_handshake(mut stream: TcpStream, credentials: &Credentials) {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await.unwrap();
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await.unwrap();
+
+    // ユーザー名とパスワードが送られてくる場合はそれを使う
+    let credential = if methods.contains(&2) {
+        stream.write_all(&[5, 2]).await.unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut username = vec![0; len[1] as usize];
+        stream.read_exact(&mut username).await.unwrap();
+        stream.read_exact(&mut len[..1]).await.unwrap();
+        let mut password = vec![0; len[0] as usize];
+        stream.read_exact(&mut password).await.unwrap();
+        stream.write_all(&[1, 0]).await.unwrap();
+        Some((
+            String::from_utf8(username).unwrap(),
+            String::from_utf8(password).unwrap(),
+        ))
+    } else {
+        stream.write_all(&[5, 0]).await.unwrap();
+        None
+    };
+
+    // CONNECTの要求を読み、記録してから成功したことにする
+    let mut request = [0; 5];
+    stream.read_exact(&mut request).await.unwrap();
+    let mut rest = vec![0; request[4] as usize + 2];
+    stream.read_exact(&mut rest).await.unwrap();
+    credentials.lock().unwrap().push(credential);
+    stream
+        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+        .await
+        .unwrap();
+}
+
+async fn dial_contacts(isolation: CircuitIsolation) -> Vec<Option<(String, String)>> {
+    let (socks_address, credentials) = fake_socks().await;
+    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .await
+        .unwrap();
+    let transport = TorTransport::new(listener, socks_address, 4545, "me.onion".to_string())
+        .isolation(isolation);
+    for hostname in ["a.onion", "b.onion", "a.onion"] {
+        transport.dial(hostname).await.unwrap();
+    }
+    let credentials = credentials.lock().unwrap().clone();
+    credentials
+}
+
+#[tokio::test]
+async fn per_contact_isolation_uses_one_credential_per_contact() {
+    let credentials = dial_contacts(CircuitIsolation::PerContact).await;
+    let credentials: Vec<_> = credentials.into_iter().map(Option::unwrap).collect();
+    assert_ne!(credentials[0], credentials[1]);
+    assert_eq!(credentials[0], credentials[2]);
+}
+
+#[tokio::test]
+async fn per_connection_isolation_uses_new_credentials_every_time() {
+    let credentials = dial_contacts(CircuitIsolation::PerConnection).await;
+    let credentials: Vec<_> = credentials.into_iter().map(Option::unwrap).collect();
+    assert_ne!(credentials[0], credentials[1]);
+    assert_ne!(credentials[0], credentials[2]);
+    assert_ne!(credentials[1], credentials[2]);
+}
+
+#[tokio::test]
+async fn shared_isolation_sends_no_credentials() {
+    let credentials = dial_contacts(CircuitIsolation::Shared).await;
+    assert_eq!(credentials, vec![None, None, None]);
+}
+
+#[tokio::test]
+async fn credentials_differ_between_transports() {
+    let first = dial_contacts(CircuitIsolation::PerContact).await;
+    let second = dial_contacts(CircuitIsolation::PerContact).await;
+    assert_ne!(first[0], second[0]);
+}
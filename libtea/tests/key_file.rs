@@ -0,0 +1,265 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+
+use libtea::{
+    test_support::wait_for, transport::MemoryNetwork, Error, Message, RYOKUCHATSession,
+    SessionBuilder,
+};
+
+const KEY_FILE: &str = "DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr";
+const PREVIOUS_KEY_FILE: &str = "DO_NOT_SEND_TO_OTHER_PEOPLE_previouskeys.ykr";
+// 以前のバージョンで保存された、ヘッダーの無い秘密鍵の長さ
+const RAW_KEY_LENGTH: usize = 57;
+// ヘッダーの中の位置
+const VERSION: usize = 8;
+const KEY_TYPE: usize = 9;
+const ENCRYPTION: usize = 10;
+
+// MemoryNetworkを使ってセッションを作る
+async fn open(data_dir: &Path, passphrase: Option<&str>) -> Result<RYOKUCHATSession, Error> {
+    let network = MemoryNetwork::new();
+    let mut builder = SessionBuilder::new(data_dir).transport(network.transport("peer.test")?);
+    if let Some(p) = passphrase {
+        builder = builder.passphrase(p);
+    }
+    builder.build().await
+}
+
+#[tokio::test]
+async fn encrypted_key_needs_the_passphrase() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let session = open(data_dir.path(), Some("correct horse")).await.unwrap();
+    let address = session.myaddress();
+    session.shutdown().await.unwrap();
+
+    let data = std::fs::read(data_dir.path().join(KEY_FILE)).unwrap();
+    assert!(data.starts_with(b"RYOKUKEY"));
+
+    let result = open(data_dir.path(), None).await;
+    assert!(matches!(result, Err(Error::Locked)));
+    let result = open(data_dir.path(), Some("wrong horse")).await;
+    assert!(matches!(result, Err(Error::Locked)));
+
+    let session = open(data_dir.path(), Some("correct horse")).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn plaintext_key_is_encrypted_with_a_passphrase() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let session = open(data_dir.path(), None).await.unwrap();
+    let address = session.myaddress();
+    session.shutdown().await.unwrap();
+    let data = std::fs::read(data_dir.path().join(KEY_FILE)).unwrap();
+    assert!(data.starts_with(b"RYOKUKEY"));
+    assert_eq!((data[VERSION], data[KEY_TYPE], data[ENCRYPTION]), (1, 1, 0));
+
+    let session = open(data_dir.path(), Some("passphrase")).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+    let data = std::fs::read(data_dir.path().join(KEY_FILE)).unwrap();
+    assert_eq!(data[ENCRYPTION], 1);
+    assert!(matches!(
+        open(data_dir.path(), None).await,
+        Err(Error::Locked)
+    ));
+}
+
+#[tokio::test]
+async fn passphrase_can_be_changed_and_removed() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let session = open(data_dir.path(), Some("old")).await.unwrap();
+    let address = session.myaddress();
+    assert!(matches!(
+        session.change_passphrase(Some("")).await,
+        Err(Error::Config(_))
+    ));
+    session.change_passphrase(Some("new")).await.unwrap();
+    session.shutdown().await.unwrap();
+
+    assert!(matches!(
+        open(data_dir.path(), Some("old")).await,
+        Err(Error::Locked)
+    ));
+    let session = open(data_dir.path(), Some("new")).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.change_passphrase(None).await.unwrap();
+    session.shutdown().await.unwrap();
+
+    let data = std::fs::read(data_dir.path().join(KEY_FILE)).unwrap();
+    assert_eq!(data[ENCRYPTION], 0);
+    let session = open(data_dir.path(), None).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_passphrase_change_keeps_the_old_passphrase() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let peer_dir = tempfile::tempdir().unwrap();
+
+    // 届かない連絡先がいるので、IDを移行しても古い鍵が残る
+    let peer = open(peer_dir.path(), None).await.unwrap();
+    let session = open(data_dir.path(), Some("old")).await.unwrap();
+    session.add_user(&peer.myaddress()).await.unwrap();
+    assert_eq!(session.rotate_identity().await.unwrap().len(), 1);
+    let address = session.myaddress();
+    assert!(data_dir.path().join(PREVIOUS_KEY_FILE).exists());
+
+    // 古い鍵のファイルを書き込めないようにする
+    let blocker = data_dir.path().join(format!("{}.new", PREVIOUS_KEY_FILE));
+    std::fs::create_dir(&blocker).unwrap();
+    assert!(session.change_passphrase(Some("new")).await.is_err());
+    std::fs::remove_dir(&blocker).unwrap();
+    // 古い鍵も元のパスフレーズのまま読める
+    assert_eq!(session.resend_identity_rotation().await.unwrap().len(), 1);
+    session.shutdown().await.unwrap();
+    peer.shutdown().await.unwrap();
+
+    assert!(matches!(
+        open(data_dir.path(), Some("new")).await,
+        Err(Error::Locked)
+    ));
+    let session = open(data_dir.path(), Some("old")).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn lost_previous_key_keeps_the_rotation_pending() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let peer_dir = tempfile::tempdir().unwrap();
+
+    let peer = open(peer_dir.path(), None).await.unwrap();
+    let session = open(data_dir.path(), None).await.unwrap();
+    session.add_user(&peer.myaddress()).await.unwrap();
+    assert_eq!(session.rotate_identity().await.unwrap().len(), 1);
+
+    // 古い鍵のファイルが無くなると、送れずに知らされる
+    let previous = data_dir.path().join(PREVIOUS_KEY_FILE);
+    let moved = data_dir.path().join("previouskeys.bak");
+    std::fs::rename(&previous, &moved).unwrap();
+    let mut events = session.subscribe();
+    assert_eq!(session.resend_identity_rotation().await.unwrap().len(), 1);
+    wait_for(&mut events, |m| match m {
+        Message::StorageError(_) => Some(()),
+        _ => None,
+    })
+    .await;
+
+    // 送ったことにはならないので、鍵を戻せばまた送り直せる
+    std::fs::rename(&moved, &previous).unwrap();
+    assert_eq!(session.resend_identity_rotation().await.unwrap().len(), 1);
+    assert!(previous.exists());
+
+    session.shutdown().await.unwrap();
+    peer.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rotation_keeps_the_passphrase() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let session = open(data_dir.path(), Some("old")).await.unwrap();
+    session.change_passphrase(Some("new")).await.unwrap();
+    assert!(session.rotate_identity().await.unwrap().is_empty());
+    let address = session.myaddress();
+    session.shutdown().await.unwrap();
+
+    let data = std::fs::read(data_dir.path().join(KEY_FILE)).unwrap();
+    assert_eq!(data[ENCRYPTION], 1);
+    // 連絡先が無いので、古い鍵は残らない
+    assert!(!data_dir.path().join(PREVIOUS_KEY_FILE).exists());
+    assert!(matches!(
+        open(data_dir.path(), None).await,
+        Err(Error::Locked)
+    ));
+    let session = open(data_dir.path(), Some("new")).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn legacy_key_file_is_rewritten() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let key_file = data_dir.path().join(KEY_FILE);
+    std::fs::write(&key_file, [7; RAW_KEY_LENGTH]).unwrap();
+
+    let session = open(data_dir.path(), None).await.unwrap();
+    let address = session.myaddress();
+    session.shutdown().await.unwrap();
+    let data = std::fs::read(&key_file).unwrap();
+    assert!(data.starts_with(b"RYOKUKEY"));
+
+    let session = open(data_dir.path(), None).await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn corrupted_key_file_is_reported() {
+    for passphrase in [None, Some("passphrase")] {
+        let data_dir = tempfile::tempdir().unwrap();
+        let key_file = data_dir.path().join(KEY_FILE);
+        open(data_dir.path(), passphrase)
+            .await
+            .unwrap()
+            .shutdown()
+            .await
+            .unwrap();
+
+        // 鍵の部分を1ビットだけ書き換える
+        let mut data = std::fs::read(&key_file).unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        std::fs::write(&key_file, &data).unwrap();
+
+        // パスフレーズの間違いとは区別され、新しい鍵も作られない
+        let result = open(data_dir.path(), passphrase).await;
+        assert!(matches!(result, Err(Error::KeyFile(_))));
+        assert_eq!(std::fs::read(&key_file).unwrap(), data);
+    }
+}
+
+#[tokio::test]
+async fn truncated_key_file_is_reported() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let key_file = data_dir.path().join(KEY_FILE);
+    open(data_dir.path(), None)
+        .await
+        .unwrap()
+        .shutdown()
+        .await
+        .unwrap();
+    let data = std::fs::read(&key_file).unwrap();
+
+    for length in [0, 8, data.len() - 1] {
+        std::fs::write(&key_file, &data[..length]).unwrap();
+        let result = open(data_dir.path(), None).await;
+        assert!(matches!(result, Err(Error::KeyFile(_))));
+    }
+}
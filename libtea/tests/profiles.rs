@@ -0,0 +1,231 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use libtea::{
+    test_support::{wait_for, FakeControlPort},
+    transport::MemoryNetwork,
+    ControlAuth, Error, Message, ProfileManager, SessionBuilder,
+};
+
+fn manager(root: &std::path::Path, fake: &FakeControlPort) -> ProfileManager {
+    ProfileManager::new(
+        SessionBuilder::new(root)
+            .bind_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .system_tor(fake.address(), ControlAuth::Password("secret".to_string())),
+    )
+}
+
+#[tokio::test]
+async fn profiles_are_created_switched_and_deleted() {
+    let root = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let manager = manager(root.path(), &fake);
+
+    assert!(manager.list().await.unwrap().is_empty());
+    assert_eq!(manager.current().await.unwrap(), None);
+
+    // 最初に作ったプロフィールが使われる
+    manager.create("work").await.unwrap();
+    manager.create("home").await.unwrap();
+    assert_eq!(manager.list().await.unwrap(), ["home", "work"]);
+    assert_eq!(manager.current().await.unwrap().as_deref(), Some("work"));
+
+    for name in ["", "../escape", "a b", &"x".repeat(65)] {
+        assert!(matches!(manager.create(name).await, Err(Error::Config(_))));
+    }
+    assert!(matches!(
+        manager.create("home").await,
+        Err(Error::Config(_))
+    ));
+    assert!(matches!(
+        manager.switch("missing").await,
+        Err(Error::Config(_))
+    ));
+
+    manager.switch("home").await.unwrap();
+    assert_eq!(manager.current().await.unwrap().as_deref(), Some("home"));
+    // 作り直しても保存されている
+    let manager = self::manager(root.path(), &fake);
+    assert_eq!(manager.current().await.unwrap().as_deref(), Some("home"));
+
+    manager.delete("home").await.unwrap();
+    assert_eq!(manager.list().await.unwrap(), ["work"]);
+    assert_eq!(manager.current().await.unwrap(), None);
+    assert!(matches!(
+        manager.delete("home").await,
+        Err(Error::Config(_))
+    ));
+}
+
+#[tokio::test]
+async fn profiles_run_concurrently_on_one_tor() {
+    let root = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let manager = manager(root.path(), &fake);
+    manager.create("alice").await.unwrap();
+    manager.create("bob").await.unwrap();
+
+    let alice = manager.open("alice", None).await.unwrap();
+    let bob = manager.open("bob", None).await.unwrap();
+    // プロフィールごとに別のIDとHidden Serviceが作られる
+    assert_ne!(alice.myaddress(), bob.myaddress());
+    let add_onions = fake
+        .commands()
+        .iter()
+        .filter(|c| c.starts_with("ADD_ONION "))
+        .count();
+    assert_eq!(add_onions, 2);
+    for name in ["alice", "bob"] {
+        assert!(manager
+            .profile_dir(name)
+            .unwrap()
+            .join("DO_NOT_SEND_TO_OTHER_PEOPLE_secretkey.ykr")
+            .exists());
+    }
+
+    // 既にopenしている場合は同じセッションが返る
+    let again = manager.open("alice", None).await.unwrap();
+    assert_eq!(again.myaddress(), alice.myaddress());
+    assert!(matches!(
+        manager.delete("alice").await,
+        Err(Error::Config(_))
+    ));
+
+    // 同じTorを通して、プロフィール同士で送り合える
+    alice.add_user(&bob.myaddress()).await.unwrap();
+    bob.add_user(&alice.myaddress()).await.unwrap();
+    let bob_id = alice.get_users().await.unwrap().pop().unwrap().id;
+    let mut events = bob.subscribe();
+    alice.send_dm(&bob_id, "hello").await.unwrap();
+    let msg = wait_for(&mut events, |m| match m {
+        Message::DirectMsg(_, msg) => Some(msg),
+        _ => None,
+    })
+    .await;
+    assert_eq!(msg, "hello");
+
+    manager.shutdown().await.unwrap();
+    manager.delete("alice").await.unwrap();
+    assert_eq!(manager.list().await.unwrap(), ["bob"]);
+}
+
+#[tokio::test]
+async fn transport_can_not_be_shared() {
+    let root = tempfile::tempdir().unwrap();
+    let network = MemoryNetwork::new();
+    let manager = ProfileManager::new(
+        SessionBuilder::new(root.path()).transport(network.transport("shared.test").unwrap()),
+    );
+    manager.create("alice").await.unwrap();
+    assert!(matches!(
+        manager.open("alice", None).await,
+        Err(Error::Config(_))
+    ));
+}
+
+#[tokio::test]
+async fn opening_a_profile_does_not_block_others() {
+    let root = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let manager = manager(root.path(), &fake);
+    manager.create("alice").await.unwrap();
+    manager.create("bob").await.unwrap();
+
+    // aliceの起動が止まっている間も、他のプロフィールは操作できる
+    fake.stall_bootstrap();
+    let open = manager.open("alice", None);
+    let others = async {
+        while !fake.commands().iter().any(|c| c.starts_with("SETEVENTS ")) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::timeout(Duration::from_secs(10), manager.delete("bob"))
+            .await
+            .expect("delete is blocked by another profile")
+            .unwrap();
+        assert_eq!(manager.list().await.unwrap(), ["alice"]);
+        fake.emit_event("STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"");
+    };
+    let (alice, ()) = tokio::join!(open, others);
+    alice.unwrap();
+
+    manager.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn profiles_share_client_authorization_for_a_contact() {
+    let root = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let manager = manager(root.path(), &fake);
+    manager.create("alice").await.unwrap();
+    manager.create("bob").await.unwrap();
+    let alice = manager.open("alice", None).await.unwrap();
+    let bob = manager.open("bob", None).await.unwrap();
+
+    // 両方のプロフィールが連絡先にしている相手
+    let carol_dir = tempfile::tempdir().unwrap();
+    let carol = SessionBuilder::new(carol_dir.path())
+        .bind_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        .system_tor(fake.address(), ControlAuth::Password("secret".to_string()))
+        .build()
+        .await
+        .unwrap();
+    let carol_address = carol.myaddress();
+    let service_id = carol_address
+        .split_once('@')
+        .unwrap()
+        .1
+        .split_once(".onion")
+        .unwrap()
+        .0
+        .to_string();
+    // carolが自分のHidden Serviceのために登録したものは数えない
+    let before = fake.commands().len();
+    let count = |command: &str| {
+        fake.commands()[before..]
+            .iter()
+            .filter(|c| c.starts_with(&format!("{} {}", command, service_id)))
+            .count()
+    };
+
+    alice.add_user(&carol_address).await.unwrap();
+    bob.add_user(&carol_address).await.unwrap();
+    // 先に登録したaliceの鍵がそのまま使われる
+    assert_eq!(count("ONION_CLIENT_AUTH_ADD"), 1);
+
+    // aliceが消しても、bobの鍵に置き換えるだけで外さない
+    let alice_carol = alice.get_users().await.unwrap().pop().unwrap();
+    alice.del_user(&alice_carol.id).await.unwrap();
+    assert_eq!(count("ONION_CLIENT_AUTH_ADD"), 2);
+    assert_eq!(count("ONION_CLIENT_AUTH_REMOVE"), 0);
+
+    // 最後のプロフィールが消したときに外す
+    let bob_carol = bob.get_users().await.unwrap().pop().unwrap();
+    bob.del_user(&bob_carol.id).await.unwrap();
+    assert_eq!(count("ONION_CLIENT_AUTH_REMOVE"), 1);
+
+    carol.shutdown().await.unwrap();
+    manager.shutdown().await.unwrap();
+}
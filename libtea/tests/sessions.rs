@@ -0,0 +1,242 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+
+use libtea::{
+    test_support::{send_forged_rotation, wait_for, TestSessions},
+    Error, Message, RYOKUCHATSession, SessionBuilder, UserData,
+};
+
+// 連絡先リストからアドレスでユーザーを探す
+async fn user_of(session: &RYOKUCHATSession, address: &str) -> UserData {
+    session
+        .get_users()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|u| u.get_address() == address)
+        .unwrap()
+}
+
+async fn direct_message(sessions: TestSessions) {
+    let (a, b) = (&sessions.sessions[0], &sessions.sessions[1]);
+    sessions.connect_all().await.unwrap();
+    let a_user = user_of(b, &a.myaddress()).await;
+    let b_user = user_of(a, &b.myaddress()).await;
+
+    let mut events = b.subscribe();
+    a.send_dm(&b_user.id, "hello").await.unwrap();
+    let (from, msg) = wait_for(&mut events, |m| match m {
+        Message::DirectMsg(from, msg) => Some((from.as_byte(), msg)),
+        _ => None,
+    })
+    .await;
+    assert!(from == a_user.id.as_byte());
+    assert_eq!(msg, "hello");
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn direct_message_over_memory() {
+    direct_message(TestSessions::memory(2).await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn direct_message_over_tcp() {
+    direct_message(TestSessions::tcp(2).await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn messages_between_three_sessions() {
+    let sessions = TestSessions::memory(3).await.unwrap();
+    sessions.connect_all().await.unwrap();
+
+    let mut events: Vec<_> = sessions.sessions.iter().map(|s| s.subscribe()).collect();
+    for (i, session) in sessions.sessions.iter().enumerate() {
+        let next = &sessions.sessions[(i + 1) % 3];
+        let user = user_of(session, &next.myaddress()).await;
+        session
+            .send_dm(&user.id, &format!("from {}", i))
+            .await
+            .unwrap();
+    }
+    for (i, events) in events.iter_mut().enumerate() {
+        let msg = wait_for(events, |m| match m {
+            Message::DirectMsg(_, msg) => Some(msg),
+            _ => None,
+        })
+        .await;
+        assert_eq!(msg, format!("from {}", (i + 2) % 3));
+    }
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_sender_is_rejected() {
+    let sessions = TestSessions::memory(2).await.unwrap();
+    let (a, b) = (&sessions.sessions[0], &sessions.sessions[1]);
+    // aだけがbを連絡先に追加する
+    a.add_user(&b.myaddress()).await.unwrap();
+    let b_user = user_of(a, &b.myaddress()).await;
+
+    let mut events = b.subscribe();
+    assert!(a.send_dm(&b_user.id, "hello").await.is_err());
+    wait_for(&mut events, |m| match m {
+        Message::HandshakeRejected(_) => Some(()),
+        Message::DirectMsg(..) => panic!("the message from an unknown user is accepted"),
+        _ => None,
+    })
+    .await;
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rotation_needs_tor() {
+    let sessions = TestSessions::memory(1).await.unwrap();
+    let result = sessions.sessions[0]
+        .rotate_onion(Duration::from_secs(1))
+        .await;
+    assert!(matches!(result, Err(Error::Config(_))));
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn identity_rotation_updates_the_contact() {
+    let sessions = TestSessions::memory(2).await.unwrap();
+    let (a, b) = (&sessions.sessions[0], &sessions.sessions[1]);
+    sessions.connect_all().await.unwrap();
+    let old_user = user_of(b, &a.myaddress()).await;
+    let b_user = user_of(a, &b.myaddress()).await;
+    // 移行する前に接続を張っておく
+    a.send_dm(&b_user.id, "before").await.unwrap();
+
+    let mut events = b.subscribe();
+    let old_address = a.myaddress();
+    let failed = a.rotate_identity().await.unwrap();
+    assert!(failed.is_empty());
+    assert_ne!(a.myaddress(), old_address);
+
+    let (old_id, new_id) = wait_for(&mut events, |m| match m {
+        Message::ContactRotated(old, new) => Some((old, new)),
+        _ => None,
+    })
+    .await;
+    assert!(old_id.as_byte() == old_user.id.as_byte());
+    // ホスト名などはそのままで、IDだけが変わる
+    let new_user = user_of(b, &a.myaddress()).await;
+    assert!(new_user.id.as_byte() == new_id.as_byte());
+    assert_eq!(new_user.hostname, old_user.hostname);
+    assert_eq!(b.get_users().await.unwrap().len(), 1);
+
+    // 新しいIDで両方向に送れる
+    let mut events_a = a.subscribe();
+    b.send_dm(&new_user.id, "to the new id").await.unwrap();
+    wait_for(&mut events_a, |m| match m {
+        Message::DirectMsg(_, msg) if msg == "to the new id" => Some(()),
+        _ => None,
+    })
+    .await;
+    a.send_dm(&b_user.id, "from the new id").await.unwrap();
+    let from = wait_for(&mut events, |m| match m {
+        Message::DirectMsg(from, msg) if msg == "from the new id" => Some(from),
+        _ => None,
+    })
+    .await;
+    assert!(from.as_byte() == new_id.as_byte());
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn forged_identity_rotation_is_rejected() {
+    let sessions = TestSessions::memory(2).await.unwrap();
+    let (a, b) = (&sessions.sessions[0], &sessions.sessions[1]);
+    sessions.connect_all().await.unwrap();
+    let a_user = user_of(b, &a.myaddress()).await;
+    let b_user = user_of(a, &b.myaddress()).await;
+
+    let mut events = b.subscribe();
+    let forged = send_forged_rotation(a, &b_user.id).await.unwrap();
+    wait_for(&mut events, |m| match m {
+        Message::Disconnected(_) => Some(()),
+        Message::ContactRotated(..) => panic!("the forged statement is accepted"),
+        _ => None,
+    })
+    .await;
+
+    // 連絡先は古いIDのまま残る
+    let users = b.get_users().await.unwrap();
+    assert_eq!(users.len(), 1);
+    assert!(users[0].id.as_byte() == a_user.id.as_byte());
+    assert!(users[0].id.as_byte() != forged.as_byte());
+
+    sessions.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn identity_rotation_can_be_resent() {
+    let sessions = TestSessions::memory(1).await.unwrap();
+    let a = &sessions.sessions[0];
+    let network = sessions.network().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let open = || async {
+        SessionBuilder::new(dir.path())
+            .transport(network.transport("offline.test").unwrap())
+            .build()
+            .await
+            .unwrap()
+    };
+
+    let b = open().await;
+    a.add_user(&b.myaddress()).await.unwrap();
+    b.add_user(&a.myaddress()).await.unwrap();
+    let b_user = user_of(a, &b.myaddress()).await;
+    let old_a = user_of(&b, &a.myaddress()).await;
+    b.shutdown().await.unwrap();
+    drop(b);
+
+    // bがオフラインの間にIDを変える
+    let failed = a.rotate_identity().await.unwrap();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].as_byte() == b_user.id.as_byte());
+    assert_eq!(a.resend_identity_rotation().await.unwrap().len(), 1);
+
+    // bが戻ってきたら古い鍵で送り直せる
+    let b = open().await;
+    let mut events = b.subscribe();
+    assert!(a.resend_identity_rotation().await.unwrap().is_empty());
+    let (old_id, new_id) = wait_for(&mut events, |m| match m {
+        Message::ContactRotated(old, new) => Some((old, new)),
+        _ => None,
+    })
+    .await;
+    assert!(old_id.as_byte() == old_a.id.as_byte());
+    assert!(user_of(&b, &a.myaddress()).await.id.as_byte() == new_id.as_byte());
+    // 送り終えたので、もう送るものは無い
+    assert!(a.resend_identity_rotation().await.unwrap().is_empty());
+
+    b.shutdown().await.unwrap();
+    sessions.shutdown().await.unwrap();
+}
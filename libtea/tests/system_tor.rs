@@ -0,0 +1,611 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use libtea::{
+    test_support::{read_onion_key, send_hostname_migration, wait_for, FakeControlPort},
+    ControlAuth, Error, Message, RYOKUCHATSession, SessionBuilder,
+};
+use tokio_stream::StreamExt;
+
+fn builder(data_dir: &std::path::Path, fake: &FakeControlPort) -> SessionBuilder {
+    SessionBuilder::new(data_dir)
+        .bind_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        .system_tor(fake.address(), ControlAuth::Password("secret".to_string()))
+}
+
+#[tokio::test]
+async fn onion_service_is_created_with_add_onion() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    let builder = builder(data_dir.path(), &fake);
+    let mut events = builder.subscribe();
+    let session = builder.build().await.unwrap();
+    let address = session.myaddress();
+    let (hostname, own_key) = address.split_once('#').unwrap();
+    assert!(hostname.ends_with(".onion"));
+
+    // 起動途中の状態と、イベントで届いた完了の両方が通知される
+    let mut progress = Vec::new();
+    while let Some(event) = events.next().await {
+        match event {
+            Message::TorBootstrap(p, _) => progress.push(p),
+            Message::OnionReady(_) => break,
+            _ => {}
+        }
+    }
+    assert_eq!(progress, vec![50, 100]);
+
+    let commands = fake.commands();
+    let add_onion = commands
+        .iter()
+        .find(|c| c.starts_with("ADD_ONION "))
+        .unwrap();
+    assert!(add_onion.starts_with("ADD_ONION NEW:ED25519-V3 Flags=V3Auth Port=4545,127.0.0.1:"));
+
+    // 連絡先がいなくても自分の鍵だけでクライアント認証が有効になる
+    assert!(add_onion.ends_with(&format!(" ClientAuthV3={}", own_key)));
+
+    // 既に動いているTorは終了させない
+    session.shutdown().await.unwrap();
+    assert!(!fake.commands().iter().any(|c| c.starts_with("SIGNAL")));
+}
+
+#[tokio::test]
+async fn onion_key_is_reused() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    let session = builder(data_dir.path(), &fake).build().await.unwrap();
+    let address = session.myaddress();
+    session.shutdown().await.unwrap();
+
+    let session = builder(data_dir.path(), &fake).build().await.unwrap();
+    assert_eq!(session.myaddress(), address);
+    let add_onion: Vec<_> = fake
+        .commands()
+        .into_iter()
+        .filter(|c| c.starts_with("ADD_ONION "))
+        .collect();
+    assert_eq!(add_onion.len(), 2);
+    assert!(add_onion[1].starts_with("ADD_ONION ED25519-V3:"));
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    let result = SessionBuilder::new(data_dir.path())
+        .bind_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        .system_tor(fake.address(), ControlAuth::Password("wrong".to_string()))
+        .build()
+        .await;
+    assert!(matches!(result, Err(Error::Tor(_))));
+}
+
+#[test]
+fn password_is_not_logged() {
+    let builder = SessionBuilder::new("data").system_tor(
+        "127.0.0.1:9051",
+        ControlAuth::Password("hunter2".to_string()),
+    );
+    let debug = format!("{:?}", builder);
+    assert!(debug.contains("Password(..)"));
+    assert!(!debug.contains("hunter2"));
+}
+
+#[tokio::test]
+async fn hidden_service_dir_key_is_migrated() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    // 以前のバージョンでTorが作った鍵を置いておく
+    let hidden_dir = data_dir.path().join("tor").join("hidden");
+    std::fs::create_dir_all(&hidden_dir).unwrap();
+    let mut old_key = b"== ed25519v1-secret: type0 ==\0\0\0".to_vec();
+    old_key.extend_from_slice(&[7; 64]);
+    std::fs::write(hidden_dir.join("hs_ed25519_secret_key"), &old_key).unwrap();
+
+    let session = builder(data_dir.path(), &fake).build().await.unwrap();
+    let expected = format!("ADD_ONION ED25519-V3:{} ", base64::encode([7; 64]));
+    assert!(fake.commands().iter().any(|c| c.starts_with(&expected)));
+    let saved = read_onion_key(
+        &data_dir
+            .path()
+            .join("DO_NOT_SEND_TO_OTHER_PEOPLE_onionkey.ykr"),
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(saved, expected["ADD_ONION ".len()..].trim_end());
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn contacts_are_authorized_clients() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b = builder(&data_dir.path().join("b"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b_address = b.myaddress();
+    let (b_host, b_key) = b_address
+        .split_once('@')
+        .unwrap()
+        .1
+        .split_once('#')
+        .unwrap();
+    let b_service_id = b_host.strip_suffix(".onion").unwrap();
+
+    // 追加した相手のHidden Serviceに接続するための鍵が登録され、相手の公開鍵を含めて作り直される
+    a.add_user(&b.myaddress()).await.unwrap();
+    let commands = fake.commands();
+    assert!(commands
+        .iter()
+        .any(|c| c.starts_with(&format!("ONION_CLIENT_AUTH_ADD {} x25519:", b_service_id))));
+    let add_onion = commands
+        .iter()
+        .rev()
+        .find(|c| c.starts_with("ADD_ONION "))
+        .unwrap();
+    assert!(add_onion.contains(&format!(" ClientAuthV3={}", b_key)));
+
+    // 削除すると鍵が消され、相手の公開鍵を含まずに作り直される
+    let b_user = a.get_users().await.unwrap().pop().unwrap();
+    assert_eq!(b_user.get_address(), b.myaddress());
+    a.del_user(&b_user.id).await.unwrap();
+    let commands = fake.commands();
+    assert!(commands
+        .iter()
+        .any(|c| c == &format!("ONION_CLIENT_AUTH_REMOVE {}", b_service_id)));
+    let add_onion = commands
+        .iter()
+        .rev()
+        .find(|c| c.starts_with("ADD_ONION "))
+        .unwrap();
+    assert!(!add_onion.contains(b_key));
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_auth_can_be_disabled() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+
+    let session = builder(data_dir.path(), &fake)
+        .client_auth(false)
+        .build()
+        .await
+        .unwrap();
+    assert!(!session.myaddress().contains('#'));
+    assert!(!fake.commands().iter().any(|c| c.contains("ClientAuthV3=")));
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn sessions_talk_through_the_socks_proxy() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b = builder(&data_dir.path().join("b"), &fake)
+        .build()
+        .await
+        .unwrap();
+    a.add_user(&b.myaddress()).await.unwrap();
+    b.add_user(&a.myaddress()).await.unwrap();
+
+    let mut events = b.subscribe();
+    let b_user = a.get_users().await.unwrap().pop().unwrap();
+    a.send_dm(&b_user.id, "over tor").await.unwrap();
+    wait_for(&mut events, |m| {
+        matches!(m, Message::DirectMsg(_, msg) if msg == "over tor").then_some(())
+    })
+    .await;
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_onion_service_is_republished() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let builder = builder(data_dir.path(), &fake).health_check_interval(Duration::from_millis(100));
+    let mut events = builder.subscribe();
+    let session = builder.build().await.unwrap();
+    wait_for(&mut events, |m| matches!(m, Message::Online).then_some(())).await;
+
+    // 記述子が消えたように接続できなくすると、通知された後に作り直されて元に戻る
+    fake.drop_onions();
+    wait_for(&mut events, |m| {
+        matches!(m, Message::Offline(_)).then_some(())
+    })
+    .await;
+    wait_for(&mut events, |m| matches!(m, Message::Online).then_some(())).await;
+    assert!(fake.commands().iter().any(|c| c.starts_with("DEL_ONION ")));
+
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hs_desc_event_triggers_a_check() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let builder = builder(data_dir.path(), &fake).health_check_interval(Duration::from_secs(3600));
+    let mut events = builder.subscribe();
+    let session = builder.build().await.unwrap();
+    let address = session.myaddress();
+    let hostname = address.split_once('@').unwrap().1;
+    let service_id = hostname.split_once(".onion").unwrap().0;
+
+    // 監視用の接続がイベントを受け取れるようになるまで待つ
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !fake.commands().iter().any(|c| c == "SETEVENTS HS_DESC") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    fake.emit_event(&format!("HS_DESC UPLOADED {} x25519 $AAAA", service_id));
+    wait_for(&mut events, |m| matches!(m, Message::Online).then_some(())).await;
+
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hs_desc_events_are_coalesced() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let builder = builder(data_dir.path(), &fake).health_check_interval(Duration::from_secs(3600));
+    let mut events = builder.subscribe();
+    let session = builder.build().await.unwrap();
+    let address = session.myaddress();
+    let hostname = address.split_once('@').unwrap().1;
+    let service_id = hostname.split_once(".onion").unwrap().0;
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !fake.commands().iter().any(|c| c == "SETEVENTS HS_DESC") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // HSDirごとに届くイベントでは、自分への接続は1回だけ試される
+    for _ in 0..8 {
+        fake.emit_event(&format!("HS_DESC UPLOADED {} x25519 $AAAA", service_id));
+    }
+    wait_for(&mut events, |m| matches!(m, Message::Online).then_some(())).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(fake.socks_connections(), 1);
+
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn onion_keys_are_encrypted_with_the_passphrase() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let onion_key_file = data_dir
+        .path()
+        .join("DO_NOT_SEND_TO_OTHER_PEOPLE_onionkey.ykr");
+    let client_auth_key_file = data_dir
+        .path()
+        .join("DO_NOT_SEND_TO_OTHER_PEOPLE_clientauth.ykr");
+    // ヘッダーの中で、暗号化されているかを表す位置
+    let encrypted = |path: &std::path::Path| std::fs::read(path).unwrap()[10] == 1;
+
+    let session = builder(data_dir.path(), &fake)
+        .passphrase("correct horse")
+        .build()
+        .await
+        .unwrap();
+    let address = session.myaddress();
+    assert!(encrypted(&onion_key_file));
+    assert!(encrypted(&client_auth_key_file));
+    assert!(matches!(
+        read_onion_key(&onion_key_file, None).await,
+        Err(Error::Locked)
+    ));
+    let key = read_onion_key(&onion_key_file, Some("correct horse"))
+        .await
+        .unwrap();
+    session.shutdown().await.unwrap();
+
+    // 作り直しても同じ鍵が使われる
+    let session = builder(data_dir.path(), &fake)
+        .passphrase("correct horse")
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(session.myaddress(), address);
+
+    // パスフレーズを外すと、Hidden Serviceの鍵も暗号化せずに保存し直される
+    session.change_passphrase(None).await.unwrap();
+    assert!(!encrypted(&onion_key_file));
+    assert!(!encrypted(&client_auth_key_file));
+    assert_eq!(read_onion_key(&onion_key_file, None).await.unwrap(), key);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn address_with_a_line_break_is_refused() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b = builder(&data_dir.path().join("b"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let address = b.myaddress();
+    let (id, rest) = address.split_once('@').unwrap();
+    let (hostname, client_auth) = rest.split_once('#').unwrap();
+
+    // ControlPortのコマンドを紛れ込ませようとするアドレスや、.onionでないアドレスは追加できない
+    for hostname in [
+        format!("x\r\nSIGNAL SHUTDOWN\r\n{}", hostname),
+        format!("{} Flags=Permanent", hostname),
+        format!("{}@{}", hostname, hostname),
+        "example.com".to_string(),
+    ] {
+        let address = format!("{}@{}#{}", id, hostname, client_auth);
+        assert!(matches!(a.add_user(&address).await, Err(Error::Address(_))));
+    }
+    assert!(a.get_users().await.unwrap().is_empty());
+    assert!(!fake.commands().iter().any(|c| c.starts_with("SIGNAL")));
+
+    a.add_user(&address).await.unwrap();
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rotated_address_is_sent_to_contacts() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b = builder(&data_dir.path().join("b"), &fake)
+        .build()
+        .await
+        .unwrap();
+    a.add_user(&b.myaddress()).await.unwrap();
+    b.add_user(&a.myaddress()).await.unwrap();
+    let old_address = a.myaddress();
+    let old_service_id = old_address
+        .split_once('@')
+        .unwrap()
+        .1
+        .split_once(".onion")
+        .unwrap()
+        .0
+        .to_string();
+
+    let mut events = b.subscribe();
+    let failed = a.rotate_onion(Duration::from_millis(200)).await.unwrap();
+    assert!(failed.is_empty());
+    let new_address = a.myaddress();
+    assert_ne!(new_address, old_address);
+
+    // 受け取った側の連絡先リストが書き換えられる
+    wait_for(&mut events, |m| {
+        matches!(m, Message::ContactMoved(_, address) if address == new_address).then_some(())
+    })
+    .await;
+    let a_user = b.get_users().await.unwrap().pop().unwrap();
+    assert_eq!(a_user.get_address(), new_address);
+
+    // 新しい鍵が保存され、猶予が過ぎると古いHidden Serviceが消される
+    let saved = read_onion_key(
+        &data_dir
+            .path()
+            .join("a")
+            .join("DO_NOT_SEND_TO_OTHER_PEOPLE_onionkey.ykr"),
+        None,
+    )
+    .await
+    .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !fake
+            .commands()
+            .iter()
+            .any(|c| c == &format!("DEL_ONION {}", old_service_id))
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    a.shutdown().await.unwrap();
+    wait_for(&mut events, |m| {
+        matches!(m, Message::Disconnected(_)).then_some(())
+    })
+    .await;
+
+    // 再起動しても新しいアドレスのまま
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(a.myaddress(), new_address);
+    assert!(fake
+        .commands()
+        .iter()
+        .any(|c| c.starts_with(&format!("ADD_ONION {} ", saved))));
+
+    // 新しいアドレスに送れる
+    let mut events = a.subscribe();
+    b.send_dm(&a_user.id, "moved").await.unwrap();
+    wait_for(&mut events, |m| {
+        matches!(m, Message::DirectMsg(_, msg) if msg == "moved").then_some(())
+    })
+    .await;
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn migration_to_a_non_onion_host_is_rejected() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let b = builder(&data_dir.path().join("b"), &fake)
+        .build()
+        .await
+        .unwrap();
+    a.add_user(&b.myaddress()).await.unwrap();
+    b.add_user(&a.myaddress()).await.unwrap();
+    let a_address = a.myaddress();
+    let b_user = a.get_users().await.unwrap().pop().unwrap();
+
+    // Socksプロキシを通して手元のポートに接続させようとする
+    let mut events = b.subscribe();
+    send_hostname_migration(&a, &b_user.id, "127.0.0.1:22")
+        .await
+        .unwrap();
+    wait_for(&mut events, |m| match m {
+        Message::ContactMoved(..) => panic!("the migration to a non-onion host is accepted"),
+        Message::Disconnected(_) => Some(()),
+        _ => None,
+    })
+    .await;
+    let a_user = b.get_users().await.unwrap().pop().unwrap();
+    assert_eq!(a_user.get_address(), a_address);
+
+    a.shutdown().await.unwrap();
+    b.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn stuck_bootstrap_is_notified() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    fake.stall_bootstrap();
+    let builder =
+        builder(data_dir.path(), &fake).bootstrap_stuck_timeout(Duration::from_millis(100));
+    let mut events = builder.subscribe();
+    let session = tokio::spawn(builder.build());
+
+    // 進捗が変わらないまま時間が過ぎると知らされる
+    wait_for(&mut events, |m| {
+        matches!(m, Message::BootstrapStuck(50, _)).then_some(())
+    })
+    .await;
+
+    // Torが報告した問題もそのまま知らされる
+    fake.emit_event("STATUS_CLIENT WARN BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\" WARNING=\"Connection refused\" REASON=CONNECTREFUSED COUNT=1 RECOMMENDATION=warn");
+    wait_for(&mut events, |m| {
+        matches!(m, Message::BootstrapStuck(50, w) if w == "Connection refused").then_some(())
+    })
+    .await;
+
+    // 進めば起動は続けられる
+    fake.emit_event("STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"");
+    let session = session.await.unwrap().unwrap();
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn proxy_error_is_notified() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    fake.stall_bootstrap();
+    fake.set_conf("HTTPSProxy", "192.0.2.1:3128");
+    let builder = builder(data_dir.path(), &fake);
+    let mut events = builder.subscribe();
+    let session = tokio::spawn(builder.build());
+
+    // リレーに接続できなかった場合は、プロキシのエラーにはしない
+    wait_for(&mut events, |m| {
+        matches!(m, Message::TorBootstrap(50, _)).then_some(())
+    })
+    .await;
+    fake.emit_event("STATUS_CLIENT WARN BOOTSTRAP PROGRESS=50 TAG=conn SUMMARY=\"Connecting to a relay\" WARNING=\"Connection refused\" REASON=CONNECTREFUSED COUNT=1 RECOMMENDATION=warn");
+    wait_for(&mut events, |m| match m {
+        Message::ProxyError(_) => panic!("a relay failure is blamed on the proxy"),
+        Message::BootstrapStuck(_, w) if w == "Connection refused" => Some(()),
+        _ => None,
+    })
+    .await;
+
+    // プロキシに接続する段階で失敗すると、プロキシのエラーとして知らされる
+    fake.emit_event("STATUS_CLIENT WARN BOOTSTRAP PROGRESS=3 TAG=conn_proxy SUMMARY=\"Connecting to proxy\" WARNING=\"Proxy Authentication Required\" REASON=MISC COUNT=1 RECOMMENDATION=warn");
+    wait_for(&mut events, |m| {
+        matches!(m, Message::ProxyError(w) if w == "Proxy Authentication Required").then_some(())
+    })
+    .await;
+
+    fake.emit_event("STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"");
+    let session = session.await.unwrap().unwrap();
+    assert!(fake.commands().iter().any(|c| c == "GETCONF HTTPSProxy"));
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn backup_keeps_the_onion_address() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let fake = FakeControlPort::start(Some("secret")).await.unwrap();
+    let backup = data_dir.path().join("backup.ykr");
+
+    let a = builder(&data_dir.path().join("a"), &fake)
+        .build()
+        .await
+        .unwrap();
+    let address = a.myaddress();
+    a.export_backup(&backup, "passphrase").await.unwrap();
+    a.shutdown().await.unwrap();
+
+    // 別の場所に復元しても、同じHidden Serviceの鍵とクライアント認証の鍵が使われる
+    let restored = builder(&data_dir.path().join("restored"), &fake);
+    RYOKUCHATSession::import_backup(&restored, &backup, "passphrase")
+        .await
+        .unwrap();
+    let restored = restored.build().await.unwrap();
+    assert_eq!(restored.myaddress(), address);
+    restored.shutdown().await.unwrap();
+}
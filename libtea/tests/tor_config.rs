@@ -0,0 +1,287 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use libtea::{Bridge, Error, Padding, Proxy, TorConfig};
+
+#[test]
+fn default_config_keeps_the_previous_options() {
+    let torrc = TorConfig::new().to_torrc().unwrap();
+    assert_eq!(
+        torrc,
+        "ExcludeNodes SlowServer\n\
+         StrictNodes 1\n\
+         ConnectionPadding 1\n\
+         ReducedConnectionPadding 0\n\
+         CircuitPadding 1\n\
+         ReducedCircuitPadding 0\n"
+    );
+}
+
+#[test]
+fn nodes_padding_and_custom_options_are_rendered() {
+    let torrc = TorConfig::new()
+        .exclude_nodes(Vec::<String>::new())
+        .entry_nodes(["{jp}", "$0123456789ABCDEF0123456789ABCDEF01234567~relay"])
+        .exit_nodes(["192.0.2.0/24", "2001:db8::1"])
+        .strict_nodes(false)
+        .padding(Padding::Reduced)
+        .custom("NumEntryGuards", "2")
+        .to_torrc()
+        .unwrap();
+    assert_eq!(
+        torrc,
+        "EntryNodes {jp},$0123456789ABCDEF0123456789ABCDEF01234567~relay\n\
+         ExitNodes 192.0.2.0/24,2001:db8::1\n\
+         StrictNodes 0\n\
+         ConnectionPadding auto\n\
+         ReducedConnectionPadding 1\n\
+         CircuitPadding 1\n\
+         ReducedCircuitPadding 1\n\
+         NumEntryGuards 2\n"
+    );
+
+    let torrc = TorConfig::new()
+        .padding(Padding::Disabled)
+        .to_torrc()
+        .unwrap();
+    assert!(torrc.ends_with("ConnectionPadding 0\nCircuitPadding 0\n"));
+}
+
+#[test]
+fn invalid_config_is_rejected() {
+    for config in [
+        TorConfig::new().exclude_nodes(["two words"]),
+        TorConfig::new().entry_nodes(["{japan}"]),
+        TorConfig::new().exit_nodes(["$0123"]),
+        TorConfig::new().exit_nodes(["10.0.0.0/33"]),
+        TorConfig::new().exit_nodes(["a,b"]),
+        TorConfig::new().custom("SocksPort", "9050"),
+        TorConfig::new().custom("bad key", "1"),
+        TorConfig::new().custom("Nickname", "a\nSocksPort 9050"),
+    ] {
+        assert!(
+            matches!(config.validate(), Err(Error::Config(_))),
+            "{:?} is accepted",
+            config
+        );
+        assert!(config.to_torrc().is_err());
+    }
+}
+
+const OBFS4: &str =
+    "obfs4 192.0.2.1:443 0123456789abcdef0123456789abcdef01234567 cert=AAAA+bb/cc iat-mode=0";
+
+#[test]
+fn bridge_lines_are_parsed() {
+    let bridge: Bridge = OBFS4.parse().unwrap();
+    assert_eq!(bridge.transport(), Some("obfs4"));
+    assert_eq!(bridge.address(), "192.0.2.1:443".parse().unwrap());
+    assert_eq!(
+        bridge.fingerprint(),
+        Some("0123456789ABCDEF0123456789ABCDEF01234567")
+    );
+    assert_eq!(
+        bridge.to_string(),
+        "obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AAAA+bb/cc iat-mode=0"
+    );
+
+    // torrcの行や普通のブリッジも読み取れる
+    let bridge: Bridge = "Bridge [2001:db8::1]:9001".parse().unwrap();
+    assert_eq!(bridge.transport(), None);
+    assert_eq!(bridge.fingerprint(), None);
+    assert_eq!(bridge.to_string(), "[2001:db8::1]:9001");
+
+    for line in [
+        "",
+        "obfs4",
+        "obfs4 bridge.example.com:443",
+        "192.0.2.1",
+        "obfs4 192.0.2.1:443 0123 cert=AAAA",
+        "192.0.2.1:443 cert=AAAA",
+        "ob-fs4 192.0.2.1:443",
+    ] {
+        assert!(
+            matches!(line.parse::<Bridge>(), Err(Error::Config(_))),
+            "{:?} is accepted",
+            line
+        );
+    }
+}
+
+#[test]
+fn bridges_and_plugins_are_rendered() {
+    let torrc = TorConfig::new()
+        .bridges([
+            OBFS4.parse().unwrap(),
+            "snowflake 192.0.2.3:80 url=https://snowflake.example/ ice=stun:a:3478,stun:b:3478"
+                .parse()
+                .unwrap(),
+        ])
+        .transport_plugin(["obfs4", "meek_lite"], "/usr/bin/lyrebird", &[])
+        .transport_plugin(
+            ["snowflake"],
+            "/usr/bin/snowflake-client",
+            &["-log", "/tmp/sf.log"],
+        )
+        .to_torrc()
+        .unwrap();
+    let lines: Vec<_> = torrc.lines().skip(6).collect();
+    assert_eq!(
+        lines,
+        [
+            "UseBridges 1",
+            "Bridge obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AAAA+bb/cc iat-mode=0",
+            "Bridge snowflake 192.0.2.3:80 url=https://snowflake.example/ ice=stun:a:3478,stun:b:3478",
+            "ClientTransportPlugin obfs4,meek_lite exec /usr/bin/lyrebird",
+            "ClientTransportPlugin snowflake exec /usr/bin/snowflake-client -log /tmp/sf.log",
+        ]
+    );
+}
+
+#[test]
+fn bridges_need_a_plugin() {
+    let bridge: Bridge = OBFS4.parse().unwrap();
+    for config in [
+        TorConfig::new().bridges([bridge.clone()]),
+        TorConfig::new().bridges([bridge.clone()]).transport_plugin(
+            ["snowflake"],
+            "/usr/bin/snowflake-client",
+            &[],
+        ),
+        TorConfig::new()
+            .bridges([bridge.clone()])
+            .transport_plugin(["obfs4"], "lyrebird", &[]),
+        TorConfig::new().bridges([bridge.clone()]).transport_plugin(
+            ["obfs4"],
+            "/usr/bin/lyrebird",
+            &["two words"],
+        ),
+        TorConfig::new()
+            .bridges(["192.0.2.1:9001".parse().unwrap()])
+            .entry_nodes(["{jp}"]),
+    ] {
+        assert!(
+            matches!(config.validate(), Err(Error::Config(_))),
+            "{:?} is accepted",
+            config
+        );
+    }
+}
+
+#[test]
+fn proxies_are_rendered() {
+    let options = |config: TorConfig| -> Vec<String> {
+        config
+            .to_torrc()
+            .unwrap()
+            .lines()
+            .skip(6)
+            .map(|l| l.to_string())
+            .collect()
+    };
+
+    let https = TorConfig::new()
+        .proxy(Proxy::Https {
+            address: "proxy.example.com:3128".to_string(),
+            auth: Some(("user".to_string(), "pass word".to_string())),
+        })
+        .reachable_addresses(["*:80", "*:443", "reject [2001:db8::]/32:*"]);
+    assert_eq!(
+        options(https),
+        [
+            "HTTPSProxy proxy.example.com:3128",
+            "HTTPSProxyAuthenticator user:pass word",
+            "ReachableAddresses *:80,*:443,reject [2001:db8::]/32:*",
+        ]
+    );
+
+    let socks5 = TorConfig::new().proxy(Proxy::Socks5 {
+        address: "[2001:db8::1]:1080".to_string(),
+        auth: Some(("user".to_string(), "pass".to_string())),
+    });
+    assert_eq!(
+        options(socks5),
+        [
+            "Socks5Proxy [2001:db8::1]:1080",
+            "Socks5ProxyUsername user",
+            "Socks5ProxyPassword pass",
+        ]
+    );
+
+    let socks4 = TorConfig::new().proxy(Proxy::Socks4 {
+        address: "192.0.2.1:1080".to_string(),
+    });
+    assert_eq!(options(socks4), ["Socks4Proxy 192.0.2.1:1080"]);
+}
+
+#[test]
+fn proxy_auth_is_not_logged() {
+    let config = TorConfig::new().proxy(Proxy::Socks5 {
+        address: "192.0.2.1:1080".to_string(),
+        auth: Some(("user".to_string(), "hunter2".to_string())),
+    });
+    let debug = format!("{:?}", config);
+    assert!(debug.contains("192.0.2.1:1080"));
+    assert!(!debug.contains("hunter2"));
+}
+
+#[test]
+fn invalid_proxies_are_rejected() {
+    let https = |address: &str, auth: Option<(&str, &str)>| {
+        TorConfig::new().proxy(Proxy::Https {
+            address: address.to_string(),
+            auth: auth.map(|(u, p)| (u.to_string(), p.to_string())),
+        })
+    };
+    for config in [
+        https("proxy.example.com", None),
+        https("proxy.example.com:0", None),
+        https("2001:db8::1:1080", None),
+        https("proxy example:3128", None),
+        https("proxy.example.com:3128", Some(("us:er", "pass"))),
+        https("proxy.example.com:3128", Some(("", "pass"))),
+        https(
+            "proxy.example.com:3128",
+            Some(("user", "pass\nSocksPort 9050")),
+        ),
+        TorConfig::new().reachable_addresses(["*:443-80"]),
+        TorConfig::new().reachable_addresses(["192.0.2.0/33:80"]),
+        TorConfig::new().reachable_addresses(["2001:db8::1:80"]),
+        TorConfig::new().reachable_addresses(["allow *:80"]),
+    ] {
+        assert!(
+            matches!(config.validate(), Err(Error::Config(_))),
+            "{:?} is accepted",
+            config
+        );
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
+#[tokio::test]
+async fn invalid_config_fails_before_starting_tor() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let result = libtea::SessionBuilder::new(data_dir.path())
+        .tor_config(TorConfig::new().custom("ControlPort", "9051"))
+        .build()
+        .await;
+    assert!(matches!(result, Err(Error::Config(_))));
+}
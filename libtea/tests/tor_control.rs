@@ -0,0 +1,193 @@
+/*
+RYOKUCHAT is a P2P chat application.
+
+Copyright (C) 2021 TrendCreate
+Copyright (C) 2021 WinLinux1028
+Copyright (C) 2021 TRENDcreate
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use libtea::{
+    tor_control::{parse_bootstrap, parse_keywords, ControlConnection},
+    Error,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream, DuplexStream},
+    task::JoinHandle,
+};
+
+// 受け取るコマンドと返す応答を順番に決めておいたControlPortにつながった接続を作る
+fn scripted(
+    script: Vec<(&'static str, &'static str)>,
+) -> (ControlConnection<DuplexStream>, JoinHandle<()>) {
+    let (client, server) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move {
+        let mut server = BufStream::new(server);
+        for (command, reply) in script {
+            let mut line = String::new();
+            server.read_line(&mut line).await.unwrap();
+            assert_eq!(line, format!("{}\r\n", command));
+            server.write_all(reply.as_bytes()).await.unwrap();
+            server.flush().await.unwrap();
+        }
+    });
+    (ControlConnection::new(client), handle)
+}
+
+#[tokio::test]
+async fn multi_line_and_data_replies_are_read() {
+    let (mut control, handle) = scripted(vec![(
+        "GETINFO config-text",
+        "250+config-text=\r\nSocksPort 9050\r\n..hidden\r\n.\r\n250-version=0.4.8.0\r\n250 OK\r\n",
+    )]);
+    let reply = control.command("GETINFO config-text").await.unwrap();
+    assert_eq!(reply.code, 250);
+    assert_eq!(
+        reply.lines,
+        vec![
+            "config-text=\nSocksPort 9050\n.hidden".to_string(),
+            "version=0.4.8.0".to_string(),
+            "OK".to_string(),
+        ]
+    );
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn events_are_queued_while_waiting_for_a_reply() {
+    let (mut control, handle) = scripted(vec![
+        (
+            "SETEVENTS STATUS_CLIENT HS_DESC",
+            "650 STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED\r\n250 OK\r\n",
+        ),
+        (
+            "SIGNAL NEWNYM",
+            "250 OK\r\n650-HS_DESC UPLOADED abc UNKNOWN\r\n650 OK\r\n",
+        ),
+    ]);
+    control
+        .set_events(&["STATUS_CLIENT", "HS_DESC"])
+        .await
+        .unwrap();
+    control.signal_newnym().await.unwrap();
+
+    let event = control.next_event().await.unwrap();
+    assert_eq!(event.name, "STATUS_CLIENT");
+    assert_eq!(event.body, "NOTICE CIRCUIT_ESTABLISHED");
+    let event = control.next_event().await.unwrap();
+    assert_eq!(event.name, "HS_DESC");
+    assert_eq!(event.body, "UPLOADED abc UNKNOWN");
+    assert_eq!(event.data, vec!["OK".to_string()]);
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn error_replies_become_errors() {
+    let (mut control, handle) = scripted(vec![(
+        "AUTHENTICATE \"wrong\"",
+        "515 Authentication failed\r\n",
+    )]);
+    let result = control.authenticate_password("wrong").await;
+    assert!(matches!(result, Err(Error::Tor(_))));
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn onion_services_are_added_and_deleted() {
+    let (mut control, handle) = scripted(vec![
+        (
+            "ADD_ONION NEW:ED25519-V3 Flags=V3Auth Port=4545,127.0.0.1:1234 ClientAuthV3=AAAA ClientAuthV3=BBBB",
+            "250-ServiceID=abcdef\r\n250-PrivateKey=ED25519-V3:secret\r\n250 OK\r\n",
+        ),
+        ("DEL_ONION abcdef", "250 OK\r\n"),
+    ]);
+    let added = control
+        .add_onion("NEW:ED25519-V3", 4545, "127.0.0.1:1234", &["AAAA", "BBBB"])
+        .await
+        .unwrap();
+    assert_eq!(added.service_id, "abcdef");
+    assert_eq!(added.private_key.as_deref(), Some("ED25519-V3:secret"));
+    control.del_onion("abcdef").await.unwrap();
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn arguments_with_line_breaks_or_spaces_are_refused() {
+    // 拒否されたコマンドは送られず、次のコマンドが最初に届く
+    let (mut control, handle) = scripted(vec![("DEL_ONION abcdef", "250 OK\r\n")]);
+    assert!(matches!(
+        control.del_onion("abcdef\r\nSIGNAL SHUTDOWN").await,
+        Err(Error::Tor(_))
+    ));
+    assert!(matches!(
+        control.remove_client_auth("abcdef\nSIGNAL SHUTDOWN").await,
+        Err(Error::Tor(_))
+    ));
+    assert!(matches!(
+        control
+            .add_client_auth("abcdef Flags=Permanent", "secret")
+            .await,
+        Err(Error::Tor(_))
+    ));
+    assert!(matches!(
+        control
+            .set_conf(&[("Socks5Proxy", "a\r\nSIGNAL SHUTDOWN")])
+            .await,
+        Err(Error::Tor(_))
+    ));
+    control.del_onion("abcdef").await.unwrap();
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn configuration_is_read_and_written() {
+    let (mut control, handle) = scripted(vec![
+        (
+            "GETCONF Bridge",
+            "250-Bridge=obfs4 192.0.2.1:443\r\n250 Bridge=obfs4 192.0.2.2:443\r\n",
+        ),
+        ("GETCONF Socks5Proxy", "250 Socks5Proxy\r\n"),
+        ("SETCONF UseBridges=\"1\" Socks5Proxy", "250 OK\r\n"),
+    ]);
+    assert_eq!(
+        control.get_conf("Bridge").await.unwrap(),
+        vec![
+            "obfs4 192.0.2.1:443".to_string(),
+            "obfs4 192.0.2.2:443".to_string()
+        ]
+    );
+    assert!(control.get_conf("Socks5Proxy").await.unwrap().is_empty());
+    control
+        .set_conf(&[("UseBridges", "1"), ("Socks5Proxy", "")])
+        .await
+        .unwrap();
+    handle.await.unwrap();
+}
+
+#[test]
+fn keywords_and_bootstrap_status_are_parsed() {
+    let keywords = parse_keywords(r#"METHODS=COOKIE COOKIEFILE="/var/run/tor/\"cookie\"" FLAG"#);
+    assert_eq!(keywords["METHODS"], "COOKIE");
+    assert_eq!(keywords["COOKIEFILE"], "/var/run/tor/\"cookie\"");
+    assert!(!keywords.contains_key("FLAG"));
+
+    assert_eq!(
+        parse_bootstrap(
+            "NOTICE BOOTSTRAP PROGRESS=75 TAG=enough_dirinfo SUMMARY=\"Loaded enough\""
+        ),
+        Some((75, "Loaded enough".to_string()))
+    );
+    assert_eq!(parse_bootstrap("NOTICE CIRCUIT_ESTABLISHED"), None);
+}